@@ -0,0 +1,127 @@
+// Frame blending between the emulated screen and whatever presents it. Games
+// flicker sprites every other frame for transparency, which the slow LCD
+// smoothed out; blending consecutive frames gives the same look.
+//
+// Frames are RGB888 as returned by `GPU::get_rgb_data`.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlendMode {
+    #[default]
+    Off,
+    // Average of this frame and the previous one
+    Mix,
+    // Each pixel keeps `persistence` of its previous value, like the LCD
+    // slowly responding to changes
+    Lcd(f32),
+}
+
+pub const DEFAULT_PERSISTENCE: f32 = 0.5;
+
+impl BlendMode {
+    // `off`, `mix`, `lcd` or `lcd:PERSISTENCE` with a persistence below 1.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("off", None) => Ok(BlendMode::Off),
+            ("mix", None) => Ok(BlendMode::Mix),
+            ("lcd", None) => Ok(BlendMode::Lcd(DEFAULT_PERSISTENCE)),
+            ("lcd", Some(p)) => match p.parse() {
+                Ok(p) if (0.0..1.0).contains(&p) => Ok(BlendMode::Lcd(p)),
+                _ => Err(format!(
+                    "persistence must be at least 0 and below 1, got {}",
+                    p
+                )),
+            },
+            _ => Err(format!(
+                "unknown frame blending {}, expected off, mix or lcd[:PERSISTENCE]",
+                s
+            )),
+        }
+    }
+}
+
+pub struct FrameBlender {
+    mode: BlendMode,
+    // Previous frame for Mix, the blended result so far for Lcd
+    previous: Vec<f32>,
+}
+
+impl FrameBlender {
+    pub fn new(mode: BlendMode) -> Self {
+        Self {
+            mode,
+            previous: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> BlendMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: BlendMode) {
+        self.mode = mode;
+        self.reset();
+    }
+
+    // Forget earlier frames, e.g. after loading a state.
+    pub fn reset(&mut self) {
+        self.previous.clear();
+    }
+
+    pub fn blend(&mut self, frame: &[u8]) -> Vec<u8> {
+        if self.previous.len() != frame.len() {
+            self.previous = frame.iter().map(|&c| f32::from(c)).collect();
+        }
+        match self.mode {
+            BlendMode::Off => frame.to_vec(),
+            BlendMode::Mix => frame
+                .iter()
+                .zip(self.previous.iter_mut())
+                .map(|(&c, p)| {
+                    let mixed = (f32::from(c) + *p) / 2.0;
+                    *p = f32::from(c);
+                    mixed.round() as u8
+                })
+                .collect(),
+            BlendMode::Lcd(persistence) => frame
+                .iter()
+                .zip(self.previous.iter_mut())
+                .map(|(&c, p)| {
+                    *p = *p * persistence + f32::from(c) * (1.0 - persistence);
+                    p.round() as u8
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(BlendMode::parse("off"), Ok(BlendMode::Off));
+        assert_eq!(BlendMode::parse("mix"), Ok(BlendMode::Mix));
+        assert_eq!(BlendMode::parse("lcd"), Ok(BlendMode::Lcd(0.5)));
+        assert_eq!(BlendMode::parse("lcd:0.75"), Ok(BlendMode::Lcd(0.75)));
+        assert!(BlendMode::parse("lcd:1").is_err());
+        assert!(BlendMode::parse("mix:0.5").is_err());
+        assert!(BlendMode::parse("ghost").is_err());
+    }
+
+    #[test]
+    fn test_blend() {
+        let mut blender = FrameBlender::new(BlendMode::Mix);
+        assert_eq!(blender.blend(&[0xff, 0x00]), [0xff, 0x00]);
+        assert_eq!(blender.blend(&[0x00, 0x00]), [0x80, 0x00]);
+        assert_eq!(blender.blend(&[0xff, 0x00]), [0x80, 0x00]);
+
+        let mut blender = FrameBlender::new(BlendMode::Lcd(0.75));
+        assert_eq!(blender.blend(&[0xff]), [0xff]);
+        assert_eq!(blender.blend(&[0x00]), [0xbf]);
+        assert_eq!(blender.blend(&[0x00]), [0x8f]);
+        blender.set_mode(BlendMode::Off);
+        assert_eq!(blender.blend(&[0x10]), [0x10]);
+    }
+}
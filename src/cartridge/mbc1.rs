@@ -1,5 +1,7 @@
-use super::{ram_size, rom_size, Battery, MBC};
+use super::{ram_size, Battery, MBC};
 use crate::memory::Memory;
+use crate::savestate::SaveState;
+use std::io::{self, Read, Write};
 
 #[derive(Eq, PartialEq)]
 enum BankMode {
@@ -19,9 +21,8 @@ pub struct Mbc1 {
 
 impl Mbc1 {
     pub fn new(rom: Vec<u8>, battery: Option<Battery>) -> Self {
-        let rom_size = rom_size(rom[0x148]);
-        let ram_size = ram_size(rom[0x149]);
-        assert!(rom_size >= rom.len());
+        // The header was checked when the cartridge was created
+        let ram_size = ram_size(rom[0x149]).unwrap_or(0);
         let ram = match &battery {
             Some(b) => b.load_ram(ram_size),
             None => vec![0u8; ram_size],
@@ -46,17 +47,13 @@ impl Memory for Mbc1 {
                 let a = usize::from(self.rom_bank) * 0x4000 + usize::from(address) - 0x4000;
                 *self.rom.get(a).unwrap_or(&0)
             }
-            0xa000..=0xbfff => {
-                if self.ram_enabled {
-                    let bank = match self.bank_mode {
-                        BankMode::Rom => 0,
-                        BankMode::Ram => u16::from(self.ram_bank),
-                    };
-                    let a = bank * 0x2000 + address - 0xa000;
-                    *self.ram.get(usize::from(a)).unwrap_or(&0)
-                } else {
-                    0
-                }
+            0xa000..=0xbfff if self.ram_enabled => {
+                let bank = match self.bank_mode {
+                    BankMode::Rom => 0,
+                    BankMode::Ram => u16::from(self.ram_bank),
+                };
+                let a = usize::from(bank) * 0x2000 + usize::from(address) - 0xa000;
+                *self.ram.get(a).unwrap_or(&0)
             }
             _ => 0,
         }
@@ -91,7 +88,9 @@ impl Memory for Mbc1 {
                         BankMode::Ram => u16::from(self.ram_bank),
                     };
                     let a = usize::from(bank) * 0x2000 + usize::from(address) - 0xa000;
-                    self.ram[a] = value;
+                    if let Some(v) = self.ram.get_mut(a) {
+                        *v = value;
+                    }
                 }
             }
             _ => println!("invalid write address {}", address),
@@ -99,7 +98,36 @@ impl Memory for Mbc1 {
     }
 }
 
-impl MBC for Mbc1 {}
+impl SaveState for Mbc1 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.rom_bank.save(w)?;
+        self.ram.save(w)?;
+        self.ram_bank.save(w)?;
+        self.ram_enabled.save(w)?;
+        (self.bank_mode == BankMode::Ram).save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.rom_bank.load(r)?;
+        self.ram.load(r)?;
+        self.ram_bank.load(r)?;
+        self.ram_enabled.load(r)?;
+        let mut ram_mode = false;
+        ram_mode.load(r)?;
+        self.bank_mode = if ram_mode {
+            BankMode::Ram
+        } else {
+            BankMode::Rom
+        };
+        Ok(())
+    }
+}
+
+impl MBC for Mbc1 {
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank
+    }
+}
 
 impl Drop for Mbc1 {
     fn drop(&mut self) {
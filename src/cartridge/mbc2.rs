@@ -1,5 +1,7 @@
-use super::{rom_size, Battery, MBC};
+use super::{Battery, MBC};
 use crate::memory::Memory;
+use crate::savestate::SaveState;
+use std::io::{self, Read, Write};
 
 pub struct Mbc2 {
     rom: Vec<u8>,
@@ -11,9 +13,7 @@ pub struct Mbc2 {
 
 impl Mbc2 {
     pub fn new(rom: Vec<u8>, battery: Option<Battery>) -> Self {
-        let rom_size = rom_size(rom[0x148]);
         let ram_size = 512;
-        assert!(rom_size >= rom.len());
         let ram = match &battery {
             Some(b) => b.load_ram(ram_size),
             None => vec![0u8; ram_size],
@@ -36,12 +36,8 @@ impl Memory for Mbc2 {
                 let a = usize::from(self.rom_bank) * 0x4000 + usize::from(address) - 0x4000;
                 *self.rom.get(a).unwrap_or(&0)
             }
-            0xa000..=0xbfff => {
-                if self.ram_enabled {
-                    *self.ram.get(usize::from(address) - 0xa000).unwrap_or(&0)
-                } else {
-                    0
-                }
+            0xa000..=0xbfff if self.ram_enabled => {
+                *self.ram.get(usize::from(address) - 0xa000).unwrap_or(&0)
             }
             _ => 0,
         }
@@ -61,7 +57,9 @@ impl Memory for Mbc2 {
             }
             0xa000..=0xbfff => {
                 if self.ram_enabled {
-                    self.ram[usize::from(address) - 0xa000] = value;
+                    if let Some(v) = self.ram.get_mut(usize::from(address) - 0xa000) {
+                        *v = value;
+                    }
                 }
             }
             _ => println!("invalid write address {}", address),
@@ -69,7 +67,25 @@ impl Memory for Mbc2 {
     }
 }
 
-impl MBC for Mbc2 {}
+impl SaveState for Mbc2 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.rom_bank.save(w)?;
+        self.ram.save(w)?;
+        self.ram_enabled.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.rom_bank.load(r)?;
+        self.ram.load(r)?;
+        self.ram_enabled.load(r)
+    }
+}
+
+impl MBC for Mbc2 {
+    fn rom_bank(&self) -> u16 {
+        u16::from(self.rom_bank)
+    }
+}
 
 impl Drop for Mbc2 {
     fn drop(&mut self) {
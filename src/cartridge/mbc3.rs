@@ -1,6 +1,11 @@
-use super::{ram_size, rom_size, Battery, MBC};
+use super::{ram_size, Battery, MBC};
 use crate::memory::Memory;
-use std::time::SystemTime;
+use crate::savestate::{invalid_data, SaveState};
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime};
+
+// About 136 years, keeps the second counts clear of overflows
+const MAX_CLOCK_SECS: u64 = u32::MAX as u64;
 
 struct RealTimeClock {
     clock_start: SystemTime,
@@ -130,6 +135,59 @@ impl RealTimeClock {
     }
 }
 
+// The clock is stored as elapsed seconds rather than wall-clock timestamps,
+// so it resumes from the saved value when the state is loaded.
+impl SaveState for RealTimeClock {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        let now = SystemTime::now();
+        let running_secs = match now.duration_since(self.clock_start) {
+            Ok(n) => n.as_secs(),
+            Err(_) => 0,
+        } + self.offset_sec;
+        running_secs.save(w)?;
+        self.is_latched().save(w)?;
+        self.clock_time_in_secs().save(w)?;
+        self.halt.save(w)?;
+        self.halt_seconds.save(w)?;
+        self.halt_minutes.save(w)?;
+        self.halt_hours.save(w)?;
+        self.halt_days.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut running_secs = 0u64;
+        running_secs.load(r)?;
+        let mut latched = false;
+        latched.load(r)?;
+        let mut latched_secs = 0u64;
+        latched_secs.load(r)?;
+        self.halt.load(r)?;
+        self.halt_seconds.load(r)?;
+        self.halt_minutes.load(r)?;
+        self.halt_hours.load(r)?;
+        self.halt_days.load(r)?;
+        if running_secs > MAX_CLOCK_SECS || latched_secs > MAX_CLOCK_SECS || self.halt_days >= 512 {
+            return Err(invalid_data("invalid RTC state"));
+        }
+
+        // Start the clock in the past by the amount the latched value lags behind
+        let behind = if latched {
+            running_secs.saturating_sub(latched_secs)
+        } else {
+            0
+        };
+        let now = SystemTime::now();
+        self.clock_start = now.checked_sub(Duration::from_secs(behind)).unwrap_or(now);
+        self.offset_sec = running_secs - behind;
+        self.latch_start = if latched {
+            Some(self.clock_start)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
 fn get_seconds(sec: u64) -> u8 {
     (sec % 60) as u8
 }
@@ -156,9 +214,8 @@ pub struct Mbc3 {
 
 impl Mbc3 {
     pub fn new(rom: Vec<u8>, battery: Option<Battery>) -> Self {
-        let rom_size = rom_size(rom[0x148]);
-        let ram_size = ram_size(rom[0x149]);
-        assert!(rom_size >= rom.len());
+        // The header was checked when the cartridge was created
+        let ram_size = ram_size(rom[0x149]).unwrap_or(0);
         let ram = match &battery {
             Some(b) => b.load_ram(ram_size),
             None => vec![0u8; ram_size],
@@ -263,7 +320,9 @@ impl Memory for Mbc3 {
                     if self.ram_bank < 4 {
                         let idx =
                             usize::from(self.ram_bank) * 0x2000 + usize::from(address) - 0xa000;
-                        self.ram[idx] = value;
+                        if let Some(v) = self.ram.get_mut(idx) {
+                            *v = value;
+                        }
                     } else {
                         self.set_timer(value);
                     }
@@ -273,7 +332,32 @@ impl Memory for Mbc3 {
         };
     }
 }
-impl MBC for Mbc3 {}
+
+impl SaveState for Mbc3 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.rom_bank.save(w)?;
+        self.ram.save(w)?;
+        self.ram_bank.save(w)?;
+        self.ram_enabled.save(w)?;
+        self.rtc.save(w)?;
+        self.latch_reg.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.rom_bank.load(r)?;
+        self.ram.load(r)?;
+        self.ram_bank.load(r)?;
+        self.ram_enabled.load(r)?;
+        self.rtc.load(r)?;
+        self.latch_reg.load(r)
+    }
+}
+
+impl MBC for Mbc3 {
+    fn rom_bank(&self) -> u16 {
+        u16::from(self.rom_bank)
+    }
+}
 
 impl Drop for Mbc3 {
     fn drop(&mut self) {
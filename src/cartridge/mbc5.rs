@@ -1,5 +1,7 @@
-use super::{ram_size, rom_size, Battery, MBC};
+use super::{ram_size, Battery, MBC};
 use crate::memory::Memory;
+use crate::savestate::SaveState;
+use std::io::{self, Read, Write};
 
 pub struct Mbc5 {
     rom: Vec<u8>,
@@ -12,9 +14,8 @@ pub struct Mbc5 {
 
 impl Mbc5 {
     pub fn new(rom: Vec<u8>, battery: Option<Battery>) -> Self {
-        let rom_size = rom_size(rom[0x148]);
-        let ram_size = ram_size(rom[0x149]);
-        assert!(rom_size >= rom.len());
+        // The header was checked when the cartridge was created
+        let ram_size = ram_size(rom[0x149]).unwrap_or(0);
         let ram = match &battery {
             Some(b) => b.load_ram(ram_size),
             None => vec![0u8; ram_size],
@@ -38,13 +39,9 @@ impl Memory for Mbc5 {
                 let a = usize::from(self.rom_bank) * 0x4000 + usize::from(address) - 0x4000;
                 *self.rom.get(a).unwrap_or(&0)
             }
-            0xa000..=0xbfff => {
-                if self.ram_enabled {
-                    let a = usize::from(self.ram_bank) * 0x2000 + usize::from(address) - 0xa000;
-                    *self.ram.get(a).unwrap_or(&0)
-                } else {
-                    0
-                }
+            0xa000..=0xbfff if self.ram_enabled => {
+                let a = usize::from(self.ram_bank) * 0x2000 + usize::from(address) - 0xa000;
+                *self.ram.get(a).unwrap_or(&0)
             }
             _ => 0,
         }
@@ -59,7 +56,9 @@ impl Memory for Mbc5 {
             0xa000..=0xbfff => {
                 if self.ram_enabled {
                     let a = usize::from(self.ram_bank) * 0x2000 + usize::from(address) - 0xa000;
-                    self.ram[a] = value;
+                    if let Some(v) = self.ram.get_mut(a) {
+                        *v = value;
+                    }
                 }
             }
             _ => println!("invalid write address {}", address),
@@ -67,7 +66,27 @@ impl Memory for Mbc5 {
     }
 }
 
-impl MBC for Mbc5 {}
+impl SaveState for Mbc5 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.rom_bank.save(w)?;
+        self.ram.save(w)?;
+        self.ram_bank.save(w)?;
+        self.ram_enabled.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.rom_bank.load(r)?;
+        self.ram.load(r)?;
+        self.ram_bank.load(r)?;
+        self.ram_enabled.load(r)
+    }
+}
+
+impl MBC for Mbc5 {
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank
+    }
+}
 
 impl Drop for Mbc5 {
     fn drop(&mut self) {
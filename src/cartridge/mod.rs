@@ -5,6 +5,7 @@ mod mbc5;
 mod rom_only;
 
 use crate::memory::Memory;
+use crate::savestate::{invalid_data, SaveState};
 use mbc1::Mbc1;
 use mbc2::Mbc2;
 use mbc3::Mbc3;
@@ -12,7 +13,7 @@ use mbc5::Mbc5;
 use rom_only::RomOnly;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
@@ -182,43 +183,58 @@ const GBC_BOOT_ROM: [u8; 0x800] = [
     0x12, 0xb0, 0x79, 0xb8, 0xad, 0x16, 0x17, 0x07, 0xba, 0x05, 0x7c, 0x13, 0x00, 0x00, 0x00, 0x00,
 ];
 
-fn read_title(data: &Vec<u8>) -> String {
-    let mut end = 0;
-    for i in 0x134..0x142 {
-        end = i;
-        if data[i] == 0 {
-            break;
-        }
-    }
-    String::from_utf8(data[0x134..end].to_vec()).unwrap_or("unknown".to_string())
+fn read_title(data: &[u8]) -> String {
+    let title = &data[0x134..0x142];
+    let end = title
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(title.len() - 1);
+    String::from_utf8(title[..end].to_vec()).unwrap_or_else(|_| "unknown".to_string())
 }
 
-fn rom_size(hex: u8) -> usize {
-    0x4000 // 16KB
-        * match hex {
-            0x00 => 2,
-            0x01 => 4,
-            0x02 => 8,
-            0x03 => 16,
-            0x04 => 32,
-            0x05 => 64,
-            0x06 => 128,
-            0x07 => 256,
-            0x08 => 512,
-            _ => panic!("invalid rom size hex"),
-        }
+fn rom_size(hex: u8) -> Option<usize> {
+    let banks = match hex {
+        0x00 => 2,
+        0x01 => 4,
+        0x02 => 8,
+        0x03 => 16,
+        0x04 => 32,
+        0x05 => 64,
+        0x06 => 128,
+        0x07 => 256,
+        0x08 => 512,
+        _ => return None,
+    };
+    Some(0x4000 * banks) // 16KB banks
 }
 
-fn ram_size(hex: u8) -> usize {
+fn ram_size(hex: u8) -> Option<usize> {
     match hex {
-        0x00 => 0,
-        0x01 => 0x0800,
-        0x02 => 0x2000,
-        0x03 => 0x2000 * 4,
-        0x04 => 0x2000 * 16,
-        0x05 => 0x2000 * 8,
-        _ => panic!("invalid ram size hex"),
+        0x00 => Some(0),
+        0x01 => Some(0x0800),
+        0x02 => Some(0x2000),
+        0x03 => Some(0x2000 * 4),
+        0x04 => Some(0x2000 * 16),
+        0x05 => Some(0x2000 * 8),
+        _ => None,
+    }
+}
+
+// Check the header fields the MBCs rely on so a bad file is an error, not a panic.
+fn check_header(data: &[u8]) -> io::Result<CartridgeType> {
+    if data.len() < 0x150 {
+        return Err(invalid_data("ROM is smaller than its header"));
+    }
+    let cart_type =
+        CartridgeType::new(data[0x147]).ok_or_else(|| invalid_data("invalid cartridge type"))?;
+    let rom_size = rom_size(data[0x148]).ok_or_else(|| invalid_data("invalid ROM size"))?;
+    if data.len() > rom_size {
+        return Err(invalid_data("ROM is larger than its header says"));
     }
+    if ram_size(data[0x149]).is_none() {
+        return Err(invalid_data("invalid RAM size"));
+    }
+    Ok(cart_type)
 }
 
 #[derive(Debug)]
@@ -248,8 +264,8 @@ enum CartridgeType {
 }
 
 impl CartridgeType {
-    fn new(n: u8) -> CartridgeType {
-        match n {
+    fn new(n: u8) -> Option<CartridgeType> {
+        Some(match n {
             0x00 => CartridgeType::RomOnly,
             0x01 => CartridgeType::Mbc1,
             0x02 => CartridgeType::Mbc1Ram,
@@ -272,60 +288,57 @@ impl CartridgeType {
             0x1c => CartridgeType::Mbc5Rumble,
             0x1d => CartridgeType::Mbc5RumbleSram,
             0x1e => CartridgeType::Mbc5RumbleSramBattery,
-            _ => panic!("invalid CartridgeType hex {}", n),
-        }
+            _ => return None,
+        })
     }
 
     fn is_mbc1(&self) -> bool {
-        match self {
-            CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery
+        )
     }
 
     fn is_mbc2(&self) -> bool {
-        match self {
-            CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => true,
-            _ => false,
-        }
+        matches!(self, CartridgeType::Mbc2 | CartridgeType::Mbc2Battery)
     }
 
     fn is_mbc3(&self) -> bool {
-        match self {
+        matches!(
+            self,
             CartridgeType::Mbc3TimerBattery
-            | CartridgeType::Mbc3TimerRamBattery
-            | CartridgeType::Mbc3
-            | CartridgeType::Mbc3Ram
-            | CartridgeType::Mbc3RamBattery => true,
-            _ => false,
-        }
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc3
+                | CartridgeType::Mbc3Ram
+                | CartridgeType::Mbc3RamBattery
+        )
     }
 
     fn is_mbc5(&self) -> bool {
-        match self {
+        matches!(
+            self,
             CartridgeType::Mbc5
-            | CartridgeType::Mbc5Ram
-            | CartridgeType::Mbc5RamBattery
-            | CartridgeType::Mbc5Rumble
-            | CartridgeType::Mbc5RumbleSram
-            | CartridgeType::Mbc5RumbleSramBattery => true,
-            _ => false,
-        }
+                | CartridgeType::Mbc5Ram
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5Rumble
+                | CartridgeType::Mbc5RumbleSram
+                | CartridgeType::Mbc5RumbleSramBattery
+        )
     }
 
     fn is_battery(&self) -> bool {
-        match self {
+        matches!(
+            self,
             CartridgeType::Mbc1RamBattery
-            | CartridgeType::Mbc2Battery
-            | CartridgeType::RomRamBattery
-            | CartridgeType::Mmm01SramBattery
-            | CartridgeType::Mbc3TimerBattery
-            | CartridgeType::Mbc3TimerRamBattery
-            | CartridgeType::Mbc3RamBattery
-            | CartridgeType::Mbc5RamBattery
-            | CartridgeType::Mbc5RumbleSramBattery => true,
-            _ => false,
-        }
+                | CartridgeType::Mbc2Battery
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01SramBattery
+                | CartridgeType::Mbc3TimerBattery
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc3RamBattery
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5RumbleSramBattery
+        )
     }
 }
 
@@ -349,14 +362,19 @@ impl Battery {
         }
     }
 
-    fn save_ram(&self, ram: &Vec<u8>) {
+    fn save_ram(&self, ram: &[u8]) {
         File::create(self.sav_path.clone())
             .and_then(|mut f| f.write_all(ram))
             .unwrap();
     }
 }
 
-trait MBC: Memory + Send {}
+trait MBC: Memory + SaveState + Send {
+    // Bank currently mapped at 0x4000-0x7fff
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+}
 
 pub struct Cartridge {
     title: String,
@@ -371,30 +389,42 @@ impl Cartridge {
         self
     }
 
-    pub fn new<P: AsRef<Path>>(file_path: P, sav_path: Option<P>, force_cgb: bool) -> Self {
-        let mut file = File::open(&file_path).unwrap();
+    pub fn new<P: AsRef<Path>>(
+        file_path: P,
+        sav_path: Option<P>,
+        force_cgb: bool,
+    ) -> io::Result<Self> {
+        let mut file = File::open(&file_path)?;
         let mut data = Vec::new();
-        file.read_to_end(&mut data).unwrap();
+        file.read_to_end(&mut data)?;
+        check_header(&data)?;
 
+        let sav_path = match sav_path {
+            Some(p) => p.as_ref().to_path_buf(),
+            None => file_path
+                .as_ref()
+                .with_file_name(read_title(&data))
+                .with_extension("sav"),
+        };
+        Self::from_bytes(data, Some(sav_path), force_cgb)
+    }
+
+    // Battery backed RAM is only persisted when `sav_path` is given.
+    pub fn from_bytes(
+        data: Vec<u8>,
+        sav_path: Option<PathBuf>,
+        force_cgb: bool,
+    ) -> io::Result<Self> {
+        let cart_type = check_header(&data)?;
         let gb_type = GBType::new(data[0x143]);
         let is_gbc = match gb_type {
             GBType::NonCGB => false,
             GBType::CGB => true,
             GBType::Universal => force_cgb,
         };
-        let cart_type = CartridgeType::new(data[0x147]);
-        println!("CartridgeType: {:?}", cart_type);
         let title = read_title(&data);
         let battery = if cart_type.is_battery() {
-            let sav_path = match sav_path {
-                Some(p) => p.as_ref().to_path_buf(),
-                None => file_path
-                    .as_ref()
-                    .with_file_name(&title)
-                    .with_extension("sav")
-                    .to_path_buf(),
-            };
-            Some(Battery::new(sav_path))
+            sav_path.map(Battery::new)
         } else {
             None
         };
@@ -409,17 +439,21 @@ impl Cartridge {
         } else {
             Box::new(RomOnly::new(data))
         };
-        Cartridge {
+        Ok(Cartridge {
             title,
             mbc,
             skip_boot: false,
             is_gbc,
-        }
+        })
     }
 
     pub fn title(&self) -> &str {
         &self.title
     }
+
+    pub fn rom_bank(&self) -> u16 {
+        self.mbc.rom_bank()
+    }
 }
 
 impl Memory for Cartridge {
@@ -444,3 +478,15 @@ impl Memory for Cartridge {
         }
     }
 }
+
+impl SaveState for Cartridge {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.skip_boot.save(w)?;
+        self.mbc.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.skip_boot.load(r)?;
+        self.mbc.load(r)
+    }
+}
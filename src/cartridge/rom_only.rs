@@ -1,5 +1,7 @@
 use super::MBC;
 use crate::memory::Memory;
+use crate::savestate::SaveState;
+use std::io::{self, Read, Write};
 
 #[derive(Debug)]
 pub struct RomOnly {
@@ -15,7 +17,7 @@ impl RomOnly {
 impl Memory for RomOnly {
     fn read(&self, address: u16) -> u8 {
         match address {
-            0x0000..=0x7fff => self.memory[usize::from(address)],
+            0x0000..=0x7fff => *self.memory.get(usize::from(address)).unwrap_or(&0),
             _ => 0,
         }
     }
@@ -23,4 +25,14 @@ impl Memory for RomOnly {
     fn write(&mut self, _address: u16, _value: u8) {}
 }
 
+impl SaveState for RomOnly {
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 impl MBC for RomOnly {}
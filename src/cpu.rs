@@ -1,13 +1,18 @@
 use crate::memory::Memory;
 use crate::reg::Flag::{C, H, N, Z};
 use crate::reg::Registers;
+use crate::savestate::SaveState;
+use crate::trace::Tracer;
+use std::io::{self, Read, Write};
 
 pub struct CPU {
     pub reg: Registers,
     halted: bool,
+    stopped: bool,
     di: u8,
     ei: u8,
     ime: bool,
+    pub tracer: Option<Tracer>,
 }
 
 impl CPU {
@@ -20,15 +25,17 @@ impl CPU {
         CPU {
             reg,
             halted: false,
+            stopped: false,
             di: 0,
             ei: 0,
             ime: false,
+            tracer: None,
         }
     }
 }
 
-const OP_CYCLES: [u32; 256] = [
-    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, 0, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+pub(crate) const OP_CYCLES: [u32; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, 1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
     2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, 2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 2, 2, 2, 2, 2, 2, 0, 2, 1, 1, 1, 1, 1, 1, 2, 1,
@@ -38,7 +45,7 @@ const OP_CYCLES: [u32; 256] = [
     3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, 3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
 ];
 
-const CB_CYCLES: [u32; 256] = [
+pub(crate) const CB_CYCLES: [u32; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2,
@@ -51,6 +58,21 @@ const CB_CYCLES: [u32; 256] = [
 
 impl CPU {
     pub fn tick(&mut self, mem: &mut dyn Memory) -> u32 {
+        let cycles = self.step(mem);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.advance(cycles);
+        }
+        cycles
+    }
+
+    fn step(&mut self, mem: &mut dyn Memory) -> u32 {
+        if self.stopped {
+            // STOP mode is left by a joypad input
+            if mem.read(0xff0f) & 0x10 == 0 {
+                return 1;
+            }
+            self.stopped = false;
+        }
         self.update_ime();
 
         let c = self.handle_interrupts(mem);
@@ -60,9 +82,20 @@ impl CPU {
         if self.halted {
             return 1;
         }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.reg, mem);
+        }
         self.command(mem)
     }
 
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn resume(&mut self) {
+        self.stopped = false;
+    }
+
     fn update_ime(&mut self) {
         match self.di {
             2 => self.di = 1,
@@ -144,7 +177,11 @@ impl CPU {
                 self.reg.a = self.alu_rrc(self.reg.a);
                 self.reg.set_flag(Z, false);
             }
-            0x10 => {}
+            0x10 => {
+                // STOP is followed by an ignored byte
+                self.reg.pc += 1;
+                self.stopped = true;
+            }
             0x11 => {
                 let v = self.read_word(mem);
                 self.reg.set_de(v);
@@ -1169,7 +1206,7 @@ impl CPU {
         self.reg.set_flag(H, false);
         self.reg.set_flag(N, false);
         self.reg.set_flag(Z, a == 0x00);
-        (a >> 4) | (a << 4)
+        a.rotate_left(4)
     }
 
     fn alu_daa(&mut self) {
@@ -1236,7 +1273,7 @@ impl CPU {
 
     fn alu_rrc(&mut self, a: u8) -> u8 {
         let c = a & 0x01 == 0x01;
-        let r = if c { 0x80 | (a >> 1) } else { (a >> 1) };
+        let r = if c { 0x80 | (a >> 1) } else { a >> 1 };
         self.reg.set_flag(C, c);
         self.reg.set_flag(H, false);
         self.reg.set_flag(N, false);
@@ -1309,3 +1346,23 @@ impl CPU {
         self.reg.pc = (u32::from(self.reg.pc) as i32 + i32::from(n)) as u16;
     }
 }
+
+impl SaveState for CPU {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.reg.save(w)?;
+        self.halted.save(w)?;
+        self.stopped.save(w)?;
+        self.di.save(w)?;
+        self.ei.save(w)?;
+        self.ime.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.reg.load(r)?;
+        self.halted.load(r)?;
+        self.stopped.load(r)?;
+        self.di.load(r)?;
+        self.ei.load(r)?;
+        self.ime.load(r)
+    }
+}
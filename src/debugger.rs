@@ -0,0 +1,616 @@
+// Interactive debugger driven by text commands, usually read from stdin.
+
+use crate::disasm::decode;
+use crate::gameboy::Gameboy;
+use crate::gpu::Layer;
+use crate::memory::Memory;
+use std::cell::Cell;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+const HELP: &str = "\
+c, continue            resume execution
+s, step [n]            execute n instructions (default 1)
+n, next                step over CALL and RST
+out, finish            run until the current function returns
+b, break [bank:]addr   add a breakpoint
+w, watch addr [r|w|rw] add a watchpoint (default w)
+d, delete n            remove breakpoint n
+unwatch n              remove watchpoint n
+l, list                list breakpoints and watchpoints
+r, regs                show registers
+set reg value          change a register (a f b c d e h l af bc de hl sp pc)
+x addr [len]           dump memory
+dis [addr] [n]         disassemble n instructions (default 8 at PC)
+poke addr value        write a byte to memory
+hide bg|win|obj|n      hide a layer or OAM entry n from the screen
+show bg|win|obj|n      show it again
+q, quit                stop the emulator
+Numbers are hexadecimal, step counts and indexes too. Entering any command while running pauses.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub kind: WatchKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// Watchpoints checked by the MMU on every bus access. The first hit is
+// kept until the debugger takes it.
+#[derive(Default)]
+pub struct Watcher {
+    points: Vec<Watchpoint>,
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watcher {
+    pub fn add(&mut self, point: Watchpoint) {
+        self.points.push(point);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.points.len() {
+            Some(self.points.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn points(&self) -> &[Watchpoint] {
+        &self.points
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    pub fn check_read(&self, address: u16, value: u8) {
+        self.check(address, value, false);
+    }
+
+    pub fn check_write(&self, address: u16, value: u8) {
+        self.check(address, value, true);
+    }
+
+    fn check(&self, address: u16, value: u8, write: bool) {
+        if self.points.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        if self
+            .points
+            .iter()
+            .any(|p| p.address == address && p.kind.matches(write))
+        {
+            self.hit.set(Some(WatchHit {
+                address,
+                value,
+                write,
+            }));
+        }
+    }
+}
+
+// A PC breakpoint. When `bank` is set it only matches while that bank is
+// mapped at `address`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub bank: Option<u16>,
+}
+
+impl Breakpoint {
+    fn matches(&self, gameboy: &Gameboy) -> bool {
+        let pc = gameboy.cpu.reg.pc;
+        let bank_matches = match self.bank {
+            Some(bank) => gameboy.mmu.bank_at(pc) == bank,
+            None => true,
+        };
+        pc == self.address && bank_matches
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02x}:{:04x}", bank, self.address),
+            None => write!(f, "{:04x}", self.address),
+        }
+    }
+}
+
+enum Run {
+    Paused,
+    Continue,
+    Step(u32),
+    StepOver { address: u16, sp: u16 },
+    StepOut { sp: u16 },
+}
+
+enum Action {
+    Prompt,
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    commands: Receiver<String>,
+    breakpoints: Vec<Breakpoint>,
+    run: Run,
+    // Set on resume so that a breakpoint at the current PC is not hit again
+    resumed: bool,
+}
+
+impl Debugger {
+    // The machine starts paused at the prompt.
+    pub fn new(commands: Receiver<String>) -> Self {
+        Self {
+            commands,
+            breakpoints: Vec::new(),
+            run: Run::Paused,
+            resumed: false,
+        }
+    }
+
+    pub fn stdin() -> Self {
+        let (tx, rx) = channel();
+        thread::Builder::new()
+            .name("Debugger input".to_string())
+            .spawn(move || {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines().map_while(Result::ok) {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        Self::new(rx)
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    // Execute one instruction, stopping at the prompt first when paused or on a
+    // breakpoint. Returns None when the user quits or the command source is
+    // closed while paused.
+    pub fn step(&mut self, gameboy: &mut Gameboy) -> Option<u32> {
+        if !self.paused() {
+            match self.commands.try_recv() {
+                Ok(line) => {
+                    self.pause(gameboy);
+                    match self.execute(&line, gameboy) {
+                        Action::Prompt => {}
+                        Action::Resume => self.resumed = true,
+                        Action::Quit => return None,
+                    }
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        if !self.resumed && !self.paused() {
+            if let Some(i) = self.breakpoints.iter().position(|b| b.matches(gameboy)) {
+                println!("Breakpoint {:x} at {}", i, self.breakpoints[i]);
+                self.pause(gameboy);
+            }
+        }
+        self.resumed = false;
+        if self.paused() && !self.prompt(gameboy) {
+            return None;
+        }
+
+        let pc = gameboy.cpu.reg.pc;
+        let opcode = gameboy.mmu.peek(pc);
+        let clocks = gameboy.step_instruction();
+
+        if let Some(hit) = gameboy.mmu.watcher.take_hit() {
+            println!(
+                "Watchpoint: {} {:04x} = {:02x} at {:04x}",
+                if hit.write { "write" } else { "read" },
+                hit.address,
+                hit.value,
+                pc
+            );
+            self.pause(gameboy);
+            return Some(clocks);
+        }
+        let reg = &gameboy.cpu.reg;
+        let stop = match self.run {
+            Run::Paused | Run::Continue => false,
+            Run::Step(n) if n > 1 => {
+                self.run = Run::Step(n - 1);
+                false
+            }
+            Run::Step(_) => true,
+            Run::StepOver { address, sp } => reg.pc == address && reg.sp >= sp,
+            Run::StepOut { sp } => is_return(opcode) && reg.sp > sp,
+        };
+        if stop {
+            self.pause(gameboy);
+        }
+        Some(clocks)
+    }
+
+    fn paused(&self) -> bool {
+        matches!(self.run, Run::Paused)
+    }
+
+    fn pause(&mut self, gameboy: &Gameboy) {
+        self.run = Run::Paused;
+        let pc = gameboy.cpu.reg.pc;
+        println!(
+            "{:02x}:{:04x}  {:<20} {:?}",
+            gameboy.mmu.bank_at(pc),
+            pc,
+            decode(&gameboy.mmu, pc).to_string(),
+            gameboy.cpu.reg
+        );
+    }
+
+    // Read and execute commands until execution resumes. Returns false to quit.
+    fn prompt(&mut self, gameboy: &mut Gameboy) -> bool {
+        loop {
+            print!("(gbdb) ");
+            io::stdout().flush().ok();
+            let line = match self.commands.recv() {
+                Ok(line) => line,
+                Err(_) => return false,
+            };
+            match self.execute(&line, gameboy) {
+                Action::Prompt => {}
+                Action::Resume => return true,
+                Action::Quit => return false,
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str, gameboy: &mut Gameboy) -> Action {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let result = match args.first().copied().unwrap_or("") {
+            "" => Ok(Action::Prompt),
+            "h" | "help" => {
+                println!("{}", HELP);
+                Ok(Action::Prompt)
+            }
+            "c" | "continue" => {
+                self.run = Run::Continue;
+                Ok(Action::Resume)
+            }
+            "s" | "step" => args.get(1).map_or(Ok(1), |n| parse_number(n)).map(|n| {
+                self.run = Run::Step(u32::from(n.max(1)));
+                Action::Resume
+            }),
+            "n" | "next" => {
+                let reg = &gameboy.cpu.reg;
+                let instruction = decode(&gameboy.mmu, reg.pc);
+                self.run = match instruction.mnemonic {
+                    "CALL" | "RST" => Run::StepOver {
+                        address: instruction.next_address(),
+                        sp: reg.sp,
+                    },
+                    _ => Run::Step(1),
+                };
+                Ok(Action::Resume)
+            }
+            "out" | "finish" => {
+                self.run = Run::StepOut {
+                    sp: gameboy.cpu.reg.sp,
+                };
+                Ok(Action::Resume)
+            }
+            "b" | "break" => arg(&args, 1).and_then(parse_breakpoint).map(|b| {
+                println!("Breakpoint {:x} at {}", self.breakpoints.len(), b);
+                self.breakpoints.push(b);
+                Action::Prompt
+            }),
+            "w" | "watch" => arg(&args, 1).and_then(parse_number).and_then(|address| {
+                let kind = match args.get(2).copied() {
+                    None | Some("w") => WatchKind::Write,
+                    Some("r") => WatchKind::Read,
+                    Some("rw") => WatchKind::Access,
+                    Some(k) => return Err(format!("unknown watch kind {}", k)),
+                };
+                let watcher = &mut gameboy.mmu.watcher;
+                println!("Watchpoint {:x} at {:04x}", watcher.points().len(), address);
+                watcher.add(Watchpoint { address, kind });
+                Ok(Action::Prompt)
+            }),
+            "d" | "delete" => arg(&args, 1).and_then(parse_index).and_then(|i| {
+                if i < self.breakpoints.len() {
+                    self.breakpoints.remove(i);
+                    Ok(Action::Prompt)
+                } else {
+                    Err(format!("no breakpoint {:x}", i))
+                }
+            }),
+            "unwatch" => arg(&args, 1).and_then(parse_index).and_then(|i| {
+                match gameboy.mmu.watcher.remove(i) {
+                    Some(_) => Ok(Action::Prompt),
+                    None => Err(format!("no watchpoint {:x}", i)),
+                }
+            }),
+            "l" | "list" => {
+                for (i, b) in self.breakpoints.iter().enumerate() {
+                    println!("Breakpoint {:x} at {}", i, b);
+                }
+                for (i, w) in gameboy.mmu.watcher.points().iter().enumerate() {
+                    println!("Watchpoint {:x} at {:04x} ({:?})", i, w.address, w.kind);
+                }
+                Ok(Action::Prompt)
+            }
+            "r" | "regs" => {
+                println!("{:?}", gameboy.cpu.reg);
+                Ok(Action::Prompt)
+            }
+            "set" => arg(&args, 1).and_then(|name| {
+                let value = arg(&args, 2).and_then(parse_number)?;
+                set_register(gameboy, name, value).map(|_| Action::Prompt)
+            }),
+            "x" => arg(&args, 1).and_then(parse_number).and_then(|address| {
+                let len = args.get(2).map_or(Ok(0x10), |n| parse_number(n))?;
+                dump_memory(gameboy, address, len);
+                Ok(Action::Prompt)
+            }),
+            "dis" => args
+                .get(1)
+                .map_or(Ok(gameboy.cpu.reg.pc), |a| parse_number(a))
+                .and_then(|address| {
+                    let count = args.get(2).map_or(Ok(8), |n| parse_number(n))?;
+                    print_disassembly(gameboy, address, count);
+                    Ok(Action::Prompt)
+                }),
+            "poke" => arg(&args, 1).and_then(parse_number).and_then(|address| {
+                let value = arg(&args, 2).and_then(parse_number)?;
+                if value > 0xff {
+                    return Err(format!("{:x} is not a byte", value));
+                }
+                gameboy.mmu.write(address, value as u8);
+                gameboy.mmu.watcher.take_hit();
+                Ok(Action::Prompt)
+            }),
+            "hide" | "show" => arg(&args, 1).and_then(|target| {
+                let visible = args[0] == "show";
+                let gpu = &mut gameboy.mmu.gpu;
+                match target {
+                    "bg" => gpu.set_layer_visible(Layer::Background, visible),
+                    "win" => gpu.set_layer_visible(Layer::Window, visible),
+                    "obj" => gpu.set_layer_visible(Layer::Sprites, visible),
+                    n => match parse_number(n)? {
+                        n if n < 40 => gpu.set_sprite_visible(n as u8, visible),
+                        n => return Err(format!("no OAM entry {:x}", n)),
+                    },
+                }
+                Ok(Action::Prompt)
+            }),
+            "q" | "quit" => Ok(Action::Quit),
+            cmd => Err(format!("unknown command {}, try help", cmd)),
+        };
+        result.unwrap_or_else(|e| {
+            println!("{}", e);
+            Action::Prompt
+        })
+    }
+}
+
+fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i)
+        .copied()
+        .ok_or_else(|| "missing argument".to_string())
+}
+
+fn parse_number(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid number {}", s))
+}
+
+fn parse_index(s: &str) -> Result<usize, String> {
+    parse_number(s).map(usize::from)
+}
+
+// "addr" or "bank:addr"
+fn parse_breakpoint(s: &str) -> Result<Breakpoint, String> {
+    match s.find(':') {
+        Some(i) => Ok(Breakpoint {
+            address: parse_number(&s[i + 1..])?,
+            bank: Some(parse_number(&s[..i])?),
+        }),
+        None => Ok(Breakpoint {
+            address: parse_number(s)?,
+            bank: None,
+        }),
+    }
+}
+
+fn set_register(gameboy: &mut Gameboy, name: &str, value: u16) -> Result<(), String> {
+    let reg = &mut gameboy.cpu.reg;
+    let byte = || {
+        if value > 0xff {
+            Err(format!("{:x} does not fit in {}", value, name))
+        } else {
+            Ok(value as u8)
+        }
+    };
+    match name {
+        "a" => reg.a = byte()?,
+        "f" => reg.f = byte()? & 0xf0,
+        "b" => reg.b = byte()?,
+        "c" => reg.c = byte()?,
+        "d" => reg.d = byte()?,
+        "e" => reg.e = byte()?,
+        "h" => reg.h = byte()?,
+        "l" => reg.l = byte()?,
+        "af" => reg.set_af(value),
+        "bc" => reg.set_bc(value),
+        "de" => reg.set_de(value),
+        "hl" => reg.set_hl(value),
+        "sp" => reg.sp = value,
+        "pc" => reg.pc = value,
+        _ => return Err(format!("unknown register {}", name)),
+    }
+    Ok(())
+}
+
+fn dump_memory(gameboy: &Gameboy, address: u16, len: u16) {
+    for row in (0..len).step_by(0x10) {
+        let start = address.wrapping_add(row);
+        let bytes: Vec<String> = (0..(len - row).min(0x10))
+            .map(|i| format!("{:02x}", gameboy.mmu.peek(start.wrapping_add(i))))
+            .collect();
+        println!("{:04x}: {}", start, bytes.join(" "));
+    }
+}
+
+fn print_disassembly(gameboy: &Gameboy, mut address: u16, count: u16) {
+    for _ in 0..count {
+        let instruction = decode(&gameboy.mmu, address);
+        println!(
+            "{:02x}:{:04x}  {}",
+            gameboy.mmu.bank_at(address),
+            address,
+            instruction
+        );
+        address = instruction.next_address();
+    }
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xc0 | 0xc8 | 0xc9 | 0xd0 | 0xd8 | 0xd9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::rom;
+
+    // Run `rom` under the debugger with a fixed list of commands until the
+    // commands run out while paused.
+    fn run(rom: Vec<u8>, commands: &[&str]) -> Gameboy {
+        let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+        let (tx, rx) = channel();
+        for c in commands {
+            tx.send(c.to_string()).unwrap();
+        }
+        drop(tx);
+        let mut debugger = Debugger::new(rx);
+        for _ in 0..100_000 {
+            if debugger.step(&mut gameboy).is_none() {
+                return gameboy;
+            }
+        }
+        panic!("debugger did not stop");
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        // NOP; NOP; JR -4
+        let rom = rom(&[(0x100, &[0x00, 0x00, 0x18, 0xfc])]);
+        let gameboy = run(rom.clone(), &["break 101", "c"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x101);
+        // The first breakpoint is in another bank and never hit
+        let gameboy = run(rom, &["break 2:101", "break 0:100", "c"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x100);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        // LD A,42; NOP; LD (c000),A; LD A,(c000); JR -2
+        let rom = rom(&[(
+            0x100,
+            &[
+                0x3e, 0x42, 0x00, 0xea, 0x00, 0xc0, 0xfa, 0x00, 0xc0, 0x18, 0xfe,
+            ],
+        )]);
+        let gameboy = run(rom.clone(), &["watch c000", "c"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x106);
+        assert_eq!(gameboy.mmu.peek(0xc000), 0x42);
+        let gameboy = run(rom, &["watch $c000 r", "c"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x109);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        // CALL 0200; JR -2 ... 0200: NOP; RET
+        let rom = rom(&[
+            (0x100, &[0xcd, 0x00, 0x02, 0x18, 0xfe]),
+            (0x200, &[0x00, 0xc9]),
+        ]);
+        let gameboy = run(rom.clone(), &["next"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x103);
+        let gameboy = run(rom.clone(), &["s"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x200);
+        let gameboy = run(rom, &["s", "out"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x103);
+        assert_eq!(gameboy.cpu.reg.sp, 0xfffe);
+    }
+
+    #[test]
+    fn test_hex_arguments() {
+        // 0x20 NOPs; JR -2
+        let rom = rom(&[(0x100, &[0x00; 0x20]), (0x120, &[0x18, 0xfe])]);
+        let gameboy = run(rom.clone(), &["s 10"]);
+        assert_eq!(gameboy.cpu.reg.pc, 0x110);
+        // Breakpoint a is deleted, b is the one left at 0x10b
+        let mut commands = vec!["break 11f"; 10];
+        commands.extend(["break 10a", "break 10b", "delete a", "c"]);
+        let gameboy = run(rom, &commands);
+        assert_eq!(gameboy.cpu.reg.pc, 0x10b);
+    }
+
+    #[test]
+    fn test_edit() {
+        let rom = rom(&[(0x100, &[0x18, 0xfe])]);
+        let gameboy = run(
+            rom,
+            &[
+                "set a 12",
+                "set hl c0de",
+                "set f ff",
+                "poke c000 55",
+                "x c000 20",
+            ],
+        );
+        assert_eq!(gameboy.cpu.reg.a, 0x12);
+        assert_eq!(gameboy.cpu.reg.hl(), 0xc0de);
+        assert_eq!(gameboy.cpu.reg.f, 0xf0);
+        assert_eq!(gameboy.mmu.peek(0xc000), 0x55);
+    }
+
+    #[test]
+    fn test_hide() {
+        let rom = rom(&[(0x100, &[0x18, 0xfe])]);
+        let gameboy = run(
+            rom,
+            &["hide win", "hide obj", "show obj", "hide 27", "hide 28"],
+        );
+        let gpu = &gameboy.mmu.gpu;
+        assert!(!gpu.layer_visible(Layer::Window));
+        assert!(gpu.layer_visible(Layer::Background));
+        assert!(gpu.layer_visible(Layer::Sprites));
+        assert!(!gpu.sprite_visible(0x27));
+        assert!(gpu.sprite_visible(0x26));
+    }
+}
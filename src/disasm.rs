@@ -0,0 +1,254 @@
+// SM83 disassembler. Instructions are decoded through the Memory trait so
+// that it works on a running MMU as well as on a raw ROM image.
+
+use crate::cpu::{CB_CYCLES, OP_CYCLES};
+use crate::memory::Memory;
+
+// Operand placeholders are replaced by the immediate bytes following the
+// opcode: d8/d16 data, a8 high page address, a16 address, r8 jump offset and
+// e8 signed offset. "-" marks an unused opcode.
+#[rustfmt::skip]
+const OPCODES: [&str; 256] = [
+    "NOP", "LD BC,d16", "LD (BC),A", "INC BC", "INC B", "DEC B", "LD B,d8", "RLCA",
+    "LD (a16),SP", "ADD HL,BC", "LD A,(BC)", "DEC BC", "INC C", "DEC C", "LD C,d8", "RRCA",
+    "STOP d8", "LD DE,d16", "LD (DE),A", "INC DE", "INC D", "DEC D", "LD D,d8", "RLA",
+    "JR r8", "ADD HL,DE", "LD A,(DE)", "DEC DE", "INC E", "DEC E", "LD E,d8", "RRA",
+    "JR NZ,r8", "LD HL,d16", "LD (HL+),A", "INC HL", "INC H", "DEC H", "LD H,d8", "DAA",
+    "JR Z,r8", "ADD HL,HL", "LD A,(HL+)", "DEC HL", "INC L", "DEC L", "LD L,d8", "CPL",
+    "JR NC,r8", "LD SP,d16", "LD (HL-),A", "INC SP", "INC (HL)", "DEC (HL)", "LD (HL),d8", "SCF",
+    "JR C,r8", "ADD HL,SP", "LD A,(HL-)", "DEC SP", "INC A", "DEC A", "LD A,d8", "CCF",
+    "LD B,B", "LD B,C", "LD B,D", "LD B,E", "LD B,H", "LD B,L", "LD B,(HL)", "LD B,A",
+    "LD C,B", "LD C,C", "LD C,D", "LD C,E", "LD C,H", "LD C,L", "LD C,(HL)", "LD C,A",
+    "LD D,B", "LD D,C", "LD D,D", "LD D,E", "LD D,H", "LD D,L", "LD D,(HL)", "LD D,A",
+    "LD E,B", "LD E,C", "LD E,D", "LD E,E", "LD E,H", "LD E,L", "LD E,(HL)", "LD E,A",
+    "LD H,B", "LD H,C", "LD H,D", "LD H,E", "LD H,H", "LD H,L", "LD H,(HL)", "LD H,A",
+    "LD L,B", "LD L,C", "LD L,D", "LD L,E", "LD L,H", "LD L,L", "LD L,(HL)", "LD L,A",
+    "LD (HL),B", "LD (HL),C", "LD (HL),D", "LD (HL),E", "LD (HL),H", "LD (HL),L", "HALT", "LD (HL),A",
+    "LD A,B", "LD A,C", "LD A,D", "LD A,E", "LD A,H", "LD A,L", "LD A,(HL)", "LD A,A",
+    "ADD A,B", "ADD A,C", "ADD A,D", "ADD A,E", "ADD A,H", "ADD A,L", "ADD A,(HL)", "ADD A,A",
+    "ADC A,B", "ADC A,C", "ADC A,D", "ADC A,E", "ADC A,H", "ADC A,L", "ADC A,(HL)", "ADC A,A",
+    "SUB B", "SUB C", "SUB D", "SUB E", "SUB H", "SUB L", "SUB (HL)", "SUB A",
+    "SBC A,B", "SBC A,C", "SBC A,D", "SBC A,E", "SBC A,H", "SBC A,L", "SBC A,(HL)", "SBC A,A",
+    "AND B", "AND C", "AND D", "AND E", "AND H", "AND L", "AND (HL)", "AND A",
+    "XOR B", "XOR C", "XOR D", "XOR E", "XOR H", "XOR L", "XOR (HL)", "XOR A",
+    "OR B", "OR C", "OR D", "OR E", "OR H", "OR L", "OR (HL)", "OR A",
+    "CP B", "CP C", "CP D", "CP E", "CP H", "CP L", "CP (HL)", "CP A",
+    "RET NZ", "POP BC", "JP NZ,a16", "JP a16", "CALL NZ,a16", "PUSH BC", "ADD A,d8", "RST $00",
+    "RET Z", "RET", "JP Z,a16", "PREFIX", "CALL Z,a16", "CALL a16", "ADC A,d8", "RST $08",
+    "RET NC", "POP DE", "JP NC,a16", "-", "CALL NC,a16", "PUSH DE", "SUB d8", "RST $10",
+    "RET C", "RETI", "JP C,a16", "-", "CALL C,a16", "-", "SBC A,d8", "RST $18",
+    "LDH (a8),A", "POP HL", "LD (C),A", "-", "-", "PUSH HL", "AND d8", "RST $20",
+    "ADD SP,e8", "JP HL", "LD (a16),A", "-", "-", "-", "XOR d8", "RST $28",
+    "LDH A,(a8)", "POP AF", "LD A,(C)", "DI", "-", "PUSH AF", "OR d8", "RST $30",
+    "LD HL,SP+e8", "LD SP,HL", "LD A,(a16)", "EI", "-", "-", "CP d8", "RST $38",
+];
+
+const PLACEHOLDERS: [(&str, u16); 6] = [
+    ("d16", 2),
+    ("a16", 2),
+    ("d8", 1),
+    ("a8", 1),
+    ("r8", 1),
+    ("e8", 1),
+];
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+const CB_OPS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operands: String,
+    // Machine cycles. Conditional jumps, calls and returns take longer when
+    // the branch is taken.
+    pub cycles: u32,
+}
+
+impl Instruction {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length())
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+pub fn decode(mem: &dyn Memory, address: u16) -> Instruction {
+    let opcode = mem.peek(address);
+    if opcode == 0xcb {
+        let cbcode = mem.peek(address.wrapping_add(1));
+        let reg = REGISTERS[usize::from(cbcode & 0x07)];
+        let bit = (cbcode >> 3) & 0x07;
+        let (mnemonic, operands) = match cbcode >> 6 {
+            0 => (CB_OPS[usize::from(bit)], reg.to_string()),
+            1 => ("BIT", format!("{},{}", bit, reg)),
+            2 => ("RES", format!("{},{}", bit, reg)),
+            _ => ("SET", format!("{},{}", bit, reg)),
+        };
+        return Instruction {
+            address,
+            bytes: vec![opcode, cbcode],
+            mnemonic,
+            operands,
+            cycles: CB_CYCLES[usize::from(cbcode)],
+        };
+    }
+
+    let entry = OPCODES[usize::from(opcode)];
+    if entry == "-" {
+        return Instruction {
+            address,
+            bytes: vec![opcode],
+            mnemonic: "DB",
+            operands: format!("${:02x}", opcode),
+            cycles: 0,
+        };
+    }
+    let (mnemonic, template) = match entry.find(' ') {
+        Some(i) => (&entry[..i], &entry[i + 1..]),
+        None => (entry, ""),
+    };
+    let placeholder = PLACEHOLDERS.iter().find(|(p, _)| template.contains(p));
+    let size = placeholder.map_or(0, |(_, size)| *size);
+    let bytes: Vec<u8> = (0..=size)
+        .map(|i| mem.peek(address.wrapping_add(i)))
+        .collect();
+    let operands = match placeholder {
+        Some((p, _)) => template
+            .replace(p, &format_operand(p, &bytes, address))
+            .replace("+-", "-"),
+        None => template.to_string(),
+    };
+    Instruction {
+        address,
+        bytes,
+        mnemonic,
+        operands,
+        cycles: OP_CYCLES[usize::from(opcode)],
+    }
+}
+
+fn format_operand(placeholder: &str, bytes: &[u8], address: u16) -> String {
+    match placeholder {
+        "d16" | "a16" => format!("${:02x}{:02x}", bytes[2], bytes[1]),
+        "a8" => format!("$ff{:02x}", bytes[1]),
+        "r8" => {
+            let target = address.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16);
+            format!("${:04x}", target)
+        }
+        "e8" if (bytes[1] as i8) < 0 => format!("-${:02x}", (bytes[1] as i8).unsigned_abs()),
+        _ => format!("${:02x}", bytes[1]),
+    }
+}
+
+// Decode consecutive instructions in `start..end`.
+pub fn disassemble(mem: &dyn Memory, start: u16, end: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut address = u32::from(start);
+    while address < u32::from(end) {
+        let instruction = decode(mem, address as u16);
+        address += u32::from(instruction.length());
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// A ROM image with bank 0 at 0x0000-0x3fff and the chosen bank at
+// 0x4000-0x7fff. Everything else reads as 0xff and writes are ignored.
+pub struct RomBank<'a> {
+    rom: &'a [u8],
+    bank: usize,
+}
+
+impl<'a> RomBank<'a> {
+    pub fn new(rom: &'a [u8], bank: usize) -> Self {
+        Self { rom, bank }
+    }
+
+    pub fn banks(rom: &[u8]) -> usize {
+        rom.len().div_ceil(0x4000)
+    }
+}
+
+impl Memory for RomBank<'_> {
+    fn read(&self, address: u16) -> u8 {
+        let a = match address {
+            0x0000..=0x3fff => usize::from(address),
+            0x4000..=0x7fff => self.bank * 0x4000 + usize::from(address) - 0x4000,
+            _ => return 0xff,
+        };
+        *self.rom.get(a).unwrap_or(&0xff)
+    }
+
+    fn write(&mut self, _address: u16, _value: u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8]) -> Instruction {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x150..0x150 + bytes.len()].copy_from_slice(bytes);
+        decode(&RomBank::new(&rom, 1), 0x150)
+    }
+
+    #[test]
+    fn test_decode() {
+        let i = decode_bytes(&[0x3e, 0x42]);
+        assert_eq!(
+            (i.to_string(), i.length(), i.cycles),
+            ("LD A,$42".into(), 2, 2)
+        );
+        let i = decode_bytes(&[0xc3, 0x50, 0x01]);
+        assert_eq!(
+            (i.to_string(), i.length(), i.cycles),
+            ("JP $0150".into(), 3, 4)
+        );
+        let i = decode_bytes(&[0x20, 0xfe]);
+        assert_eq!(i.to_string(), "JR NZ,$0150");
+        assert_eq!(i.next_address(), 0x152);
+        let i = decode_bytes(&[0xe0, 0x40]);
+        assert_eq!(i.to_string(), "LDH ($ff40),A");
+        let i = decode_bytes(&[0xf8, 0xfe]);
+        assert_eq!(i.to_string(), "LD HL,SP-$02");
+        let i = decode_bytes(&[0xe8, 0x05]);
+        assert_eq!(i.to_string(), "ADD SP,$05");
+        let i = decode_bytes(&[0x76]);
+        assert_eq!((i.to_string(), i.length()), ("HALT".into(), 1));
+        let i = decode_bytes(&[0xcb, 0x7c]);
+        assert_eq!(
+            (i.to_string(), i.length(), i.cycles),
+            ("BIT 7,H".into(), 2, 2)
+        );
+        let i = decode_bytes(&[0xcb, 0x36]);
+        assert_eq!((i.to_string(), i.cycles), ("SWAP (HL)".into(), 4));
+        let i = decode_bytes(&[0xd3]);
+        assert_eq!((i.to_string(), i.length()), ("DB $d3".into(), 1));
+    }
+
+    #[test]
+    fn test_disassemble_bank() {
+        let mut rom = vec![0u8; 0x10000];
+        rom[0x8000..0x8004].copy_from_slice(&[0x01, 0x34, 0x12, 0xc9]);
+        let bank = RomBank::new(&rom, 2);
+        let instructions = disassemble(&bank, 0x4000, 0x4005);
+        let text: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+        assert_eq!(text, ["LD BC,$1234", "RET", "NOP"]);
+        assert_eq!(RomBank::banks(&rom), 4);
+    }
+}
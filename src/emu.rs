@@ -1,19 +1,50 @@
-use crate::cartridge::Cartridge;
-use crate::cpu::CPU;
-use crate::gui::Window;
+use crate::blend::{BlendMode, FrameBlender};
+use crate::debugger::Debugger;
+use crate::gameboy::Gameboy;
+use crate::gpu::{Layer, TilePalette};
+use crate::gui::{ViewWindow, Window};
 use crate::joypad::JoypadKey;
-use crate::memory::MMU;
+use crate::palette::{ColorCorrection, DmgPalette};
+use crate::scale::{Filter, Image};
+use crate::serial::SerialLink;
 use crate::sound::AudioPlayer;
+use crate::trace::Tracer;
+use crate::wav::WavRecorder;
 use glium::glutin;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+#[cfg(feature = "audio")]
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+enum Input {
+    Key(glutin::ElementState, JoypadKey),
+    SaveState(u8),
+    LoadState(u8),
+    NextColorCorrection,
+    ToggleLayer(Layer),
+    PrintOam,
+    // Select the next OAM slot, or the previous one
+    SelectSprite(bool),
+    ToggleSprite,
+}
+
 pub struct Emulator<P: AsRef<Path>> {
     file_path: P,
     sav_path: Option<P>,
     mute: bool,
+    link: Option<Box<dyn SerialLink>>,
+    debug: bool,
+    tracer: Option<Tracer>,
+    access_blocking: bool,
+    palette: DmgPalette,
+    color_correction: ColorCorrection,
+    frame_blend: BlendMode,
+    filter: Filter,
+    debug_views: bool,
+    recorder: Option<WavRecorder<BufWriter<File>>>,
 }
 
 impl<P: AsRef<Path>> Emulator<P> {
@@ -22,6 +53,16 @@ impl<P: AsRef<Path>> Emulator<P> {
             file_path,
             sav_path: None,
             mute: false,
+            link: None,
+            debug: false,
+            tracer: None,
+            access_blocking: false,
+            palette: DmgPalette::default(),
+            color_correction: ColorCorrection::default(),
+            frame_blend: BlendMode::default(),
+            filter: Filter::default(),
+            debug_views: false,
+            recorder: None,
         }
     }
 
@@ -35,27 +76,123 @@ impl<P: AsRef<Path>> Emulator<P> {
         self
     }
 
+    pub fn link(mut self, link: Option<Box<dyn SerialLink>>) -> Self {
+        self.link = link;
+        self
+    }
+
+    // Start paused with a debugger prompt on stdin.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    pub fn trace(mut self, tracer: Option<Tracer>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    // Block CPU access to VRAM, OAM and palettes while the PPU uses them.
+    pub fn access_blocking(mut self, access_blocking: bool) -> Self {
+        self.access_blocking = access_blocking;
+        self
+    }
+
+    pub fn palette(mut self, palette: DmgPalette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    pub fn color_correction(mut self, color_correction: ColorCorrection) -> Self {
+        self.color_correction = color_correction;
+        self
+    }
+
+    // Blend frames before they are shown to smooth out flickering sprites.
+    pub fn frame_blend(mut self, frame_blend: BlendMode) -> Self {
+        self.frame_blend = frame_blend;
+        self
+    }
+
+    // Upscaling filter, F11 switches to the next one while running.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // Show VRAM, OAM and the palettes in extra windows.
+    pub fn debug_views(mut self, debug_views: bool) -> Self {
+        self.debug_views = debug_views;
+        self
+    }
+
+    // Write everything played to a WAV file, also when muted.
+    pub fn record_audio(mut self, recorder: Option<WavRecorder<BufWriter<File>>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
     pub fn run(self, skip_boot: bool) {
         let (data_tx, data_rx) = channel();
-        let (key_tx, key_rx) = channel();
-        let mut gameboy = Gameboy::new(self.file_path, self.sav_path, skip_boot);
-        let title = gameboy.mmu.title().to_owned();
+        let (input_tx, input_rx) = channel();
+        let state_path = self.file_path.as_ref().to_path_buf();
+        let mut gameboy = match Gameboy::new(&self.file_path, self.sav_path.as_ref(), skip_boot) {
+            Ok(gameboy) => gameboy,
+            Err(e) => {
+                eprintln!(
+                    "Failed to load {}: {}",
+                    self.file_path.as_ref().display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
+        let title = gameboy.title().to_owned();
+        if let Some(link) = self.link {
+            gameboy.set_serial_link(link);
+        }
+        gameboy.set_tracer(self.tracer);
+        gameboy.set_access_blocking(self.access_blocking);
+        gameboy.set_dmg_palette(self.palette);
+        gameboy.set_color_correction(self.color_correction);
 
         // Sound
-        if !self.mute {
-            if let Some((player, event_loop, shared_buffer)) = CpalPlayer::new() {
-                gameboy.mmu.enable_sound(Box::new(player));
-                thread::spawn(move || Self::run_cpal_thread(event_loop, shared_buffer));
-            }
+        let player = if self.mute {
+            None
+        } else {
+            Self::audio_player()
+        };
+        match (self.recorder, player) {
+            (Some(recorder), player) => gameboy.enable_sound(Box::new(recorder.player(player))),
+            (None, Some(player)) => gameboy.enable_sound(player),
+            (None, None) => {}
         }
 
+        let debugger = if self.debug {
+            Some(Debugger::stdin())
+        } else {
+            None
+        };
+
         // CPU
+        let blender = FrameBlender::new(self.frame_blend);
+        let (views_tx, views_rx) = channel();
+        let (views_tx, mut views_rx) = if self.debug_views {
+            (Some(views_tx), Some(views_rx))
+        } else {
+            (None, None)
+        };
         let cpu_thread = thread::Builder::new()
             .name("CPU thread".to_string())
-            .spawn(move || Self::run_cpu_thread(gameboy, data_tx, key_rx))
+            .spawn(move || {
+                Self::run_cpu_thread(
+                    gameboy, debugger, blender, state_path, data_tx, views_tx, input_rx,
+                )
+            })
             .unwrap();
 
-        let mut window = Window::new(title);
+        let mut window = Window::new(title, self.filter);
+        let mut views: Vec<Option<ViewWindow>> = Vec::new();
         let mut closed = false;
         while !closed {
             if let Ok(data) = data_rx.try_recv() {
@@ -68,17 +205,34 @@ impl<P: AsRef<Path>> Emulator<P> {
                     TryRecvError::Empty => {}
                 },
             }
+            if let Some(images) = views_rx.as_ref().and_then(|rx| rx.try_iter().last()) {
+                draw_views(&window, &mut views, images);
+            }
 
+            let main_id = window.id();
+            let view_ids: Vec<_> = views.iter().map(|v| v.as_ref().map(|v| v.id())).collect();
+            let mut closed_view = None;
+            let mut next_filter = false;
             window.poll_events(|event| {
                 closed = match event {
+                    glutin::Event::WindowEvent {
+                        event: glutin::WindowEvent::CloseRequested,
+                        window_id,
+                    } if window_id != main_id => {
+                        closed_view = view_ids.iter().position(|&id| id == Some(window_id));
+                        false
+                    }
                     glutin::Event::WindowEvent { event, .. } => match event {
                         glutin::WindowEvent::CloseRequested => true,
+                        glutin::WindowEvent::KeyboardInput { input, .. }
+                            if input.virtual_keycode == Some(glutin::VirtualKeyCode::F11) =>
+                        {
+                            next_filter |= input.state == glutin::ElementState::Pressed;
+                            false
+                        }
                         glutin::WindowEvent::KeyboardInput { input, .. } => {
-                            match input.virtual_keycode {
-                                Some(key) => match get_joypad_key(key) {
-                                    Some(key) => key_tx.send((input.state, key)).is_err(),
-                                    None => false,
-                                },
+                            match input.virtual_keycode.and_then(|key| get_input(key, input)) {
+                                Some(input) => input_tx.send(input).is_err(),
                                 None => false,
                             }
                         }
@@ -87,6 +241,18 @@ impl<P: AsRef<Path>> Emulator<P> {
                     _ => false,
                 }
             });
+            if let Some(i) = closed_view {
+                views[i] = None;
+                // Hanging up tells the CPU thread to stop building the views
+                if views.iter().all(Option::is_none) {
+                    views_rx = None;
+                }
+            }
+            if next_filter {
+                let filter = window.filter().next();
+                window.set_filter(filter);
+                println!("Filter: {}", filter.name());
+            }
         }
         drop(data_rx);
         cpu_thread.join().unwrap();
@@ -94,25 +260,111 @@ impl<P: AsRef<Path>> Emulator<P> {
 
     fn run_cpu_thread(
         mut gameboy: Gameboy,
+        mut debugger: Option<Debugger>,
+        mut blender: FrameBlender,
+        state_path: PathBuf,
         data_tx: Sender<Vec<u8>>,
-        key_rx: Receiver<(glutin::ElementState, JoypadKey)>,
+        mut views_tx: Option<Sender<Vec<Image>>>,
+        input_rx: Receiver<Input>,
     ) {
+        let mut link_connected = gameboy.serial_link_connected();
+        let mut selected_sprite = 0u8;
         'main: loop {
-            gameboy.tick();
+            match &mut debugger {
+                Some(debugger) => {
+                    if debugger.step(&mut gameboy).is_none() {
+                        break 'main;
+                    }
+                }
+                None => {
+                    gameboy.step_instruction();
+                }
+            }
             if gameboy.mmu.gpu.redraw {
                 gameboy.mmu.gpu.redraw = false;
-                let data = gameboy.mmu.gpu.get_rgb_data();
+                let data = blender.blend(gameboy.framebuffer());
                 if data_tx.send(data).is_err() {
                     break 'main;
                 }
+                // Stop once the window has closed all the views
+                if let Some(tx) = &views_tx {
+                    if tx.send(debug_views(&gameboy)).is_err() {
+                        views_tx = None;
+                    }
+                }
+                if link_connected && !gameboy.serial_link_connected() {
+                    link_connected = false;
+                    println!("Link cable disconnected");
+                }
             }
 
             'try_key: loop {
-                match key_rx.try_recv() {
-                    Ok((state, key)) => match state {
+                match input_rx.try_recv() {
+                    Ok(Input::Key(state, key)) => match state {
                         glutin::ElementState::Pressed => gameboy.mmu.keydown(key),
                         glutin::ElementState::Released => gameboy.mmu.keyup(key),
                     },
+                    Ok(Input::SaveState(slot)) => {
+                        let path = state_path.with_extension(format!("ss{}", slot));
+                        let result = File::create(&path).and_then(|f| {
+                            let mut w = BufWriter::new(f);
+                            gameboy.save_state(&mut w)?;
+                            w.flush()
+                        });
+                        match result {
+                            Ok(_) => println!("Saved state to {}", path.display()),
+                            Err(e) => println!("Failed to save state: {}", e),
+                        }
+                    }
+                    Ok(Input::LoadState(slot)) => {
+                        let path = state_path.with_extension(format!("ss{}", slot));
+                        let result = File::open(&path)
+                            .and_then(|f| gameboy.load_state(&mut BufReader::new(f)));
+                        match result {
+                            Ok(_) => {
+                                blender.reset();
+                                println!("Loaded state from {}", path.display())
+                            }
+                            Err(e) => println!("Failed to load state: {}", e),
+                        }
+                    }
+                    Ok(Input::ToggleLayer(layer)) => {
+                        let visible = !gameboy.mmu.gpu.layer_visible(layer);
+                        gameboy.mmu.gpu.set_layer_visible(layer, visible);
+                        let state = if visible { "shown" } else { "hidden" };
+                        println!("{:?} {}", layer, state);
+                    }
+                    Ok(Input::NextColorCorrection) => {
+                        let mode = gameboy.mmu.gpu.color_correction().next();
+                        gameboy.set_color_correction(mode);
+                        println!("Color correction: {}", mode.name());
+                    }
+                    Ok(Input::PrintOam) => {
+                        for entry in gameboy.mmu.gpu.oam_entries() {
+                            println!("{}", entry);
+                        }
+                    }
+                    Ok(Input::SelectSprite(next)) => {
+                        selected_sprite = if next {
+                            (selected_sprite + 1) % 40
+                        } else {
+                            (selected_sprite + 39) % 40
+                        };
+                        let gpu = &gameboy.mmu.gpu;
+                        let entry = gpu.oam_entries()[usize::from(selected_sprite)];
+                        let state = if gpu.sprite_visible(selected_sprite) {
+                            "shown"
+                        } else {
+                            "hidden"
+                        };
+                        println!("{} {}", entry, state);
+                    }
+                    Ok(Input::ToggleSprite) => {
+                        let visible = !gameboy.mmu.gpu.sprite_visible(selected_sprite);
+                        gameboy.mmu.gpu.set_sprite_visible(selected_sprite, visible);
+                        let state = if visible { "shown" } else { "hidden" };
+                        println!("Sprite {} {}", selected_sprite, state);
+                    }
                     Err(err) => match err {
                         TryRecvError::Disconnected => break 'main,
                         TryRecvError::Empty => break 'try_key,
@@ -122,6 +374,19 @@ impl<P: AsRef<Path>> Emulator<P> {
         }
     }
 
+    #[cfg(feature = "audio")]
+    fn audio_player() -> Option<Box<dyn AudioPlayer>> {
+        let (player, event_loop, shared_buffer) = CpalPlayer::new()?;
+        thread::spawn(move || Self::run_cpal_thread(event_loop, shared_buffer));
+        Some(Box::new(player))
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn audio_player() -> Option<Box<dyn AudioPlayer>> {
+        None
+    }
+
+    #[cfg(feature = "audio")]
     fn run_cpal_thread(
         event_loop: cpal::EventLoop,
         audio_buffer: Arc<Mutex<Vec<(f32, f32)>>>,
@@ -159,6 +424,79 @@ impl<P: AsRef<Path>> Emulator<P> {
     }
 }
 
+const DEBUG_VIEWS: [&str; 4] = ["Tiles", "Tile maps", "OAM", "Palettes"];
+
+// Images for the DEBUG_VIEWS windows: both VRAM banks, both tile maps, the
+// sprites and the palettes.
+fn debug_views(gameboy: &Gameboy) -> Vec<Image> {
+    let gpu = &gameboy.mmu.gpu;
+    vec![
+        gpu.tile_sheet(0, TilePalette::Grey)
+            .beside(&gpu.tile_sheet(1, TilePalette::Grey)),
+        gpu.tile_map_view(false).beside(&gpu.tile_map_view(true)),
+        gpu.oam_sheet(),
+        gpu.palette_sheet(),
+    ]
+}
+
+// Open the view windows on the first images, then update those still open.
+fn draw_views(window: &Window, views: &mut Vec<Option<ViewWindow>>, images: Vec<Image>) {
+    if views.is_empty() {
+        for (title, image) in DEBUG_VIEWS.iter().zip(&images) {
+            views.push(Some(window.open_view(title, image.width, image.height)));
+        }
+    }
+    for (view, image) in views.iter_mut().zip(images) {
+        if let Some(view) = view {
+            view.draw(image);
+        }
+    }
+}
+
+// F1-F9 load the state in the numbered slot, Shift+F1-F9 save to it.
+// F10 switches to the next color correction mode, F12 prints the decoded OAM
+// entries and 1-3 show or hide the background, window and sprites. 4 selects
+// the next OAM slot, Shift+4 the previous one, and 5 shows or hides it.
+fn get_input(key: glutin::VirtualKeyCode, input: glutin::KeyboardInput) -> Option<Input> {
+    if let Some(key) = get_joypad_key(key) {
+        return Some(Input::Key(input.state, key));
+    }
+    if input.state != glutin::ElementState::Pressed {
+        return None;
+    }
+    match key {
+        glutin::VirtualKeyCode::F10 => return Some(Input::NextColorCorrection),
+        glutin::VirtualKeyCode::F12 => return Some(Input::PrintOam),
+        glutin::VirtualKeyCode::Key1 => return Some(Input::ToggleLayer(Layer::Background)),
+        glutin::VirtualKeyCode::Key2 => return Some(Input::ToggleLayer(Layer::Window)),
+        glutin::VirtualKeyCode::Key3 => return Some(Input::ToggleLayer(Layer::Sprites)),
+        glutin::VirtualKeyCode::Key4 => return Some(Input::SelectSprite(!input.modifiers.shift)),
+        glutin::VirtualKeyCode::Key5 => return Some(Input::ToggleSprite),
+        _ => {}
+    }
+    let slot = get_state_slot(key)?;
+    if input.modifiers.shift {
+        Some(Input::SaveState(slot))
+    } else {
+        Some(Input::LoadState(slot))
+    }
+}
+
+fn get_state_slot(key: glutin::VirtualKeyCode) -> Option<u8> {
+    match key {
+        glutin::VirtualKeyCode::F1 => Some(1),
+        glutin::VirtualKeyCode::F2 => Some(2),
+        glutin::VirtualKeyCode::F3 => Some(3),
+        glutin::VirtualKeyCode::F4 => Some(4),
+        glutin::VirtualKeyCode::F5 => Some(5),
+        glutin::VirtualKeyCode::F6 => Some(6),
+        glutin::VirtualKeyCode::F7 => Some(7),
+        glutin::VirtualKeyCode::F8 => Some(8),
+        glutin::VirtualKeyCode::F9 => Some(9),
+        _ => None,
+    }
+}
+
 fn get_joypad_key(key: glutin::VirtualKeyCode) -> Option<JoypadKey> {
     match key {
         glutin::VirtualKeyCode::Up => Some(JoypadKey::Up),
@@ -173,31 +511,13 @@ fn get_joypad_key(key: glutin::VirtualKeyCode) -> Option<JoypadKey> {
     }
 }
 
-struct Gameboy {
-    pub cpu: CPU,
-    pub mmu: MMU,
-}
-
-impl Gameboy {
-    fn new<P: AsRef<Path>>(file_path: P, sav_path: Option<P>, skip_boot: bool) -> Self {
-        let cartridge = Cartridge::new(file_path, sav_path, true).set_skip_boot(skip_boot);
-        Self {
-            cpu: CPU::new(skip_boot, cartridge.is_gbc),
-            mmu: MMU::new(cartridge, skip_boot),
-        }
-    }
-
-    fn tick(&mut self) {
-        let cycles = self.cpu.tick(&mut self.mmu);
-        self.mmu.tick(cycles * 4);
-    }
-}
-
+#[cfg(feature = "audio")]
 struct CpalPlayer {
     buffer: Arc<Mutex<Vec<(f32, f32)>>>,
     sample_rate: u32,
 }
 
+#[cfg(feature = "audio")]
 impl CpalPlayer {
     fn new() -> Option<(CpalPlayer, cpal::EventLoop, Arc<Mutex<Vec<(f32, f32)>>>)> {
         let device = match cpal::default_output_device() {
@@ -253,6 +573,7 @@ impl CpalPlayer {
     }
 }
 
+#[cfg(feature = "audio")]
 impl AudioPlayer for CpalPlayer {
     fn play(&mut self, buf_left: &[f32], buf_right: &[f32]) {
         debug_assert!(buf_left.len() == buf_right.len());
@@ -0,0 +1,507 @@
+use crate::cartridge::Cartridge;
+use crate::cpu::CPU;
+use crate::memory::MMU;
+use crate::palette::{ColorCorrection, DmgPalette};
+use crate::savestate::{read_header, write_header, SaveState};
+use crate::serial::SerialLink;
+use crate::sound::AudioPlayer;
+use crate::trace::Tracer;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// Clocks of one full frame: 154 lines * 456 clocks
+pub const FRAME_CLOCKS: u32 = 154 * 456;
+// Machine cycles the CPU is paused for while switching speed
+const SPEED_SWITCH_CYCLES: u32 = 2050;
+
+pub struct Gameboy {
+    pub cpu: CPU,
+    pub mmu: MMU,
+}
+
+impl Gameboy {
+    pub fn new<P: AsRef<Path>>(
+        file_path: P,
+        sav_path: Option<P>,
+        skip_boot: bool,
+    ) -> io::Result<Self> {
+        let cartridge = Cartridge::new(file_path, sav_path, true)?.set_skip_boot(skip_boot);
+        Ok(Self::with_cartridge(cartridge, skip_boot))
+    }
+
+    // Load a ROM image held in memory. Cartridge RAM is not backed by a sav file.
+    pub fn from_bytes(rom: Vec<u8>, skip_boot: bool) -> io::Result<Self> {
+        let cartridge = Cartridge::from_bytes(rom, None, true)?.set_skip_boot(skip_boot);
+        Ok(Self::with_cartridge(cartridge, skip_boot))
+    }
+
+    fn with_cartridge(cartridge: Cartridge, skip_boot: bool) -> Self {
+        Self {
+            cpu: CPU::new(skip_boot, cartridge.is_gbc),
+            mmu: MMU::new(cartridge, skip_boot),
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        self.mmu.title()
+    }
+
+    pub fn enable_sound(&mut self, player: Box<dyn AudioPlayer>) {
+        self.mmu.enable_sound(player);
+    }
+
+    pub fn set_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        self.mmu.set_serial_link(link);
+    }
+
+    pub fn serial_link_connected(&self) -> bool {
+        self.mmu.serial_link_connected()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.cpu.tracer = tracer;
+    }
+
+    // Block CPU access to VRAM, OAM and palettes while the PPU uses them, as
+    // hardware does. Off by default.
+    pub fn set_access_blocking(&mut self, access_blocking: bool) {
+        self.mmu.gpu.set_access_blocking(access_blocking);
+    }
+
+    // Colors for the four shades of DMG games.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.mmu.gpu.set_dmg_palette(palette);
+    }
+
+    // How the colors of CGB games are displayed, can be changed at any time.
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.mmu.gpu.set_color_correction(color_correction);
+    }
+
+    // Execute one instruction (or interrupt dispatch) and return the elapsed clocks.
+    pub fn step_instruction(&mut self) -> u32 {
+        let mut cycles = self.cpu.tick(&mut self.mmu);
+        if self.cpu.stopped() && self.mmu.switch_speed() {
+            self.cpu.resume();
+            cycles += SPEED_SWITCH_CYCLES;
+        }
+        self.mmu.tick(cycles * 4)
+    }
+
+    // Run until the next VBlank. Returns early after one frame's worth of clocks
+    // so that the caller is not blocked while the LCD is turned off.
+    pub fn run_frame(&mut self) -> u32 {
+        let mut clocks = 0;
+        while clocks < FRAME_CLOCKS {
+            clocks += self.step_instruction();
+            if self.mmu.gpu.redraw {
+                self.mmu.gpu.redraw = false;
+                break;
+            }
+        }
+        clocks
+    }
+
+    // RGB888 pixels of the last rendered screen, SCREEN_W * SCREEN_H * 3 bytes.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.mmu.gpu.framebuffer()
+    }
+
+    // Set the state of all buttons at once. `buttons` is a bitmask of `JoypadKey`
+    // values where a set bit means the button is held down.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.mmu.set_buttons(buttons);
+    }
+
+    pub fn save_state(&self, w: &mut impl Write) -> io::Result<()> {
+        write_header(w, self.title())?;
+        self.cpu.save(w)?;
+        self.mmu.save(w)
+    }
+
+    // On error the machine is left as it was before loading, unless restoring
+    // that fails too, in which case its error is returned instead.
+    pub fn load_state(&mut self, r: &mut impl Read) -> io::Result<()> {
+        let mut backup = Vec::new();
+        self.cpu.save(&mut backup)?;
+        self.mmu.save(&mut backup)?;
+        let result = read_header(r, self.mmu.title())
+            .and_then(|_| self.cpu.load(r))
+            .and_then(|_| self.mmu.load(r));
+        if result.is_err() {
+            let backup = &mut backup.as_slice();
+            self.cpu.load(backup)?;
+            self.mmu.load(backup)?;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{SCREEN_H, SCREEN_W};
+    use crate::memory::Memory;
+    use crate::test_util::{looping_rom, serial_rom};
+
+    #[test]
+    fn test_run_frame() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        let clocks = gameboy.run_frame();
+        assert!(clocks > 0 && clocks <= FRAME_CLOCKS + 24);
+        assert_eq!(gameboy.cpu.reg.pc, 0x100);
+        assert_eq!(gameboy.framebuffer().len(), SCREEN_W * SCREEN_H * 3);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        gameboy.run_frame();
+        let mut state = Vec::new();
+        gameboy.save_state(&mut state).unwrap();
+        let ly = gameboy.mmu.gpu.read(0xff44);
+
+        gameboy.cpu.reg.a = 0x42;
+        gameboy.mmu.write(0xc000, 0x99);
+        gameboy.step_instruction();
+        gameboy.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(gameboy.cpu.reg.a, 0x01);
+        assert_eq!(gameboy.mmu.read(0xc000), 0x00);
+        assert_eq!(gameboy.mmu.gpu.read(0xff44), ly);
+
+        let mut resaved = Vec::new();
+        gameboy.save_state(&mut resaved).unwrap();
+        assert_eq!(state, resaved);
+    }
+
+    #[test]
+    fn test_load_state_failure() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        let mut state = Vec::new();
+        gameboy.save_state(&mut state).unwrap();
+        gameboy.run_frame();
+        gameboy.mmu.write(0xc000, 0x99);
+        let mut before = Vec::new();
+        gameboy.save_state(&mut before).unwrap();
+
+        // Fails in the middle of the MMU state
+        let truncated = &state[..state.len() - 100];
+        assert!(gameboy.load_state(&mut &truncated[..]).is_err());
+        let mut after = Vec::new();
+        gameboy.save_state(&mut after).unwrap();
+        assert!(before == after);
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        let err = |rom: Vec<u8>| Gameboy::from_bytes(rom, true).err().unwrap().kind();
+        assert_eq!(err(vec![0; 0x14f]), io::ErrorKind::InvalidData);
+        let mut rom = looping_rom();
+        rom[0x147] = 0xff;
+        assert_eq!(err(rom), io::ErrorKind::InvalidData);
+        let mut rom = looping_rom();
+        rom[0x148] = 0x42;
+        assert_eq!(err(rom), io::ErrorKind::InvalidData);
+        let mut rom = looping_rom();
+        rom.resize(0x10000, 0);
+        assert_eq!(err(rom), io::ErrorKind::InvalidData);
+
+        // Only the header is needed, the rest reads as zero
+        let gameboy = Gameboy::from_bytes(vec![0; 0x150], true).unwrap();
+        assert_eq!(gameboy.mmu.peek(0x7fff), 0);
+    }
+
+    #[test]
+    fn test_double_speed() {
+        let mut rom = looping_rom();
+        rom[0x143] = 0xc0; // CGB only
+        rom[0x100..0x108].copy_from_slice(&[
+            0x3e, 0x01, // LD A,1
+            0xe0, 0x4d, // LDH (KEY1),A
+            0x10, 0x00, // STOP
+            0x18, 0xfe, // JR -2
+        ]);
+        let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+        assert_eq!(gameboy.mmu.read(0xff4d), 0x7e);
+        gameboy.step_instruction();
+        gameboy.step_instruction();
+        assert_eq!(gameboy.mmu.read(0xff4d), 0x7f);
+        gameboy.step_instruction();
+        assert_eq!(gameboy.mmu.read(0xff4d), 0xfe);
+        assert!(!gameboy.cpu.stopped());
+        assert_eq!(gameboy.cpu.reg.pc, 0x106);
+
+        // JR takes 12 CPU clocks, which is 6 clocks of the LCD at double speed
+        assert_eq!(gameboy.step_instruction(), 6);
+    }
+
+    #[test]
+    fn test_io_registers() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        assert_eq!(gameboy.mmu.read(0xff03), 0xff);
+        assert_eq!(gameboy.mmu.read(0xff7f), 0xff);
+        gameboy.mmu.write(0xff07, 0x00);
+        assert_eq!(gameboy.mmu.read(0xff07), 0xf8);
+        gameboy.mmu.write(0xff0f, 0x00);
+        assert_eq!(gameboy.mmu.read(0xff0f), 0xe0);
+        gameboy.mmu.write(0xff26, 0x80);
+        gameboy.mmu.write(0xff23, 0x00);
+        gameboy.mmu.write(0xff24, 0x77);
+        assert_eq!(gameboy.mmu.read(0xff23), 0xbf);
+        assert_eq!(gameboy.mmu.read(0xff24), 0x77);
+        assert_eq!(gameboy.mmu.read(0xff26), 0xf0);
+        assert_eq!(gameboy.mmu.read(0xff27), 0xff);
+        // CGB registers are unmapped on DMG
+        gameboy.mmu.write(0xff4f, 0x01);
+        gameboy.mmu.write(0xff72, 0x42);
+        assert_eq!(gameboy.mmu.read(0xff4f), 0xff);
+        assert_eq!(gameboy.mmu.read(0xff72), 0xff);
+        assert_eq!(gameboy.mmu.read(0xff6a), 0xff);
+
+        let mut rom = looping_rom();
+        rom[0x143] = 0xc0; // CGB only
+        let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+        gameboy.mmu.write(0xff4f, 0x00);
+        assert_eq!(gameboy.mmu.read(0xff4f), 0xfe);
+        gameboy.mmu.write(0xff6a, 0x82);
+        gameboy.mmu.write(0xff6b, 0x1f);
+        assert_eq!(gameboy.mmu.read(0xff6a), 0xc3);
+        gameboy.mmu.write(0xff6a, 0x02);
+        assert_eq!(gameboy.mmu.read(0xff6b), 0x1f);
+        gameboy.mmu.write(0xff6c, 0x00);
+        assert_eq!(gameboy.mmu.read(0xff6c), 0xfe);
+        gameboy.mmu.write(0xff72, 0x42);
+        gameboy.mmu.write(0xff75, 0xff);
+        assert_eq!(gameboy.mmu.read(0xff72), 0x42);
+        assert_eq!(gameboy.mmu.read(0xff75), 0xff);
+        gameboy.mmu.write(0xff75, 0x00);
+        assert_eq!(gameboy.mmu.read(0xff75), 0x8f);
+        assert_eq!(gameboy.mmu.read(0xff76), 0x00);
+        assert_eq!(gameboy.mmu.read(0xff51), 0xff);
+    }
+
+    #[test]
+    fn test_pcm_registers() {
+        let mut rom = looping_rom();
+        rom[0x143] = 0xc0; // CGB only
+        let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+        gameboy.mmu.write(0xff26, 0x80);
+        gameboy.mmu.write(0xff12, 0xf0); // volume 15
+        gameboy.mmu.write(0xff11, 0x80); // 50% duty
+        gameboy.mmu.write(0xff13, 0x00);
+        gameboy.mmu.write(0xff14, 0x87); // trigger, 1024 clocks per duty step
+        let mut levels = Vec::new();
+        for _ in 0..128 {
+            gameboy.mmu.tick(64);
+            levels.push(gameboy.mmu.read(0xff76));
+        }
+        assert!(levels.contains(&0x0f));
+        assert!(levels.contains(&0x00));
+        assert!(levels.iter().all(|&v| v == 0x00 || v == 0x0f));
+        assert_eq!(gameboy.mmu.read(0xff77), 0x00);
+
+        gameboy.mmu.write(0xff26, 0x00);
+        gameboy.mmu.tick(4);
+        assert_eq!(gameboy.mmu.read(0xff76), 0x00);
+    }
+
+    #[test]
+    fn test_channel_status() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        gameboy.mmu.write(0xff26, 0x80);
+        gameboy.mmu.write(0xff12, 0xf0);
+        gameboy.mmu.write(0xff11, 0x3f); // length 1
+        gameboy.mmu.write(0xff14, 0xc0); // trigger with length enabled
+        assert_eq!(gameboy.mmu.read(0xff26) & 0x0f, 0x01);
+        // Long before the next output, NR52 still sees the length run out
+        gameboy.mmu.tick(0x8000);
+        assert_eq!(gameboy.mmu.read(0xff26) & 0x0f, 0x00);
+    }
+
+    #[test]
+    fn test_power_off() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        gameboy.mmu.write(0xff26, 0x80);
+        gameboy.mmu.write(0xff10, 0x7f);
+        gameboy.mmu.write(0xff11, 0xc0);
+        gameboy.mmu.write(0xff1c, 0x60);
+        gameboy.mmu.write(0xff24, 0x77);
+        gameboy.mmu.write(0xff25, 0xff);
+        gameboy.mmu.write(0xff30, 0x12);
+
+        // NR10-NR51 are cleared, wave RAM is kept and stays writable
+        gameboy.mmu.write(0xff26, 0x00);
+        assert_eq!(gameboy.mmu.read(0xff10), 0x80);
+        assert_eq!(gameboy.mmu.read(0xff11), 0x3f);
+        assert_eq!(gameboy.mmu.read(0xff1c), 0x9f);
+        assert_eq!(gameboy.mmu.read(0xff24), 0x00);
+        assert_eq!(gameboy.mmu.read(0xff25), 0x00);
+        assert_eq!(gameboy.mmu.read(0xff30), 0x12);
+        gameboy.mmu.write(0xff24, 0x77);
+        gameboy.mmu.write(0xff31, 0x34);
+        assert_eq!(gameboy.mmu.read(0xff24), 0x00);
+        assert_eq!(gameboy.mmu.read(0xff31), 0x34);
+
+        gameboy.mmu.write(0xff26, 0x80);
+        assert_eq!(gameboy.mmu.read(0xff25), 0x00);
+        assert_eq!(gameboy.mmu.read(0xff31), 0x34);
+    }
+
+    #[test]
+    fn test_record_powered_off() {
+        use crate::test_util::Shared;
+        use crate::wav::{SampleFormat, WavRecorder};
+
+        let out = Shared::default();
+        let recorder = WavRecorder::new(out.clone(), SampleFormat::Pcm16, 44100).unwrap();
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        gameboy.enable_sound(Box::new(recorder));
+        gameboy.mmu.write(0xff26, 0x00);
+        let mut clocks = 0;
+        for _ in 0..60 {
+            clocks += u64::from(gameboy.run_frame());
+        }
+        drop(gameboy);
+        let data = out.data();
+        let frames = u64::from(u32::from_le_bytes([data[46], data[47], data[48], data[49]]));
+        // The APU outputs 2000 samples at a time
+        let expected = clocks * 44100 / (1 << 22);
+        assert!(frames <= expected && frames + 2000 > expected);
+        assert!(data[58..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_serial_link_cable() {
+        use crate::serial::LinkCable;
+
+        let (cable1, cable2) = LinkCable::pair();
+        let mut master = Gameboy::from_bytes(serial_rom(0x42, 0x81), true).unwrap();
+        let mut slave = Gameboy::from_bytes(serial_rom(0x99, 0x80), true).unwrap();
+        master.set_serial_link(Box::new(cable1));
+        slave.set_serial_link(Box::new(cable2));
+        let mut clocks = 0;
+        while clocks < 8 * 512 + 64 {
+            clocks += master.step_instruction();
+            slave.step_instruction();
+        }
+        assert_eq!(master.mmu.read(0xff01), 0x99);
+        assert_eq!(slave.mmu.read(0xff01), 0x42);
+        assert_eq!(master.mmu.read(0xff02) & 0x80, 0);
+        assert_eq!(slave.mmu.read(0xff02) & 0x80, 0);
+        assert_ne!(master.mmu.read(0xff0f) & 0x08, 0);
+        assert_ne!(slave.mmu.read(0xff0f) & 0x08, 0);
+    }
+
+    #[test]
+    fn test_serial_transfer_timing() {
+        let mut gameboy = Gameboy::from_bytes(serial_rom(b'A', 0x81), true).unwrap();
+        for _ in 0..4 {
+            gameboy.step_instruction();
+        }
+        // The transfer started during the 12 clocks of the last LDH
+        let mut clocks = 12;
+        while clocks < 8 * 512 - 16 {
+            clocks += gameboy.step_instruction();
+            assert_eq!(gameboy.mmu.read(0xff01), b'A');
+        }
+        while clocks < 8 * 512 + 16 {
+            clocks += gameboy.step_instruction();
+        }
+        // Nothing connected shifts in ones
+        assert_eq!(gameboy.mmu.read(0xff01), 0xff);
+        assert_ne!(gameboy.mmu.read(0xff0f) & 0x08, 0);
+    }
+
+    #[test]
+    fn test_access_blocking() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        gameboy.mmu.write(0x8000, 0x42);
+        gameboy.mmu.write(0xfe00, 0x42);
+        gameboy.set_access_blocking(true);
+        // Mode 2 locks OAM only
+        assert_eq!(gameboy.mmu.read(0xff41) & 0x03, 2);
+        assert_eq!(gameboy.mmu.read(0x8000), 0x42);
+        assert_eq!(gameboy.mmu.read(0xfe00), 0xff);
+        while gameboy.mmu.read(0xff41) & 0x03 != 3 {
+            gameboy.step_instruction();
+        }
+        assert_eq!(gameboy.mmu.read(0x8000), 0xff);
+        gameboy.mmu.write(0x8000, 0x99);
+        gameboy.mmu.write(0xfe00, 0x99);
+        assert_eq!(gameboy.mmu.peek(0x8000), 0x42);
+        assert_eq!(gameboy.mmu.peek(0xfe00), 0x42);
+
+        gameboy.set_access_blocking(false);
+        assert_eq!(gameboy.mmu.read(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        for i in 0..0xa0 {
+            gameboy.mmu.write(0xc000 + i, i as u8);
+            gameboy.mmu.write(0xc100 + i, 0x80 | i as u8);
+        }
+        gameboy.mmu.write(0xff80, 0x42);
+        gameboy.mmu.write(0xff46, 0xc0);
+        assert_eq!(gameboy.mmu.read(0xff46), 0xc0);
+        gameboy.mmu.tick(4 * 11);
+        // Ten bytes copied, only HRAM and IO are accessible
+        assert_eq!(gameboy.mmu.peek(0xfe09), 0x09);
+        assert_eq!(gameboy.mmu.peek(0xfe0a), 0x00);
+        assert_eq!(gameboy.mmu.read(0xfe00), 0xff);
+        assert_eq!(gameboy.mmu.read(0xd000), 0x09);
+        assert_eq!(gameboy.mmu.read(0xff80), 0x42);
+        gameboy.mmu.write(0xc000, 0x99);
+        assert_eq!(gameboy.mmu.peek(0xc000), 0x00);
+
+        // Restart from another source halfway through
+        gameboy.mmu.tick(4 * 70);
+        gameboy.mmu.write(0xff46, 0xc1);
+        gameboy.mmu.tick(4 * 2);
+        assert_eq!(gameboy.mmu.peek(0xfe50), 0x50);
+        assert_eq!(gameboy.mmu.peek(0xfe00), 0x80);
+        assert_eq!(gameboy.mmu.peek(0xfe01), 0x01);
+        gameboy.mmu.tick(4 * 158);
+        assert_eq!(gameboy.mmu.peek(0xfe9e), 0x9e);
+        assert_eq!(gameboy.mmu.read(0xc000), 0x9e);
+        gameboy.mmu.tick(4);
+        assert_eq!(gameboy.mmu.read(0xfe9f), 0x9f);
+        assert_eq!(gameboy.mmu.read(0xc000), 0x00);
+    }
+
+    #[test]
+    fn test_hdma_addresses() {
+        let mut rom = looping_rom();
+        rom[0x143] = 0xc0; // CGB only
+        let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+        for i in 0..0x20 {
+            gameboy.mmu.write(0xc000 + i, i as u8 + 1);
+        }
+        // The low four bits are dropped and the destination lands in VRAM
+        gameboy.mmu.write(0xff51, 0xc0);
+        gameboy.mmu.write(0xff52, 0x0f);
+        gameboy.mmu.write(0xff53, 0xff);
+        gameboy.mmu.write(0xff54, 0xf5);
+        // Two blocks as general purpose DMA
+        gameboy.mmu.write(0xff55, 0x01);
+        gameboy.mmu.tick(4);
+        assert_eq!(gameboy.mmu.read(0x9ff0), 0x01);
+        assert_eq!(gameboy.mmu.read(0x9fff), 0x10);
+        // The second block wraps to the start of VRAM
+        assert_eq!(gameboy.mmu.read(0x8000), 0x11);
+        assert_eq!(gameboy.mmu.read(0x800f), 0x20);
+    }
+
+    #[test]
+    fn test_set_buttons() {
+        use crate::joypad::JoypadKey;
+
+        let mut gameboy = Gameboy::from_bytes(looping_rom(), true).unwrap();
+        gameboy.mmu.write(0xff00, 0x20); // select direction keys
+        gameboy.set_buttons(JoypadKey::Left as u8 | JoypadKey::A as u8);
+        assert_eq!(gameboy.mmu.read(0xff00) & 0x0f, 0b1101);
+        gameboy.set_buttons(0);
+        assert_eq!(gameboy.mmu.read(0xff00) & 0x0f, 0b1111);
+    }
+}
@@ -1,18 +1,15 @@
 use super::memory::{InterruptFlag, InterruptType, Memory, RAM};
+use crate::palette::{ColorCorrection, DmgPalette, Rgb, Shades};
+use crate::savestate::{invalid_data, SaveState};
+use crate::scale::Image;
 use crate::util::is_bit_on;
+use std::io::{self, Read, Write};
 
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 const DATA_SIZE: usize = SCREEN_W * SCREEN_H * 3;
 type ScreenData = [u8; DATA_SIZE];
 
-#[derive(PartialEq, Copy, Clone)]
-enum BgPrio {
-    Color0,
-    PrioFlag,
-    Normal,
-}
-
 struct Lcdc {
     inner: u8,
 }
@@ -89,11 +86,45 @@ enum StatMode {
     VRAM = 3,
 }
 
+impl From<u8> for StatMode {
+    fn from(n: u8) -> Self {
+        match n & 0b11 {
+            0 => StatMode::HBlank,
+            1 => StatMode::VBlank,
+            2 => StatMode::OAM,
+            _ => StatMode::VRAM,
+        }
+    }
+}
+
+impl SaveState for Stat {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.ly_interrupt_enabled.save(w)?;
+        self.oam_interrupt_enabled.save(w)?;
+        self.vblank_interrupt_enabled.save(w)?;
+        self.hblank_interrupt_enabled.save(w)?;
+        (self.mode as u8).save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.ly_interrupt_enabled.load(r)?;
+        self.oam_interrupt_enabled.load(r)?;
+        self.vblank_interrupt_enabled.load(r)?;
+        self.hblank_interrupt_enabled.load(r)?;
+        let mut mode = 0u8;
+        mode.load(r)?;
+        self.mode = StatMode::from(mode);
+        Ok(())
+    }
+}
+
+// DMG shade, an index into the shade tables of `DmgPalette`
+#[derive(Clone, Copy)]
 pub enum MonoColor {
-    White = 0xff,
-    Light = 0xc0,
-    Dark = 0x60,
-    Black = 0x00,
+    White = 0,
+    Light = 1,
+    Dark = 2,
+    Black = 3,
 }
 
 impl MonoColor {
@@ -133,13 +164,200 @@ impl From<u8> for Attr {
     }
 }
 
+// Layers that can be hidden from the output without the game noticing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
+// Dots (4 MHz clocks) per line, of which the OAM scan takes the first 80
+const LINE_DOTS: u32 = 456;
+const OAM_SCAN_DOTS: u32 = 80;
+// Dots taken by a sprite fetch once the background fetcher is idle
+const SPRITE_FETCH_DOTS: u8 = 6;
+// LY reads 0 from this dot of line 153 on
+const LY_153_DOTS: u32 = 4;
+// Sprites the OAM scan selects per line, later ones are not drawn
+const MAX_LINE_SPRITES: usize = 10;
+
+// Sprite selected by the OAM scan for the current line
+#[derive(Default, Clone, Copy)]
+struct LineSprite {
+    index: u8,
+    x: u8,
+    fetched: bool,
+}
+
+impl SaveState for LineSprite {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.index.save(w)?;
+        self.x.save(w)?;
+        self.fetched.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.index.load(r)?;
+        self.x.load(r)?;
+        self.fetched.load(r)?;
+        if self.index >= 40 {
+            return Err(invalid_data("invalid PPU state"));
+        }
+        Ok(())
+    }
+}
+
+// A pixel waiting in a FIFO. `attr` holds the CGB tile attributes for the
+// background and the OAM flags for sprites.
+#[derive(Default, Clone, Copy)]
+struct Pixel {
+    color: u8,
+    attr: u8,
+    index: u8,
+}
+
+impl SaveState for Pixel {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.color.save(w)?;
+        self.attr.save(w)?;
+        self.index.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.color.load(r)?;
+        self.attr.load(r)?;
+        self.index.load(r)
+    }
+}
+
+#[derive(Default)]
+struct Fifo {
+    pixels: [Pixel; 8],
+    len: usize,
+}
+
+impl Fifo {
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn pop(&mut self) -> Pixel {
+        let pixel = self.pixels[0];
+        self.pixels.copy_within(1.., 0);
+        self.pixels[7] = Pixel::default();
+        self.len = self.len.saturating_sub(1);
+        pixel
+    }
+}
+
+impl SaveState for Fifo {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.pixels.save(w)?;
+        self.len.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.pixels.load(r)?;
+        self.len.load(r)?;
+        if self.len > 8 || self.pixels.iter().any(|p| p.color > 3) {
+            return Err(invalid_data("invalid PPU state"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum FetchStep {
+    Tile = 0,
+    DataLow = 1,
+    DataHigh = 2,
+    Push = 3,
+}
+
+impl From<u8> for FetchStep {
+    fn from(n: u8) -> Self {
+        match n & 0b11 {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            _ => FetchStep::Push,
+        }
+    }
+}
+
+// Background and window tile fetcher. Every step but Push takes two dots,
+// Push waits until the background FIFO is empty.
+struct Fetcher {
+    step: FetchStep,
+    dots: u8,
+    // Tile column, counted from the left of the line or of the window
+    x: u8,
+    window: bool,
+    // The first tile of a line is fetched twice
+    first: bool,
+    tile: u8,
+    attr: u8,
+    low: u8,
+    high: u8,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self {
+            step: FetchStep::Tile,
+            dots: 0,
+            x: 0,
+            window: false,
+            first: true,
+            tile: 0,
+            attr: 0,
+            low: 0,
+            high: 0,
+        }
+    }
+}
+
+impl SaveState for Fetcher {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.step as u8).save(w)?;
+        self.dots.save(w)?;
+        self.x.save(w)?;
+        self.window.save(w)?;
+        self.first.save(w)?;
+        self.tile.save(w)?;
+        self.attr.save(w)?;
+        self.low.save(w)?;
+        self.high.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut step = 0u8;
+        step.load(r)?;
+        self.step = FetchStep::from(step);
+        self.dots.load(r)?;
+        self.x.load(r)?;
+        self.window.load(r)?;
+        self.first.load(r)?;
+        self.tile.load(r)?;
+        self.attr.load(r)?;
+        self.low.load(r)?;
+        self.high.load(r)?;
+        if self.dots >= 2 {
+            return Err(invalid_data("invalid PPU state"));
+        }
+        Ok(())
+    }
+}
+
 pub struct GPU {
     is_gbc: bool,
     pub blanked: bool,
     data: ScreenData,
     pub redraw: bool,
     bgp: u8,
-    clocks: u32,
+    // Dot within the current line
+    dots: u32,
     lcdc: Lcdc,
     ly: u8,
     ly_compare: u8,
@@ -153,9 +371,47 @@ pub struct GPU {
     stat: Stat,
     wx: u8,
     wy: u8,
-    bg_prio: [BgPrio; SCREEN_W],
     bg_palette: Palette,
     sprite_palette: Palette,
+    line_sprites: [LineSprite; MAX_LINE_SPRITES],
+    sprite_count: usize,
+    // OPRI, bit 0 selects DMG style priority by X coordinate on CGB
+    opri: u8,
+    fetcher: Fetcher,
+    bg_fifo: Fifo,
+    sprite_fifo: Fifo,
+    // Next pixel to output on the current line
+    lx: u8,
+    // Pixels still to drop from the background FIFO
+    discard: u8,
+    // Window row to fetch, counting only lines on which the window was drawn
+    window_line: u8,
+    // Set once LY matched WY in the current frame
+    wy_triggered: bool,
+    // The window started at WX=166 and covers all of the next line
+    window_wraps: bool,
+    window_from_left: bool,
+    // Line sprite being fetched and the dots spent on it
+    sprite_fetch: Option<usize>,
+    sprite_dots: u8,
+    // Lock the CPU out of memory the PPU is using
+    access_blocking: bool,
+    // Output only switches, LCDC and timing are unaffected
+    hidden_layers: [bool; 3],
+    // One bit per OAM entry
+    hidden_sprites: u64,
+    dmg_palette: DmgPalette,
+    color_correction: ColorCorrection,
+    // CGB colors indexed by their RGB555 value
+    color_table: Vec<Rgb>,
+    // STAT interrupt sources are OR'ed into one line, which requests an
+    // interrupt only when it goes high.
+    stat_line: bool,
+    lyc_match: bool,
+    // The first line after the LCD is turned on has no OAM scan, and the
+    // first frame is not displayed.
+    lcd_starting: bool,
+    first_frame: bool,
 }
 
 impl GPU {
@@ -171,7 +427,7 @@ impl GPU {
             data: [0xff; DATA_SIZE],
             redraw: false,
             bgp,
-            clocks: 0,
+            dots: 0,
             lcdc,
             ly: 0x00,
             ly_compare: 0x00,
@@ -182,19 +438,120 @@ impl GPU {
             ram_bank: 0,
             scx: 0x00,
             scy: 0x00,
-            stat: Stat::default(),
+            stat: Stat {
+                mode: if skip_boot {
+                    StatMode::OAM
+                } else {
+                    StatMode::HBlank
+                },
+                ..Stat::default()
+            },
             wx: 0x00,
             wy: 0x00,
-            bg_prio: [BgPrio::Normal; SCREEN_W],
             bg_palette: Palette::new(0xff68),
             sprite_palette: Palette::new(0xff6a),
+            line_sprites: [LineSprite::default(); MAX_LINE_SPRITES],
+            sprite_count: 0,
+            opri: 0x00,
+            fetcher: Fetcher::default(),
+            bg_fifo: Fifo::default(),
+            sprite_fifo: Fifo::default(),
+            lx: 0,
+            discard: 0,
+            window_line: 0,
+            wy_triggered: false,
+            window_wraps: false,
+            window_from_left: false,
+            sprite_fetch: None,
+            sprite_dots: 0,
+            access_blocking: false,
+            hidden_layers: [false; 3],
+            hidden_sprites: 0,
+            dmg_palette: DmgPalette::default(),
+            color_correction: ColorCorrection::default(),
+            color_table: ColorCorrection::default().table(),
+            stat_line: false,
+            lyc_match: true,
+            lcd_starting: false,
+            first_frame: false,
         }
     }
 
+    pub fn set_access_blocking(&mut self, access_blocking: bool) {
+        self.access_blocking = access_blocking;
+    }
+
+    // Whether the CPU is locked out of `address` in the current mode: OAM
+    // during modes 2 and 3, VRAM and CGB palette data during mode 3. Locked
+    // reads return 0xff and writes are dropped.
+    pub fn is_locked(&self, address: u16) -> bool {
+        if !self.access_blocking {
+            return false;
+        }
+        match (address, self.stat.mode) {
+            (0x8000..=0x9fff, StatMode::VRAM) => true,
+            (0xfe00..=0xfe9f, StatMode::OAM | StatMode::VRAM) => true,
+            (0xff69 | 0xff6b, StatMode::VRAM) => self.is_gbc,
+            _ => false,
+        }
+    }
+
+    // Hide a layer from the output. The game still sees the LCDC it wrote and
+    // the renderer keeps its timing.
+    pub fn set_layer_visible(&mut self, layer: Layer, visible: bool) {
+        self.hidden_layers[layer as usize] = !visible;
+    }
+
+    pub fn layer_visible(&self, layer: Layer) -> bool {
+        !self.hidden_layers[layer as usize]
+    }
+
+    // Hide the sprite of OAM entry `index` (0-39) from the output.
+    pub fn set_sprite_visible(&mut self, index: u8, visible: bool) {
+        if visible {
+            self.hidden_sprites &= !(1 << index);
+        } else {
+            self.hidden_sprites |= 1 << index;
+        }
+    }
+
+    // Whether OAM entry `index` is shown, regardless of the sprite layer.
+    pub fn sprite_visible(&self, index: u8) -> bool {
+        self.hidden_sprites & (1 << index) == 0
+    }
+
+    // Colors for the DMG shades. Takes effect from the next pixel drawn.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+        if !self.lcdc.lcd_enabled() {
+            self.blank_screen();
+        }
+    }
+
+    // How CGB colors are displayed. Takes effect from the next pixel drawn.
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        if color_correction != self.color_correction {
+            self.color_correction = color_correction;
+            self.color_table = color_correction.table();
+        }
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
     pub fn get_rgb_data(&self) -> Vec<u8> {
         self.data.to_vec()
     }
 
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_gbc(&self) -> bool {
+        self.is_gbc
+    }
+
     fn set_color(&mut self, x: usize, a: u8, b: u8, c: u8) {
         let idx = usize::from(self.ly) * SCREEN_W * 3 + x * 3;
         self.data[idx] = a;
@@ -202,16 +559,30 @@ impl GPU {
         self.data[idx + 2] = c;
     }
 
-    fn set_mono_color(&mut self, x: usize, g: u8) {
-        self.set_color(x, g, g, g);
+    fn set_mono_color(&mut self, x: usize, shades: Shades, color: MonoColor) {
+        let (r, g, b) = shades[color as usize];
+        self.set_color(x, r, g, b);
+    }
+
+    // Fill the screen with white, or the lightest shade on DMG
+    fn blank_screen(&mut self) {
+        let (r, g, b) = if self.is_gbc {
+            (0xff, 0xff, 0xff)
+        } else {
+            self.dmg_palette.bg[0]
+        };
+        for pixel in self.data.chunks_mut(3) {
+            pixel.copy_from_slice(&[r, g, b]);
+        }
     }
 
     fn set_rgb_color(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        let (r, g, b) = (u32::from(r), u32::from(g), u32::from(b));
-        let lr = ((r * 13 + g * 2 + b) >> 1) as u8;
-        let lg = ((g * 3 + b) << 1) as u8;
-        let lb = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
-        self.set_color(x, lr, lg, lb);
+        let (r, g, b) = self.cgb_color((r, g, b));
+        self.set_color(x, r, g, b);
+    }
+
+    fn cgb_color(&self, (r, g, b): Rgb) -> Rgb {
+        self.color_table[usize::from(b) << 10 | usize::from(g) << 5 | usize::from(r)]
     }
 
     pub fn tick(&mut self, clocks: u32, int_flag: &mut InterruptFlag) {
@@ -219,233 +590,781 @@ impl GPU {
             return;
         }
         self.blanked = false;
+        // Pick up writes to STAT and LYC
+        self.update_stat(int_flag);
 
-        if clocks == 0 {
-            return;
-        }
-        let c = (clocks - 1) / 80 + 1;
-        for i in 0..c {
-            self.clocks += if i == c - 1 { clocks % 80 } else { 80 };
-            if self.clocks >= 456 {
-                self.clocks -= 456;
-                self.ly = (self.ly + 1) % 154;
-                if self.stat.ly_interrupt_enabled && self.ly == self.ly_compare {
-                    int_flag.interrupt(InterruptType::LCDC);
+        let mut clocks = clocks;
+        while clocks > 0 {
+            let dots = match self.stat.mode {
+                StatMode::OAM => {
+                    if self.dots % 2 == 0 {
+                        self.scan_oam((self.dots / 2) as u8);
+                    }
+                    1
+                }
+                StatMode::VRAM => {
+                    self.tick_pixel_transfer(int_flag);
+                    1
                 }
-                if self.ly >= 144 && self.stat.mode != StatMode::VBlank {
-                    self.set_mode(StatMode::VBlank, int_flag);
+                _ => {
+                    let end = if self.lcd_starting {
+                        OAM_SCAN_DOTS
+                    } else if self.ly == 153 && self.dots < LY_153_DOTS {
+                        LY_153_DOTS
+                    } else {
+                        LINE_DOTS
+                    };
+                    clocks.min(end - self.dots)
                 }
+            };
+            self.dots += dots;
+            clocks -= dots;
+
+            if self.ly == 153 && self.dots == LY_153_DOTS {
+                self.update_lyc();
+                self.update_stat(int_flag);
+            }
+            if self.dots == OAM_SCAN_DOTS && (self.stat.mode == StatMode::OAM || self.lcd_starting)
+            {
+                self.lcd_starting = false;
+                self.start_pixel_transfer();
+                self.set_mode(StatMode::VRAM, int_flag);
             }
+            if self.dots == LINE_DOTS {
+                self.dots = 0;
+                self.next_line(int_flag);
+            }
+        }
+    }
 
-            if self.ly < 144 {
-                if self.clocks <= 80 {
-                    if self.stat.mode != StatMode::OAM {
-                        self.set_mode(StatMode::OAM, int_flag);
-                    }
-                } else if self.clocks <= 252 {
-                    if self.stat.mode != StatMode::VRAM {
-                        self.set_mode(StatMode::VRAM, int_flag);
-                    }
-                } else if self.stat.mode != StatMode::HBlank {
-                    self.set_mode(StatMode::HBlank, int_flag);
-                }
+    fn next_line(&mut self, int_flag: &mut InterruptFlag) {
+        self.ly = (self.ly + 1) % 154;
+        self.update_lyc();
+        if self.ly >= 144 {
+            if self.stat.mode != StatMode::VBlank {
+                self.set_mode(StatMode::VBlank, int_flag);
             }
+        } else {
+            self.start_line();
+            self.set_mode(StatMode::OAM, int_flag);
         }
     }
 
+    fn start_line(&mut self) {
+        if self.ly == 0 {
+            self.window_line = 0;
+            self.wy_triggered = false;
+        }
+        self.sprite_count = 0;
+    }
+
     fn set_mode(&mut self, mode: StatMode, int_flag: &mut InterruptFlag) {
         self.stat.mode = mode;
-        let interrupts = match mode {
-            StatMode::HBlank => {
-                self.render_scan();
-                self.blanked = true;
-                self.stat.hblank_interrupt_enabled
-            }
+        match mode {
+            StatMode::HBlank => self.blanked = true,
             StatMode::VBlank => {
+                if self.first_frame {
+                    self.first_frame = false;
+                    self.blank_screen();
+                }
                 self.redraw = true;
                 int_flag.interrupt(InterruptType::VBlank);
-                self.stat.vblank_interrupt_enabled
             }
-            StatMode::OAM => self.stat.oam_interrupt_enabled,
-            _ => false,
-        };
-        if interrupts {
-            int_flag.interrupt(InterruptType::LCDC);
+            _ => {}
         }
+        self.update_stat(int_flag);
     }
 
-    fn render_scan(&mut self) {
-        for x in 0..SCREEN_W {
-            self.set_mono_color(x, 0xff);
-            self.bg_prio[x] = BgPrio::Normal;
+    // LY as read by the CPU, which wraps to 0 early on line 153
+    fn ly_register(&self) -> u8 {
+        if self.ly == 153 && self.dots >= LY_153_DOTS {
+            0
+        } else {
+            self.ly
         }
-        self.draw_bg();
-        if self.lcdc.sprite_enabled() {
-            self.draw_sprites();
+    }
+
+    fn update_lyc(&mut self) {
+        self.lyc_match = self.ly_register() == self.ly_compare;
+    }
+
+    fn update_stat(&mut self, int_flag: &mut InterruptFlag) {
+        let stat = &self.stat;
+        let mode = stat.mode;
+        // Entering VBlank also triggers the mode 2 source
+        let vblank_start = mode == StatMode::VBlank && self.ly == 144 && self.dots == 0;
+        let line = (stat.ly_interrupt_enabled && self.lyc_match)
+            || (stat.hblank_interrupt_enabled && mode == StatMode::HBlank && !self.lcd_starting)
+            || (stat.vblank_interrupt_enabled && mode == StatMode::VBlank)
+            || (stat.oam_interrupt_enabled && (mode == StatMode::OAM || vblank_start));
+        if line && !self.stat_line {
+            int_flag.interrupt(InterruptType::LCDC);
         }
+        self.stat_line = line;
     }
 
-    fn draw_bg(&mut self) {
-        let draw_bg = self.is_gbc || self.lcdc.bg_win_enabled();
-        let win_y = if !self.lcdc.window_enabled() || (self.is_gbc && !self.lcdc.bg_win_enabled()) {
-            -1
+    pub fn sprite_height(&self) -> u8 {
+        if self.lcdc.sprite_size() {
+            16
         } else {
-            self.ly as i16 - self.wy as i16
-        };
-        if win_y < 0 && !draw_bg {
+            8
+        }
+    }
+
+    // Check OAM entry `index` against the current line, two dots per entry.
+    // Only the first ten sprites on the line are selected.
+    fn scan_oam(&mut self, index: u8) {
+        if self.sprite_count == MAX_LINE_SPRITES {
             return;
         }
-        let tile_base = if self.lcdc.tileset() { 0x8000 } else { 0x8800 };
-        let bg_y = self.ly.wrapping_add(self.scy);
-        for x in 0..SCREEN_W {
-            let win_x = x as i16 - self.wx as i16 + 7;
-            let bg_x = x as u16 + u16::from(self.scx);
-            let (tilemap_base, tile_y, tile_x, pixel_y, pixel_x) = if win_y >= 0 && win_x >= 0 {
-                (
-                    if self.lcdc.window_tilemap() {
-                        0x9c00
-                    } else {
-                        0x9800
-                    },
-                    (win_y as u8 / 8) % 32,
-                    (win_x as u8 / 8) % 32,
-                    win_y as u8 % 8,
-                    win_x as u8 % 8,
-                )
-            } else {
-                (
-                    if self.lcdc.bg_tilemap() {
-                        0x9c00
-                    } else {
-                        0x9800
-                    },
-                    (bg_y as u8 / 8) % 32,
-                    (bg_x as u8 / 8) % 32,
-                    bg_y as u8 % 8,
-                    bg_x as u8 % 8,
-                )
+        let address = 0xfe00 + 4 * u16::from(index);
+        let y = self.oam.read(address);
+        let x = self.oam.read(address + 1);
+        let line = self.ly + 16;
+        if line >= y && line < y.wrapping_add(self.sprite_height()) {
+            self.line_sprites[self.sprite_count] = LineSprite {
+                index,
+                x,
+                fetched: false,
             };
+            self.sprite_count += 1;
+        }
+    }
+
+    fn start_pixel_transfer(&mut self) {
+        self.fetcher = Fetcher::default();
+        self.bg_fifo.clear();
+        self.sprite_fifo.clear();
+        self.lx = 0;
+        self.discard = self.scx & 0x07;
+        self.sprite_fetch = None;
+        if self.ly == self.wy {
+            self.wy_triggered = true;
+        }
+        self.window_from_left = self.window_wraps;
+        self.window_wraps = false;
+    }
+
+    // One dot of mode 3: run the fetchers and shift out at most one pixel.
+    // Sprite and window fetches stall the output, which stretches mode 3.
+    fn tick_pixel_transfer(&mut self, int_flag: &mut InterruptFlag) {
+        if let Some(i) = self.sprite_fetch {
+            // The background fetch in progress is finished first
+            if self.fetcher_busy() {
+                self.tick_fetcher();
+                return;
+            }
+            self.sprite_dots += 1;
+            if self.sprite_dots < SPRITE_FETCH_DOTS {
+                return;
+            }
+            self.fetch_sprite(i);
+            self.sprite_fetch = None;
+        } else {
+            self.tick_fetcher();
+        }
+
+        if self.bg_fifo.len == 0 {
+            return;
+        }
+        if self.window_starts() {
+            self.start_window();
+            self.tick_fetcher();
+            return;
+        }
+        if let Some(i) = self.next_sprite() {
+            self.sprite_fetch = Some(i);
+            self.sprite_dots = 0;
+            return;
+        }
+
+        let bg = self.bg_fifo.pop();
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let sprite = self.sprite_fifo.pop();
+        self.draw_pixel(bg, sprite);
+        self.lx += 1;
+        if usize::from(self.lx) == SCREEN_W {
+            if self.fetcher.window {
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+            self.set_mode(StatMode::HBlank, int_flag);
+        }
+    }
+
+    // The fetcher can be paused for a sprite from the last dot of DataHigh on.
+    fn fetcher_busy(&self) -> bool {
+        match self.fetcher.step {
+            FetchStep::Push => false,
+            FetchStep::DataHigh => self.fetcher.dots == 0,
+            _ => true,
+        }
+    }
+
+    fn window_starts(&self) -> bool {
+        !self.fetcher.window
+            && self.lcdc.window_enabled()
+            && self.wy_triggered
+            && (self.window_from_left || u16::from(self.lx) + 7 >= u16::from(self.wx))
+    }
 
-            let tile_address = tilemap_base + u16::from(tile_y) * 32 + u16::from(tile_x);
-            let tile_num = self.read_ram0(tile_address);
-            let tile_location = tile_base
-                + (if self.lcdc.tileset() {
-                    tile_num as u16
+    // Restart the fetcher on the window. With WX < 7 the window begins left
+    // of the screen and its first pixels are dropped. With WX=0 it starts
+    // while the SCX fine scroll is still being dropped, so it moves with
+    // SCX % 8.
+    fn start_window(&mut self) {
+        self.fetcher = Fetcher {
+            window: true,
+            first: false,
+            ..Fetcher::default()
+        };
+        self.bg_fifo.clear();
+        self.discard = match self.wx {
+            _ if self.window_from_left => 0,
+            0 => 7 - (self.scx & 0x07),
+            wx => 7u8.saturating_sub(wx),
+        };
+        if self.wx == 166 {
+            self.window_wraps = true;
+        }
+    }
+
+    // Whether overlapping sprites are ordered by X coordinate then OAM index
+    // (DMG) or by OAM index only (CGB).
+    fn x_priority(&self) -> bool {
+        !self.is_gbc || is_bit_on(self.opri, 0)
+    }
+
+    // Line sprite not fetched yet that starts at the current pixel. Several
+    // can be due at once when they start left of the screen. With X priority
+    // the leftmost goes first, in OAM order for equal X, so that the first
+    // one fetched is the one drawn on top. Otherwise OAM order decides.
+    fn next_sprite(&self) -> Option<usize> {
+        if !self.lcdc.sprite_enabled() {
+            return None;
+        }
+        let due = self.line_sprites[..self.sprite_count]
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !s.fetched && s.x <= self.lx + 8);
+        if self.x_priority() {
+            due.min_by_key(|(_, s)| (s.x, s.index)).map(|(i, _)| i)
+        } else {
+            due.map(|(i, _)| i).next()
+        }
+    }
+
+    fn tick_fetcher(&mut self) {
+        if self.fetcher.step == FetchStep::Push {
+            if self.bg_fifo.len == 0 {
+                self.push_tile();
+            }
+            return;
+        }
+        self.fetcher.dots += 1;
+        if self.fetcher.dots < 2 {
+            return;
+        }
+        self.fetcher.dots = 0;
+        match self.fetcher.step {
+            FetchStep::Tile => {
+                self.fetch_tile();
+                self.fetcher.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                self.fetcher.low = self.read_tile_data(0);
+                self.fetcher.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                self.fetcher.high = self.read_tile_data(1);
+                self.fetcher.step = if self.fetcher.first {
+                    self.fetcher.first = false;
+                    FetchStep::Tile
                 } else {
-                    (tile_num as i8 as i16 + 128) as u16
-                }) * 16;
+                    FetchStep::Push
+                };
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
 
-            let attr = Attr::from(self.read_ram1(tile_address));
+    // Row of the background or window map being fetched
+    fn fetch_y(&self) -> u8 {
+        if self.fetcher.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        }
+    }
 
-            let line = u16::from(if self.is_gbc && attr.y_flip {
-                (7 - pixel_y) * 2
-            } else {
-                pixel_y * 2
-            });
-            let a0 = tile_location + line;
-            let (b1, b2) = if self.is_gbc && attr.bank1 {
-                (self.read_ram1(a0), self.read_ram1(a0 + 1))
-            } else {
-                (self.read_ram0(a0), self.read_ram0(a0 + 1))
-            };
+    fn fetch_tile(&mut self) {
+        let (tilemap, x) = if self.fetcher.window {
+            (self.lcdc.window_tilemap(), self.fetcher.x)
+        } else {
+            (
+                self.lcdc.bg_tilemap(),
+                (self.scx >> 3).wrapping_add(self.fetcher.x),
+            )
+        };
+        let base = if tilemap { 0x9c00 } else { 0x9800 };
+        let address = base + u16::from(self.fetch_y() / 8) * 32 + u16::from(x % 32);
+        self.fetcher.tile = self.read_ram0(address);
+        self.fetcher.attr = if self.is_gbc {
+            self.read_ram1(address)
+        } else {
+            0
+        };
+    }
 
-            let x_bit = if attr.x_flip { pixel_x } else { 7 - pixel_x };
-            let c: u8 = if b1 & (1 << x_bit) != 0 { 1 } else { 0 }
-                | if b2 & (1 << x_bit) != 0 { 2 } else { 0 };
+    fn read_tile_data(&self, offset: u16) -> u8 {
+        let attr = Attr::from(self.fetcher.attr);
+        let y = self.fetch_y() % 8;
+        let row = u16::from(if attr.y_flip { 7 - y } else { y });
+        let tile = self.fetcher.tile;
+        let location = if self.lcdc.tileset() {
+            0x8000 + u16::from(tile) * 16
+        } else {
+            (0x9000 + i32::from(tile as i8) * 16) as u16
+        };
+        let address = location + row * 2 + offset;
+        if attr.bank1 {
+            self.read_ram1(address)
+        } else {
+            self.read_ram0(address)
+        }
+    }
 
-            self.bg_prio[x] = if c == 0 {
-                BgPrio::Color0
-            } else if attr.below_bg {
-                BgPrio::PrioFlag
-            } else {
-                BgPrio::Normal
+    fn push_tile(&mut self) {
+        let attr = Attr::from(self.fetcher.attr);
+        for i in 0..8 {
+            let bit = if attr.x_flip { i } else { 7 - i };
+            self.bg_fifo.pixels[usize::from(i)] = Pixel {
+                color: tile_color(self.fetcher.low, self.fetcher.high, bit),
+                attr: self.fetcher.attr,
+                index: 0,
             };
+        }
+        self.bg_fifo.len = 8;
+        self.fetcher.x = self.fetcher.x.wrapping_add(1);
+        self.fetcher.step = FetchStep::Tile;
+    }
 
+    // Mix the line sprite's pixels into the sprite FIFO. Pixels left of the
+    // screen are dropped. With X priority the sprite fetched first, which
+    // `next_sprite` makes the leftmost, keeps its pixels. Otherwise lower OAM
+    // indexes win over higher ones.
+    fn fetch_sprite(&mut self, i: usize) {
+        self.line_sprites[i].fetched = true;
+        let LineSprite { index, x, .. } = self.line_sprites[i];
+        let address = 0xfe00 + 4 * u16::from(index);
+        let y = self.oam.read(address);
+        let flags = self.oam.read(address + 3);
+        let attr = Attr::from(flags);
+        let height = self.sprite_height();
+        let tile = if height == 16 {
+            self.oam.read(address + 2) & 0xfe
+        } else {
+            self.oam.read(address + 2)
+        };
+        let line = (self.ly + 16).wrapping_sub(y) & (height - 1);
+        let row = u16::from(if attr.y_flip { height - 1 - line } else { line });
+        let tile_address = 0x8000 + u16::from(tile) * 16 + row * 2;
+        let (low, high) = if attr.bank1 && self.is_gbc {
+            (
+                self.read_ram1(tile_address),
+                self.read_ram1(tile_address + 1),
+            )
+        } else {
+            (
+                self.read_ram0(tile_address),
+                self.read_ram0(tile_address + 1),
+            )
+        };
+        if !self.layer_visible(Layer::Sprites) || !self.sprite_visible(index) {
+            return;
+        }
+        let x_priority = self.x_priority();
+        let skip = self.lx + 8 - x;
+        for i in skip..8 {
+            let bit = if attr.x_flip { i } else { 7 - i };
+            let color = tile_color(low, high, bit);
+            let slot = &mut self.sprite_fifo.pixels[usize::from(i - skip)];
+            if color != 0 && (slot.color == 0 || (!x_priority && slot.index > index)) {
+                *slot = Pixel {
+                    color,
+                    attr: flags,
+                    index,
+                };
+            }
+        }
+    }
+
+    fn draw_pixel(&mut self, bg: Pixel, sprite: Pixel) {
+        let x = usize::from(self.lx);
+        // Once the window has started the rest of the line comes from it
+        let hidden = !self.layer_visible(if self.fetcher.window {
+            Layer::Window
+        } else {
+            Layer::Background
+        });
+        // On DMG clearing LCDC bit 0 blanks the background and window, on CGB
+        // it only takes away their priority over sprites.
+        let bg_blank = hidden || (!self.is_gbc && !self.lcdc.bg_win_enabled());
+        let bg_color = if bg_blank { 0 } else { bg.color };
+        let sprite_attr = Attr::from(sprite.attr);
+        let bg_priority = self.is_gbc && !hidden && Attr::from(bg.attr).below_bg;
+        let show_sprite = sprite.color != 0
+            && self.lcdc.sprite_enabled()
+            && (bg_color == 0
+                || (self.is_gbc && !self.lcdc.bg_win_enabled())
+                || !(sprite_attr.below_bg || bg_priority));
+
+        if show_sprite {
             if self.is_gbc {
-                let (r, g, b) = self.bg_palette.get_rgb(attr.palette_num, c);
+                let (r, g, b) = self
+                    .sprite_palette
+                    .get_rgb(sprite_attr.palette_num, sprite.color);
                 self.set_rgb_color(x, r, g, b);
             } else {
-                let color = MonoColor::new(self.bgp, c) as u8;
-                self.set_mono_color(x, color);
+                let (palette, shades) = if sprite_attr.is_obp1 {
+                    (self.obp1, self.dmg_palette.obp1)
+                } else {
+                    (self.obp0, self.dmg_palette.obp0)
+                };
+                self.set_mono_color(x, shades, MonoColor::new(palette, sprite.color));
             }
+        } else if bg_blank && self.is_gbc {
+            self.set_color(x, 0xff, 0xff, 0xff);
+        } else if bg_blank {
+            self.set_mono_color(x, self.dmg_palette.bg, MonoColor::White);
+        } else if self.is_gbc {
+            let palette_num = Attr::from(bg.attr).palette_num;
+            let (r, g, b) = self.bg_palette.get_rgb(palette_num, bg_color);
+            self.set_rgb_color(x, r, g, b);
+        } else {
+            let color = MonoColor::new(self.bgp, bg_color);
+            self.set_mono_color(x, self.dmg_palette.bg, color);
         }
     }
 
-    fn draw_sprites(&mut self) {
-        for index in 0..40 {
-            let i = 39 - index;
-            let address = 0xfe00 + 0x04 * i;
-            let pos_y = i32::from(u16::from(self.read(address + 0))) - 16;
-            let pos_x = i32::from(u16::from(self.read(address + 1))) - 8;
-            let is_8x16 = self.lcdc.sprite_size();
-            let pattern_id = self.read(address + 2) & (if is_8x16 { 0xfe } else { 0xff });
-            let attr = Attr::from(self.read(address + 3));
-            let sprite_height = if is_8x16 { 16 } else { 8 };
-            let line = i32::from(self.ly);
-            if line < pos_y || line >= pos_y + sprite_height {
-                continue;
-            }
-            if pos_x < -7 || pos_x >= SCREEN_W as i32 + 7 {
-                continue;
-            }
-            let tile_y = if attr.y_flip {
-                (sprite_height - 1 - (line - pos_y))
-            } else {
-                line - pos_y
-            } as u16;
-            let tile_address = 0x8000 + u16::from(pattern_id) * 0x10 + tile_y * 2;
-            let (b1, b2) = if attr.bank1 && self.is_gbc {
-                (
-                    self.read_ram1(tile_address),
-                    self.read_ram1(tile_address + 1),
-                )
+    fn read_ram0(&self, address: u16) -> u8 {
+        self.ram[0][usize::from(address) - 0x8000]
+    }
+
+    fn read_ram1(&self, address: u16) -> u8 {
+        self.ram[1][usize::from(address) - 0x8000]
+    }
+}
+
+// Color number of pixel `bit` in a row of tile data
+fn tile_color(low: u8, high: u8, bit: u8) -> u8 {
+    ((low >> bit) & 0x01) | (((high >> bit) & 0x01) << 1)
+}
+
+// Colors for the tile sheet: the plain DMG shades, or a background or
+// sprite palette. DMG uses BGP for any background palette and OBP0 or OBP1
+// for sprite palette 0 or 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TilePalette {
+    Grey,
+    Bg(u8),
+    Sprite(u8),
+}
+
+// Sprite as stored in OAM, with its flags decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OamEntry {
+    pub index: u8,
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub below_bg: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub obp1: bool,
+    pub bank1: bool,
+    pub palette: u8,
+}
+
+impl std::fmt::Display for OamEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let flag = |on: bool, c: char| if on { c } else { '-' };
+        write!(
+            f,
+            "{:2}: x={:3} y={:3} tile={:02x} {}{}{}{}{} pal={}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            flag(self.below_bg, 'P'),
+            flag(self.y_flip, 'Y'),
+            flag(self.x_flip, 'X'),
+            flag(self.obp1, '1'),
+            flag(self.bank1, 'B'),
+            self.palette
+        )
+    }
+}
+
+// Debug views of VRAM, OAM and the palettes. They show the memory as it is,
+// without the timing of the renderer.
+impl GPU {
+    // The 384 tiles of a VRAM bank in 16 columns.
+    pub fn tile_sheet(&self, bank: usize, palette: TilePalette) -> Image {
+        let mut image = blank_image(16 * 8, 24 * 8);
+        for tile in 0..384 {
+            let address = 0x8000 + tile as u16 * 16;
+            let (x, y) = (tile % 16 * 8, tile / 16 * 8);
+            self.draw_tile(&mut image, x, y, bank, address, 8, 0, |c| match palette {
+                TilePalette::Grey => self.dmg_palette.bg[usize::from(c)],
+                TilePalette::Bg(n) => self.bg_color(n & 0x07, c),
+                TilePalette::Sprite(n) => self.sprite_color(n & 0x07 | (n & 0x01) << 4, c),
+            });
+        }
+        image
+    }
+
+    // Tile numbers and CGB attributes of a 32x32 tile map, 0x9800 or 0x9c00
+    // when `high`. The attributes are 0 on DMG.
+    pub fn tile_map_entries(&self, high: bool) -> Vec<(u8, u8)> {
+        let base = if high { 0x9c00 } else { 0x9800 };
+        (0..0x400)
+            .map(|i| {
+                let attr = if self.is_gbc {
+                    self.read_ram1(base + i)
+                } else {
+                    0
+                };
+                (self.read_ram0(base + i), attr)
+            })
+            .collect()
+    }
+
+    // A whole tile map with the tile data and attributes the background
+    // uses.
+    pub fn tile_map(&self, high: bool) -> Image {
+        let mut image = blank_image(256, 256);
+        for (i, (tile, attr)) in (0u16..).zip(self.tile_map_entries(high)) {
+            let address = if self.lcdc.tileset() {
+                0x8000 + u16::from(tile) * 16
             } else {
-                (
-                    self.read_ram0(tile_address),
-                    self.read_ram0(tile_address + 1),
-                )
+                (0x9000 + i32::from(tile as i8) * 16) as u16
             };
-            'xloop: for x in 0..8 {
-                if pos_x + x < 0 || pos_x + x >= SCREEN_W as i32 {
-                    continue;
-                }
-                let x_bit = 1 << (if attr.x_flip { x } else { 7 - x });
-                let c =
-                    if (b1 & x_bit) != 0 { 1 } else { 0 } + if (b2 & x_bit) != 0 { 2 } else { 0 };
-                if c == 0 {
-                    continue;
+            let bank = usize::from(Attr::from(attr).bank1);
+            let (x, y) = (usize::from(i % 32) * 8, usize::from(i / 32) * 8);
+            self.draw_tile(&mut image, x, y, bank, address, 8, attr, |c| {
+                self.bg_color(attr, c)
+            });
+        }
+        image
+    }
+
+    // `tile_map` with the part shown on screen outlined in red and the
+    // window, if it uses this map, in blue.
+    pub fn tile_map_view(&self, high: bool) -> Image {
+        let mut image = self.tile_map(high);
+        if self.lcdc.bg_tilemap() == high {
+            let (x, y) = (usize::from(self.scx), usize::from(self.scy));
+            outline(&mut image, x, y, SCREEN_W, SCREEN_H, (0xff, 0x00, 0x00));
+        }
+        if self.lcdc.window_enabled() && self.lcdc.window_tilemap() == high {
+            let w = (SCREEN_W + 7).saturating_sub(usize::from(self.wx));
+            let h = SCREEN_H.saturating_sub(usize::from(self.wy));
+            if w > 0 && h > 0 {
+                outline(&mut image, 0, 0, w, h, (0x00, 0x00, 0xff));
+            }
+        }
+        image
+    }
+
+    pub fn oam_entries(&self) -> Vec<OamEntry> {
+        (0..40)
+            .map(|index| {
+                let address = 0xfe00 + 4 * u16::from(index);
+                let attr = Attr::from(self.oam.read(address + 3));
+                OamEntry {
+                    index,
+                    y: self.oam.read(address),
+                    x: self.oam.read(address + 1),
+                    tile: self.oam.read(address + 2),
+                    below_bg: attr.below_bg,
+                    y_flip: attr.y_flip,
+                    x_flip: attr.x_flip,
+                    obp1: attr.is_obp1,
+                    bank1: attr.bank1,
+                    palette: attr.palette_num,
                 }
-                if self.is_gbc {
-                    if self.lcdc.lcd_enabled()
-                        && (self.bg_prio[(pos_x + x) as usize] == BgPrio::PrioFlag
-                            || (attr.below_bg
-                                && self.bg_prio[(pos_x + x) as usize] != BgPrio::Color0))
-                    {
-                        continue 'xloop;
-                    }
-                    let (r, g, b) = self.sprite_palette.get_rgb(attr.palette_num, c);
-                    self.set_rgb_color((pos_x + x) as usize, r, g, b);
+            })
+            .collect()
+    }
+
+    // The 40 sprites in 8 columns of 8x16 cells, at the current sprite size
+    // and with their palettes. Transparent pixels are dark grey.
+    pub fn oam_sheet(&self) -> Image {
+        let mut image = blank_image(8 * 8, 5 * 16);
+        image.data.iter_mut().for_each(|c| *c = 0x40);
+        let height = self.sprite_height();
+        for (i, entry) in self.oam_entries().iter().enumerate() {
+            let flags = self.oam.read(0xfe03 + 4 * i as u16);
+            let tile = if height == 16 {
+                entry.tile & 0xfe
+            } else {
+                entry.tile
+            };
+            let address = 0x8000 + u16::from(tile) * 16;
+            let bank = usize::from(entry.bank1 && self.is_gbc);
+            let (x, y) = (i % 8 * 8, i / 8 * 16);
+            self.draw_tile(&mut image, x, y, bank, address, height, flags, |c| {
+                self.sprite_color(flags, c)
+            });
+        }
+        image
+    }
+
+    // The pixels of a sprite, 8 wide and as high as the current sprite
+    // size, flipped as on screen. Transparent pixels are None.
+    pub fn sprite_pixels(&self, index: u8) -> Vec<Option<Rgb>> {
+        let entry = self.oam_entries()[usize::from(index)];
+        let flags = self.oam.read(0xfe03 + 4 * u16::from(index));
+        let height = self.sprite_height();
+        let tile = if height == 16 {
+            entry.tile & 0xfe
+        } else {
+            entry.tile
+        };
+        let bank = usize::from(entry.bank1 && self.is_gbc);
+        let mut pixels = Vec::with_capacity(8 * usize::from(height));
+        for row in 0..height {
+            let line = if entry.y_flip { height - 1 - row } else { row };
+            let a = usize::from(tile) * 16 + usize::from(line) * 2;
+            let (low, high) = (self.ram[bank][a], self.ram[bank][a + 1]);
+            for i in 0..8 {
+                let bit = if entry.x_flip { i } else { 7 - i };
+                let color = tile_color(low, high, bit);
+                pixels.push(
+                    Some(color)
+                        .filter(|&c| c != 0)
+                        .map(|c| self.sprite_color(flags, c)),
+                );
+            }
+        }
+        pixels
+    }
+
+    // Background palettes on the left, sprite palettes on the right, one
+    // row of four 8x8 swatches each. DMG shows BGP, OBP0 and OBP1.
+    pub fn palette_sheet(&self) -> Image {
+        let mut image = blank_image(9 * 8, 8 * 8);
+        for row in 0..8u8 {
+            for c in 0..4u8 {
+                let (bg, sprite) = if self.is_gbc {
+                    let bg = self.cgb_color(self.bg_palette.get_rgb(row, c));
+                    (
+                        Some(bg),
+                        Some(self.cgb_color(self.sprite_palette.get_rgb(row, c))),
+                    )
                 } else {
-                    if attr.below_bg && self.bg_prio[(pos_x + x) as usize] != BgPrio::Color0 {
-                        continue 'xloop;
-                    }
-                    let color = if attr.is_obp1 {
-                        MonoColor::new(self.obp1, c) as u8
-                    } else {
-                        MonoColor::new(self.obp0, c) as u8
-                    };
-                    self.set_mono_color((pos_x + x) as usize, color);
+                    let bg = self.bg_color(0, c);
+                    let sprite = self.sprite_color(row << 4, c);
+                    (
+                        Some(bg).filter(|_| row == 0),
+                        Some(sprite).filter(|_| row < 2),
+                    )
+                };
+                let y = usize::from(row) * 8;
+                let x = usize::from(c) * 8;
+                if let Some(color) = bg {
+                    fill(&mut image, x, y, 8, 8, color);
+                }
+                if let Some(color) = sprite {
+                    fill(&mut image, x + 40, y, 8, 8, color);
                 }
             }
         }
+        image
     }
 
-    fn read_ram0(&self, address: u16) -> u8 {
-        self.ram[0][usize::from(address) - 0x8000]
+    fn bg_color(&self, attr: u8, color: u8) -> Rgb {
+        if self.is_gbc {
+            let palette = Attr::from(attr).palette_num;
+            self.cgb_color(self.bg_palette.get_rgb(palette, color))
+        } else {
+            self.dmg_palette.bg[MonoColor::new(self.bgp, color) as usize]
+        }
     }
 
-    fn read_ram1(&self, address: u16) -> u8 {
-        self.ram[1][usize::from(address) - 0x8000]
+    fn sprite_color(&self, flags: u8, color: u8) -> Rgb {
+        let attr = Attr::from(flags);
+        if self.is_gbc {
+            self.cgb_color(self.sprite_palette.get_rgb(attr.palette_num, color))
+        } else if attr.is_obp1 {
+            self.dmg_palette.obp1[MonoColor::new(self.obp1, color) as usize]
+        } else {
+            self.dmg_palette.obp0[MonoColor::new(self.obp0, color) as usize]
+        }
+    }
+
+    // Draw `height` rows of tile data at `address` with the flips in `flags`.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_tile(
+        &self,
+        image: &mut Image,
+        x: usize,
+        y: usize,
+        bank: usize,
+        address: u16,
+        height: u8,
+        flags: u8,
+        color: impl Fn(u8) -> Rgb,
+    ) {
+        let attr = Attr::from(flags);
+        for row in 0..height {
+            let line = if attr.y_flip { height - 1 - row } else { row };
+            let a = usize::from(address - 0x8000) + usize::from(line) * 2;
+            let (low, high) = (self.ram[bank][a], self.ram[bank][a + 1]);
+            for i in 0..8 {
+                let bit = if attr.x_flip { i } else { 7 - i };
+                let (r, g, b) = color(tile_color(low, high, bit));
+                let p = ((y + usize::from(row)) * image.width + x + usize::from(i)) * 3;
+                image.data[p..p + 3].copy_from_slice(&[r, g, b]);
+            }
+        }
+    }
+}
+
+fn blank_image(width: usize, height: usize) -> Image {
+    Image {
+        width,
+        height,
+        data: vec![0; width * height * 3],
+    }
+}
+
+fn fill(image: &mut Image, x: usize, y: usize, w: usize, h: usize, (r, g, b): Rgb) {
+    for y in y..y + h {
+        for x in x..x + w {
+            let p = (y * image.width + x) * 3;
+            image.data[p..p + 3].copy_from_slice(&[r, g, b]);
+        }
+    }
+}
+
+// A rectangle border, wrapping around the edges of the image.
+fn outline(image: &mut Image, x: usize, y: usize, w: usize, h: usize, (r, g, b): Rgb) {
+    let (width, height) = (image.width, image.height);
+    let mut set = |px: usize, py: usize| {
+        let p = ((py % height) * width + px % width) * 3;
+        image.data[p..p + 3].copy_from_slice(&[r, g, b]);
+    };
+    for i in 0..w {
+        set(x + i, y);
+        set(x + i, y + h - 1);
+    }
+    for i in 0..h {
+        set(x, y + i);
+        set(x + w - 1, y + i);
     }
 }
 
@@ -476,16 +1395,12 @@ impl Memory for GPU {
                 } else {
                     0x00
                 };
-                let bit2 = if self.ly == self.ly_compare {
-                    0x04
-                } else {
-                    0x00
-                };
+                let bit2 = if self.lyc_match { 0x04 } else { 0x00 };
                 bit6 | bit5 | bit4 | bit3 | bit2 | self.stat.mode as u8
             }
             0xff42 => self.scy,
             0xff43 => self.scx,
-            0xff44 => self.ly,
+            0xff44 => self.ly_register(),
             0xff45 => self.ly_compare,
             0xff47 => self.bgp,
             0xff48 => self.obp0,
@@ -494,6 +1409,14 @@ impl Memory for GPU {
             0xff4b => self.wx,
             0xff4f => self.ram_bank as u8,
             0xff68 | 0xff69 => self.bg_palette.read(a),
+            0xff6a | 0xff6b => self.sprite_palette.read(a),
+            0xff6c => {
+                if self.is_gbc {
+                    0xfe | self.opri
+                } else {
+                    0xff
+                }
+            }
             _ => panic!("Unsupported address to read 0x{:04x}", a),
         }
     }
@@ -503,13 +1426,22 @@ impl Memory for GPU {
             0x8000..=0x9fff => self.ram[self.ram_bank][usize::from(a - 0x8000)] = v,
             0xfe00..=0xfe9f => self.oam.write(a, v),
             0xff40 => {
+                let was_enabled = self.lcdc.lcd_enabled();
                 self.lcdc = v.into();
                 if !self.lcdc.lcd_enabled() {
-                    self.clocks = 0;
+                    self.dots = 0;
                     self.ly = 0;
                     self.stat.mode = StatMode::HBlank;
-                    self.data = [0xff; DATA_SIZE];
+                    self.stat_line = false;
+                    self.lcd_starting = false;
+                    self.update_lyc();
+                    self.blank_screen();
                     self.redraw = true;
+                } else if !was_enabled {
+                    // Line 0 starts in mode 0 where the OAM scan would be
+                    self.start_line();
+                    self.lcd_starting = true;
+                    self.first_frame = true;
                 }
             }
             0xff41 => {
@@ -521,7 +1453,10 @@ impl Memory for GPU {
             0xff42 => self.scy = v,
             0xff43 => self.scx = v,
             0xff44 => {}
-            0xff45 => self.ly_compare = v,
+            0xff45 => {
+                self.ly_compare = v;
+                self.update_lyc();
+            }
             0xff47 => self.bgp = v,
             0xff48 => self.obp0 = v,
             0xff49 => self.obp1 = v,
@@ -530,11 +1465,123 @@ impl Memory for GPU {
             0xff4f => self.ram_bank = usize::from(v & 0x01),
             0xff68 | 0xff69 => self.bg_palette.write(a, v),
             0xff6a | 0xff6b => self.sprite_palette.write(a, v),
+            0xff6c => {
+                if self.is_gbc {
+                    self.opri = v & 0x01;
+                }
+            }
             _ => panic!("Unsupported address to write 0x{:04x}", a),
         }
     }
 }
 
+impl SaveState for GPU {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.blanked.save(w)?;
+        w.write_all(&self.data)?;
+        self.redraw.save(w)?;
+        self.bgp.save(w)?;
+        self.dots.save(w)?;
+        self.lcdc.inner.save(w)?;
+        self.ly.save(w)?;
+        self.ly_compare.save(w)?;
+        self.oam.save(w)?;
+        self.obp0.save(w)?;
+        self.obp1.save(w)?;
+        w.write_all(&self.ram[0])?;
+        w.write_all(&self.ram[1])?;
+        self.ram_bank.save(w)?;
+        self.scx.save(w)?;
+        self.scy.save(w)?;
+        self.stat.save(w)?;
+        self.wx.save(w)?;
+        self.wy.save(w)?;
+        self.bg_palette.save(w)?;
+        self.sprite_palette.save(w)?;
+        self.line_sprites.save(w)?;
+        self.sprite_count.save(w)?;
+        self.opri.save(w)?;
+        self.fetcher.save(w)?;
+        self.bg_fifo.save(w)?;
+        self.sprite_fifo.save(w)?;
+        self.lx.save(w)?;
+        self.discard.save(w)?;
+        self.window_line.save(w)?;
+        self.wy_triggered.save(w)?;
+        self.window_wraps.save(w)?;
+        self.window_from_left.save(w)?;
+        self.stat_line.save(w)?;
+        self.lyc_match.save(w)?;
+        self.lcd_starting.save(w)?;
+        self.first_frame.save(w)?;
+        self.sprite_fetch.is_some().save(w)?;
+        self.sprite_fetch.unwrap_or(0).save(w)?;
+        self.sprite_dots.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.blanked.load(r)?;
+        r.read_exact(&mut self.data)?;
+        self.redraw.load(r)?;
+        self.bgp.load(r)?;
+        self.dots.load(r)?;
+        self.lcdc.inner.load(r)?;
+        self.ly.load(r)?;
+        self.ly_compare.load(r)?;
+        self.oam.load(r)?;
+        self.obp0.load(r)?;
+        self.obp1.load(r)?;
+        r.read_exact(&mut self.ram[0])?;
+        r.read_exact(&mut self.ram[1])?;
+        self.ram_bank.load(r)?;
+        self.scx.load(r)?;
+        self.scy.load(r)?;
+        self.stat.load(r)?;
+        self.wx.load(r)?;
+        self.wy.load(r)?;
+        self.bg_palette.load(r)?;
+        self.sprite_palette.load(r)?;
+        self.line_sprites.load(r)?;
+        self.sprite_count.load(r)?;
+        self.opri.load(r)?;
+        self.fetcher.load(r)?;
+        self.bg_fifo.load(r)?;
+        self.sprite_fifo.load(r)?;
+        self.lx.load(r)?;
+        self.discard.load(r)?;
+        self.window_line.load(r)?;
+        self.wy_triggered.load(r)?;
+        self.window_wraps.load(r)?;
+        self.window_from_left.load(r)?;
+        self.stat_line.load(r)?;
+        self.lyc_match.load(r)?;
+        self.lcd_starting.load(r)?;
+        self.first_frame.load(r)?;
+        let mut sprite_fetch = false;
+        let mut sprite = 0usize;
+        sprite_fetch.load(r)?;
+        sprite.load(r)?;
+        self.sprite_fetch = if sprite_fetch { Some(sprite) } else { None };
+        self.sprite_dots.load(r)?;
+        let drawing = matches!(self.stat.mode, StatMode::OAM | StatMode::VRAM);
+        if self.sprite_count > self.line_sprites.len()
+            || self.sprite_fetch.is_some_and(|i| i >= self.sprite_count)
+            || self.sprite_dots >= SPRITE_FETCH_DOTS
+            || self.ram_bank > 1
+            || self.ly > 153
+            || self.dots >= LINE_DOTS
+            || (self.lcd_starting && self.dots > OAM_SCAN_DOTS)
+            || (self.stat.mode == StatMode::OAM && self.dots >= OAM_SCAN_DOTS)
+            || (drawing && usize::from(self.ly) >= SCREEN_H)
+            || usize::from(self.lx) > SCREEN_W
+            || self.discard > 7
+        {
+            return Err(invalid_data("invalid PPU state"));
+        }
+        Ok(())
+    }
+}
+
 struct Palette {
     offset: u16,
     index: u8,
@@ -605,6 +1652,25 @@ impl Memory for Palette {
     }
 }
 
+impl SaveState for Palette {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.index.save(w)?;
+        self.increment.save(w)?;
+        self.palettes.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.index.load(r)?;
+        self.increment.load(r)?;
+        self.palettes.load(r)?;
+        let components = self.palettes.iter().flatten().flatten();
+        if self.index > 0x3f || components.copied().any(|c| c > 0x1f) {
+            return Err(invalid_data("invalid palette state"));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Eq, PartialEq)]
 pub enum HdmaMode {
     Gdma,
@@ -620,6 +1686,12 @@ pub struct Hdma {
     pub dst: u16,
 }
 
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Hdma {
     pub fn new() -> Self {
         Hdma {
@@ -659,10 +1731,462 @@ impl Memory for Hdma {
                     HdmaMode::Gdma
                 };
                 self.len = v & 0x7f;
-                self.src = (u16::from(self.data[0]) << 8) | u16::from(self.data[1]);
-                self.dst = (u16::from(self.data[2]) << 8) | u16::from(self.data[3]) | 0x8000;
+                // The low four bits are ignored and the destination is in VRAM
+                self.src = ((u16::from(self.data[0]) << 8) | u16::from(self.data[1])) & 0xfff0;
+                self.dst =
+                    ((u16::from(self.data[2]) << 8) | u16::from(self.data[3])) & 0x1ff0 | 0x8000;
             }
             _ => panic!("Hdma unsupported address to write 0x{:04x}", a),
         }
     }
 }
+
+impl SaveState for Hdma {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.data.save(w)?;
+        (self.mode == HdmaMode::Hdma).save(w)?;
+        self.is_transfer.save(w)?;
+        self.len.save(w)?;
+        self.src.save(w)?;
+        self.dst.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.data.load(r)?;
+        let mut is_hdma = false;
+        is_hdma.load(r)?;
+        self.mode = if is_hdma {
+            HdmaMode::Hdma
+        } else {
+            HdmaMode::Gdma
+        };
+        self.is_transfer.load(r)?;
+        self.len.load(r)?;
+        self.src.load(r)?;
+        self.dst.load(r)?;
+        // The addresses are only set once a transfer starts
+        let bad_address = self.src & 0x0f != 0 || self.dst & 0xe00f != 0x8000;
+        if self.len > 0x7f || (self.is_transfer && bad_address) {
+            return Err(invalid_data("invalid HDMA state"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{put_sprite, put_sprite_attr};
+
+    fn gpu() -> GPU {
+        let mut gpu = GPU::new(false, true);
+        // Tile 0 is solid color 3, tile 1 solid color 1
+        for a in 0x8000..0x8010 {
+            gpu.write(a, 0xff);
+        }
+        for a in (0x8010..0x8020).step_by(2) {
+            gpu.write(a, 0xff);
+        }
+        gpu.write(0xff47, 0xe4);
+        gpu
+    }
+
+    // Dots spent in mode 3 on the first line.
+    fn mode3_length(gpu: &mut GPU) -> u32 {
+        let mut int_flag = InterruptFlag::from(0);
+        let mut dots = 0;
+        while gpu.stat.mode != StatMode::HBlank {
+            if gpu.stat.mode == StatMode::VRAM {
+                dots += 1;
+            }
+            gpu.tick(1, &mut int_flag);
+        }
+        dots
+    }
+
+    fn pixel(gpu: &GPU, x: usize) -> u8 {
+        line_pixel(gpu, 0, x)
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        assert_eq!(mode3_length(&mut gpu()), 172);
+
+        let mut g = gpu();
+        g.write(0xff43, 0x03);
+        assert_eq!(mode3_length(&mut g), 175);
+
+        let mut g = gpu();
+        g.write(0xff40, 0xb1);
+        g.write(0xff4b, 0x57);
+        assert_eq!(mode3_length(&mut g), 178);
+
+        let mut g = gpu();
+        g.write(0xff40, 0x93);
+        put_sprite(&mut g, 0, 0x58, 0x10, 1);
+        assert_eq!(mode3_length(&mut g), 183);
+
+        let mut g = gpu();
+        g.write(0xff40, 0x93);
+        put_sprite(&mut g, 0, 0x5d, 0x10, 1);
+        assert_eq!(mode3_length(&mut g), 178);
+    }
+
+    #[test]
+    fn test_mid_line_palette() {
+        let mut g = gpu();
+        let mut int_flag = InterruptFlag::from(0);
+        while g.stat.mode != StatMode::VRAM || g.lx < 80 {
+            g.tick(1, &mut int_flag);
+        }
+        g.write(0xff47, 0x00);
+        mode3_length(&mut g);
+        assert_eq!(pixel(&g, 0), MonoColor::Black as u8);
+        assert_eq!(pixel(&g, 79), MonoColor::Black as u8);
+        assert_eq!(pixel(&g, 80), MonoColor::White as u8);
+        assert_eq!(pixel(&g, 159), MonoColor::White as u8);
+    }
+
+    #[test]
+    fn test_sprites() {
+        let mut g = gpu();
+        g.write(0xff40, 0x93);
+        g.write(0xff48, 0xe4);
+        // Sprite 1 is drawn over sprite 0 because of its lower X
+        put_sprite(&mut g, 1, 0x0c, 0x10, 0);
+        put_sprite(&mut g, 0, 0x10, 0x10, 1);
+        g.write(0x9800, 0x02);
+        for a in 0x8020..0x8030 {
+            g.write(a, 0x00);
+        }
+        mode3_length(&mut g);
+        assert_eq!(pixel(&g, 3), MonoColor::White as u8);
+        assert_eq!(pixel(&g, 4), MonoColor::Black as u8);
+        assert_eq!(pixel(&g, 11), MonoColor::Black as u8);
+        assert_eq!(pixel(&g, 12), MonoColor::Light as u8);
+        assert_eq!(pixel(&g, 15), MonoColor::Light as u8);
+        assert_eq!(pixel(&g, 16), MonoColor::Black as u8);
+    }
+
+    #[test]
+    fn test_sprite_priority_left_edge() {
+        let mut g = gpu();
+        g.write(0xff40, 0x93);
+        g.write(0xff48, 0xe4);
+        g.write(0xff49, 0x00);
+        // Both start left of the screen, sprite 1 has the lower X and wins
+        put_sprite_attr(&mut g, 0, 0x06, 0x10, 0, 0x00);
+        put_sprite_attr(&mut g, 1, 0x04, 0x10, 0, 0x10);
+        g.write(0x9800, 0x02);
+        for a in 0x8020..0x8030 {
+            g.write(a, 0x00);
+        }
+        mode3_length(&mut g);
+        // OBP1 maps every color to white, OBP0 color 3 to black
+        assert_eq!(pixel(&g, 0), MonoColor::White as u8);
+        assert_eq!(pixel(&g, 3), MonoColor::White as u8);
+        assert_eq!(pixel(&g, 4), MonoColor::Black as u8);
+        assert_eq!(pixel(&g, 5), MonoColor::Black as u8);
+        assert_eq!(pixel(&g, 6), MonoColor::White as u8);
+    }
+
+    #[test]
+    fn test_dmg_palette() {
+        let mut g = gpu();
+        let mut palette = DmgPalette::preset("green").unwrap();
+        palette.obp0[3] = (0xff, 0x00, 0x00);
+        palette.obp1[3] = (0x00, 0x00, 0xff);
+        g.set_dmg_palette(palette);
+        g.write(0xff40, 0x93);
+        g.write(0xff48, 0xe4);
+        g.write(0xff49, 0xe4);
+        put_sprite(&mut g, 0, 0x08, 0x10, 0);
+        put_sprite_attr(&mut g, 1, 0x10, 0x10, 0, 0x10);
+        g.write(0x9802, 0x02);
+        mode3_length(&mut g);
+        let rgb = |x: usize| (g.data[x * 3], g.data[x * 3 + 1], g.data[x * 3 + 2]);
+        assert_eq!(rgb(0), (0xff, 0x00, 0x00));
+        assert_eq!(rgb(8), (0x00, 0x00, 0xff));
+        assert_eq!(rgb(16), palette.bg[0]);
+        assert_eq!(rgb(24), palette.bg[3]);
+
+        // The screen goes to the lightest shade when the LCD is off
+        g.write(0xff40, 0x13);
+        assert!(g.data.chunks(3).all(|p| p == [0x9b, 0xbc, 0x0f]));
+    }
+
+    #[test]
+    fn test_debug_views() {
+        let mut g = gpu();
+        g.write(0xff43, 0x10);
+        g.write(0xff48, 0x1b);
+        put_sprite_attr(&mut g, 2, 0x08, 0x10, 1, 0x30);
+        let rgb = |image: &Image, x: usize, y: usize| {
+            let p = (y * image.width + x) * 3;
+            (image.data[p], image.data[p + 1], image.data[p + 2])
+        };
+        let grey = DmgPalette::default().bg;
+
+        let tiles = g.tile_sheet(0, TilePalette::Grey);
+        assert_eq!((tiles.width, tiles.height), (128, 192));
+        assert_eq!(rgb(&tiles, 0, 0), grey[3]);
+        assert_eq!(rgb(&tiles, 8, 0), grey[1]);
+        assert_eq!(rgb(&tiles, 16, 0), grey[0]);
+
+        let map = g.tile_map_view(false);
+        assert_eq!(rgb(&map, 0x10, 0), (0xff, 0x00, 0x00));
+        assert_eq!(rgb(&map, 0x10 + 159, 143), (0xff, 0x00, 0x00));
+        assert_eq!(rgb(&map, 0x11, 1), grey[3]);
+
+        let entry = g.oam_entries()[2];
+        assert_eq!((entry.x, entry.y, entry.tile), (0x08, 0x10, 1));
+        assert!(entry.x_flip && entry.obp1 && !entry.y_flip);
+        assert_eq!(entry.to_string(), " 2: x=  8 y= 16 tile=01 --X1- pal=0");
+        // OBP1 is 0xff, so color 1 is black
+        assert_eq!(rgb(&g.oam_sheet(), 16, 0), grey[3]);
+
+        let palettes = g.palette_sheet();
+        assert_eq!(rgb(&palettes, 0, 0), grey[0]);
+        assert_eq!(rgb(&palettes, 24, 0), grey[3]);
+        assert_eq!(rgb(&palettes, 40, 0), grey[3]);
+        assert_eq!(rgb(&palettes, 64, 0), grey[0]);
+    }
+
+    #[test]
+    fn test_hidden_layers() {
+        // Black background, light window from x=80 and dark sprites at x=0
+        // and x=88
+        let render = |setup: &dyn Fn(&mut GPU)| {
+            let mut g = gpu();
+            for a in 0x9c00..0x9c20 {
+                g.write(a, 0x01);
+            }
+            g.write(0xff4b, 0x57);
+            g.write(0xff40, 0xf3);
+            g.write(0xff48, 0x08);
+            put_sprite(&mut g, 0, 0x08, 0x10, 1);
+            put_sprite(&mut g, 1, 0x60, 0x10, 1);
+            setup(&mut g);
+            let length = mode3_length(&mut g);
+            assert_eq!(g.read(0xff40), 0xf3);
+            (length, [0, 40, 80, 90].map(|x| pixel(&g, x)))
+        };
+        let (length, shown) = render(&|_| {});
+        assert_eq!(shown, [2, 3, 1, 2]);
+
+        let hidden = render(&|g| g.set_layer_visible(Layer::Sprites, false));
+        assert_eq!(hidden, (length, [3, 3, 1, 1]));
+        let hidden = render(&|g| g.set_sprite_visible(1, false));
+        assert_eq!(hidden, (length, [2, 3, 1, 1]));
+        // Slots remember their own state while the whole layer is hidden
+        let mut g = gpu();
+        g.set_layer_visible(Layer::Sprites, false);
+        g.set_sprite_visible(1, false);
+        assert!(g.sprite_visible(0) && !g.sprite_visible(1));
+        let hidden = render(&|g| g.set_layer_visible(Layer::Background, false));
+        assert_eq!(hidden, (length, [2, 0, 1, 2]));
+        let hidden = render(&|g| g.set_layer_visible(Layer::Window, false));
+        assert_eq!(hidden, (length, [2, 3, 0, 2]));
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut g = gpu();
+        g.write(0xff40, 0x93);
+        g.write(0xff48, 0xe4);
+        for i in 0..11 {
+            put_sprite(&mut g, i, 8 + 8 * i as u8, 0x10, 1);
+        }
+        mode3_length(&mut g);
+        assert_eq!(pixel(&g, 0), MonoColor::Light as u8);
+        assert_eq!(pixel(&g, 79), MonoColor::Light as u8);
+        assert_eq!(pixel(&g, 80), MonoColor::Black as u8);
+    }
+
+    #[test]
+    fn test_cgb_sprite_priority() {
+        let priority = |opri: u8| {
+            let mut g = GPU::new(true, true);
+            for a in 0x8000..0x8010 {
+                g.write(a, if a % 2 == 0 { 0xff } else { 0x00 });
+            }
+            g.write(0xff40, 0x93);
+            g.write(0xff6c, opri);
+            // Color 1 of palette 0 is white, of palette 1 red
+            g.write(0xff6a, 0x82);
+            g.write(0xff6b, 0xff);
+            g.write(0xff6b, 0x7f);
+            g.write(0xff6a, 0x8a);
+            g.write(0xff6b, 0x1f);
+            g.write(0xff6b, 0x00);
+            put_sprite_attr(&mut g, 0, 0x10, 0x10, 0, 0x01);
+            put_sprite_attr(&mut g, 1, 0x0c, 0x10, 0, 0x00);
+            mode3_length(&mut g);
+            // Green channel of the overlapping pixel
+            g.data[10 * 3 + 1]
+        };
+        assert_eq!(priority(0x00), 0x00);
+        assert_ne!(priority(0x01), 0x00);
+    }
+
+    // Tile 0 with rows of color 0, 1, 2, 3, 0, ... and the window at WX=7
+    fn window_gpu() -> GPU {
+        let mut g = GPU::new(false, true);
+        for row in 0..8 {
+            g.write(0x8000 + row * 2, if row & 1 != 0 { 0xff } else { 0x00 });
+            g.write(0x8001 + row * 2, if row & 2 != 0 { 0xff } else { 0x00 });
+        }
+        g.write(0xff47, 0xe4);
+        g.write(0xff42, 0x01);
+        g.write(0xff4a, 0x00);
+        g.write(0xff4b, 0x07);
+        g.write(0xff40, 0xb1);
+        g
+    }
+
+    fn run_line(g: &mut GPU) {
+        let mut int_flag = InterruptFlag::from(0);
+        g.tick(LINE_DOTS, &mut int_flag);
+    }
+
+    // Shade of a DMG pixel
+    fn line_pixel(g: &GPU, ly: usize, x: usize) -> u8 {
+        let i = (ly * SCREEN_W + x) * 3;
+        let rgb = (g.data[i], g.data[i + 1], g.data[i + 2]);
+        let shade = g.dmg_palette.bg.iter().position(|&c| c == rgb);
+        shade.expect("not a DMG shade") as u8
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut g = window_gpu();
+        run_line(&mut g);
+        run_line(&mut g);
+        g.write(0xff40, 0x91);
+        run_line(&mut g);
+        run_line(&mut g);
+        g.write(0xff40, 0xb1);
+        run_line(&mut g);
+        // Window rows 0 and 1, background rows 3 and 4, window row 2
+        assert_eq!(line_pixel(&g, 0, 0), MonoColor::White as u8);
+        assert_eq!(line_pixel(&g, 1, 0), MonoColor::Light as u8);
+        assert_eq!(line_pixel(&g, 2, 0), MonoColor::Black as u8);
+        assert_eq!(line_pixel(&g, 3, 0), MonoColor::White as u8);
+        assert_eq!(line_pixel(&g, 4, 0), MonoColor::Dark as u8);
+    }
+
+    #[test]
+    fn test_window_wy_latch() {
+        let mut g = window_gpu();
+        g.write(0xff4a, 0x01);
+        run_line(&mut g);
+        run_line(&mut g);
+        // Moving WY past LY does not hide the window again
+        g.write(0xff4a, 0x40);
+        run_line(&mut g);
+        assert_eq!(line_pixel(&g, 0, 0), MonoColor::Light as u8);
+        assert_eq!(line_pixel(&g, 1, 0), MonoColor::White as u8);
+        assert_eq!(line_pixel(&g, 2, 0), MonoColor::Light as u8);
+    }
+
+    #[test]
+    fn test_window_wx_166() {
+        let mut g = window_gpu();
+        g.write(0xff4b, 0xa6);
+        run_line(&mut g);
+        g.write(0xff4b, 0xc8);
+        run_line(&mut g);
+        run_line(&mut g);
+        // Background row 1 then window row 0 on the last pixel
+        assert_eq!(line_pixel(&g, 0, 158), MonoColor::Light as u8);
+        assert_eq!(line_pixel(&g, 0, 159), MonoColor::White as u8);
+        // Window row 1 over the whole next line, background after that
+        assert_eq!(line_pixel(&g, 1, 0), MonoColor::Light as u8);
+        assert_eq!(line_pixel(&g, 1, 159), MonoColor::Light as u8);
+        assert_eq!(line_pixel(&g, 2, 0), MonoColor::Black as u8);
+    }
+
+    fn lcdc_requested(int_flag: &mut InterruptFlag) -> bool {
+        let requested = int_flag.get() & 0x02 != 0;
+        *int_flag = InterruptFlag::from(0);
+        requested
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut g = gpu();
+        let mut int_flag = InterruptFlag::from(0);
+        g.write(0xff41, 0x20);
+        g.tick(1, &mut int_flag);
+        assert!(lcdc_requested(&mut int_flag));
+        g.tick(LINE_DOTS - 1, &mut int_flag);
+        assert!(lcdc_requested(&mut int_flag));
+
+        // HBlank holds the line high into the next mode 2
+        g.write(0xff41, 0x28);
+        g.tick(LINE_DOTS - 100, &mut int_flag);
+        assert!(lcdc_requested(&mut int_flag));
+        g.tick(100, &mut int_flag);
+        assert!(!lcdc_requested(&mut int_flag));
+    }
+
+    #[test]
+    fn test_ly_153() {
+        let mut g = gpu();
+        let mut int_flag = InterruptFlag::from(0);
+        g.write(0xff41, 0x40);
+        g.write(0xff45, 0x00);
+        g.tick(LINE_DOTS * 153, &mut int_flag);
+        lcdc_requested(&mut int_flag);
+        assert_eq!(g.read(0xff44), 153);
+        assert_eq!(g.read(0xff41) & 0x04, 0);
+        g.tick(LY_153_DOTS, &mut int_flag);
+        assert_eq!(g.read(0xff44), 0);
+        assert_ne!(g.read(0xff41) & 0x04, 0);
+        assert!(lcdc_requested(&mut int_flag));
+        // No second interrupt when line 0 starts
+        g.tick(LINE_DOTS, &mut int_flag);
+        assert_eq!(g.read(0xff44), 0);
+        assert!(!lcdc_requested(&mut int_flag));
+    }
+
+    #[test]
+    fn test_lcd_power() {
+        let mut g = gpu();
+        let mut int_flag = InterruptFlag::from(0);
+        g.tick(LINE_DOTS * 10 + 100, &mut int_flag);
+        g.write(0xff40, 0x11);
+        assert_eq!(g.read(0xff44), 0);
+        assert_eq!(g.read(0xff41) & 0x03, 0);
+        g.tick(LINE_DOTS, &mut int_flag);
+        assert_eq!(g.read(0xff44), 0);
+
+        // Line 0 has mode 0 instead of the OAM scan
+        g.write(0xff40, 0x91);
+        g.tick(OAM_SCAN_DOTS - 1, &mut int_flag);
+        assert_eq!(g.read(0xff41) & 0x03, 0);
+        g.tick(1, &mut int_flag);
+        assert_eq!(g.read(0xff41) & 0x03, 3);
+
+        // The first frame stays blank
+        g.tick(LINE_DOTS * 144, &mut int_flag);
+        assert!(g.redraw);
+        assert!(g.data.iter().all(|&c| c == 0xff));
+        g.tick(LINE_DOTS * 154, &mut int_flag);
+        assert_eq!(pixel(&g, 0), MonoColor::Black as u8);
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let mut g = gpu();
+        g.ram_bank = 2;
+        let mut state = Vec::new();
+        g.save(&mut state).unwrap();
+        assert!(gpu().load(&mut state.as_slice()).is_err());
+
+        g.ram_bank = 1;
+        let mut state = Vec::new();
+        g.save(&mut state).unwrap();
+        assert!(gpu().load(&mut state.as_slice()).is_ok());
+    }
+}
@@ -3,45 +3,117 @@ use glium::texture::{ClientFormat, MipmapsOption, RawImage2d, UncompressedFloatF
 use glium::{glutin, Surface};
 
 use crate::gpu::{SCREEN_H, SCREEN_W};
+use crate::scale::{self, Filter, Image};
 
 const INIT_WINDOW_SCALE: usize = 2;
 
 pub struct Window {
     events_loop: glutin::EventsLoop,
-    display: glium::Display,
-    texture: Texture2d,
+    screen: Screen,
+    filter: Filter,
 }
 
 impl Window {
-    pub fn new(title: String) -> Self {
-        let w = SCREEN_W as u32;
-        let h = SCREEN_H as u32;
+    pub fn new(title: String, filter: Filter) -> Self {
         let events_loop = glutin::EventsLoop::new();
+        let screen = Screen::new(&events_loop, title, SCREEN_W, SCREEN_H);
+        Self {
+            events_loop,
+            screen,
+            filter,
+        }
+    }
+
+    pub fn id(&self) -> glutin::WindowId {
+        self.screen.id()
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    // Upscale the frame with the filter and show it at the largest integer
+    // scale that fits the window.
+    pub fn draw(&mut self, data: Vec<u8>) {
+        self.screen
+            .show(scale::scale(self.filter, SCREEN_W, SCREEN_H, &data));
+    }
+
+    // Open another window sharing the event loop, e.g. for debug views.
+    pub fn open_view(&self, title: &str, width: usize, height: usize) -> ViewWindow {
+        ViewWindow {
+            screen: Screen::new(&self.events_loop, title.to_owned(), width, height),
+        }
+    }
+
+    pub fn poll_events<F>(&mut self, callback: F)
+    where
+        F: FnMut(glutin::Event),
+    {
+        self.events_loop.poll_events(callback);
+    }
+}
+
+pub struct ViewWindow {
+    screen: Screen,
+}
+
+impl ViewWindow {
+    pub fn id(&self) -> glutin::WindowId {
+        self.screen.id()
+    }
+
+    pub fn draw(&mut self, image: Image) {
+        self.screen.show(image);
+    }
+}
+
+struct Screen {
+    display: glium::Display,
+    texture: Texture2d,
+}
+
+impl Screen {
+    fn new(events_loop: &glutin::EventsLoop, title: String, width: usize, height: usize) -> Self {
+        let w = width as u32;
+        let h = height as u32;
         let window = glutin::WindowBuilder::new()
             .with_title(title)
             .with_dimensions((w * INIT_WINDOW_SCALE as u32, h * INIT_WINDOW_SCALE as u32).into());
         let context = glutin::ContextBuilder::new();
-        let display = glium::Display::new(window, context, &events_loop).unwrap();
-        let texture = Texture2d::empty_with_format(
-            &display,
+        let display = glium::Display::new(window, context, events_loop).unwrap();
+        let texture = Self::create_texture(&display, w, h);
+        Self { display, texture }
+    }
+
+    fn create_texture(display: &glium::Display, w: u32, h: u32) -> Texture2d {
+        Texture2d::empty_with_format(
+            display,
             UncompressedFloatFormat::U8U8U8,
             MipmapsOption::NoMipmap,
             w,
             h,
         )
-        .unwrap();
-        Self {
-            events_loop,
-            display,
-            texture,
-        }
+        .unwrap()
+    }
+
+    fn id(&self) -> glutin::WindowId {
+        self.display.gl_window().window().id()
     }
 
-    pub fn draw(&self, data: Vec<u8>) {
-        let w = SCREEN_W as u32;
-        let h = SCREEN_H as u32;
+    // Show the image at the largest integer scale that fits.
+    fn show(&mut self, image: Image) {
+        let w = image.width as u32;
+        let h = image.height as u32;
+        if self.texture.width() != w || self.texture.height() != h {
+            self.texture = Self::create_texture(&self.display, w, h);
+        }
         let rawimage2d = RawImage2d {
-            data: std::borrow::Cow::Owned(data),
+            data: std::borrow::Cow::Owned(image.data),
             width: w,
             height: h,
             format: ClientFormat::U8U8U8,
@@ -56,25 +128,20 @@ impl Window {
             rawimage2d,
         );
 
-        let target = self.display.draw();
+        let mut target = self.display.draw();
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
         let (target_w, target_h) = target.get_dimensions();
+        let (x, y, w, h) = scale::fit(target_w, target_h, w, h);
         self.texture.as_surface().blit_whole_color_to(
             &target,
             &glium::BlitTarget {
-                left: 0,
-                bottom: target_h,
-                width: target_w as i32,
-                height: -(target_h as i32),
+                left: x,
+                bottom: y + h,
+                width: w as i32,
+                height: -(h as i32),
             },
-            glium::uniforms::MagnifySamplerFilter::Linear,
+            glium::uniforms::MagnifySamplerFilter::Nearest,
         );
         target.finish().unwrap();
     }
-
-    pub fn poll_events<F>(&mut self, callback: F)
-    where
-        F: FnMut(glutin::Event),
-    {
-        self.events_loop.poll_events(callback);
-    }
 }
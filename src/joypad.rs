@@ -1,5 +1,7 @@
 use crate::memory::{InterruptFlag, InterruptType, Memory};
+use crate::savestate::SaveState;
 use crate::util::is_bit_on;
+use std::io::{self, Read, Write};
 
 pub enum JoypadKey {
     Right = 1,
@@ -35,6 +37,13 @@ impl Joypad {
     pub fn keyup(&mut self, key: JoypadKey) {
         self.matrix |= key as u8;
     }
+
+    pub fn set_buttons(&mut self, interrupt_flag: &mut InterruptFlag, buttons: u8) {
+        if self.matrix & buttons != 0 {
+            interrupt_flag.interrupt(InterruptType::P10P13);
+        }
+        self.matrix = !buttons;
+    }
 }
 
 impl Memory for Joypad {
@@ -54,3 +63,15 @@ impl Memory for Joypad {
         self.p1 = value;
     }
 }
+
+impl SaveState for Joypad {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.p1.save(w)?;
+        self.matrix.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.p1.load(r)?;
+        self.matrix.load(r)
+    }
+}
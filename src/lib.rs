@@ -1,12 +1,30 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod blend;
 pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+#[cfg(feature = "gui")]
 pub mod emu;
+pub mod gameboy;
 pub mod gpu;
+#[cfg(feature = "gui")]
 pub mod gui;
 pub mod joypad;
+pub mod link;
 pub mod memory;
+pub mod palette;
 pub mod reg;
+#[cfg(feature = "rip")]
+pub mod rip;
+pub mod savestate;
+pub mod scale;
 pub mod serial;
 pub mod sound;
+#[cfg(test)]
+pub(crate) mod test_util;
 pub mod timer;
+pub mod trace;
 pub mod util;
+pub mod wav;
@@ -0,0 +1,335 @@
+use crate::serial::SerialLink;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+// Neither side may run further ahead of the other than this many clocks.
+const SYNC_CLOCKS: u64 = 4096;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Message {
+    // The sender has emulated up to the given clock
+    Sync(u64),
+    // The sender drove the clock at the given time and shifted out the data
+    Transfer(u64, u8),
+    // Answer to a transfer with the data shifted in from the receiver
+    Reply(u8),
+}
+
+impl Message {
+    fn encode(self) -> [u8; 10] {
+        let (kind, clock, data) = match self {
+            Message::Sync(clock) => (0, clock, 0),
+            Message::Transfer(clock, data) => (1, clock, data),
+            Message::Reply(data) => (2, 0, data),
+        };
+        let mut buf = [0u8; 10];
+        buf[0] = kind;
+        buf[1..9].copy_from_slice(&clock.to_le_bytes());
+        buf[9] = data;
+        buf
+    }
+
+    fn decode(buf: [u8; 10]) -> Option<Self> {
+        let mut clock = [0u8; 8];
+        clock.copy_from_slice(&buf[1..9]);
+        let clock = u64::from_le_bytes(clock);
+        match buf[0] {
+            0 => Some(Message::Sync(clock)),
+            1 => Some(Message::Transfer(clock, buf[9])),
+            2 => Some(Message::Reply(buf[9])),
+            _ => None,
+        }
+    }
+}
+
+// Link cable to another emulator process over a TCP or Unix domain socket.
+//
+// Both ends exchange their emulated time so that they run in lockstep, and a
+// transfer is delivered to the other end at the clock it was driven.
+pub struct NetworkLink {
+    stream: Box<dyn Write + Send>,
+    rx: Receiver<Message>,
+    connected: bool,
+    clock: u64,
+    next_sync: u64,
+    peer_clock: u64,
+    waiting: Option<u8>,
+    received: Option<u8>,
+    pending: Option<(u64, u8)>,
+}
+
+impl NetworkLink {
+    pub fn new<S: Read + Write + Send + 'static>(stream: S, reader: S) -> Self {
+        let (tx, rx) = channel();
+        thread::Builder::new()
+            .name("Link thread".to_string())
+            .spawn(move || {
+                let mut reader = reader;
+                let mut buf = [0u8; 10];
+                while reader.read_exact(&mut buf).is_ok() {
+                    match Message::decode(buf) {
+                        Some(msg) if tx.send(msg).is_ok() => {}
+                        _ => break,
+                    }
+                }
+            })
+            .unwrap();
+        Self {
+            stream: Box::new(stream),
+            rx,
+            connected: true,
+            clock: 0,
+            next_sync: SYNC_CLOCKS,
+            peer_clock: 0,
+            waiting: None,
+            received: None,
+            pending: None,
+        }
+    }
+
+    // `addr` is either "host:port" or "unix:/path/to/socket".
+    // Blocks until the other end has connected.
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = addr.strip_prefix("unix:") {
+                remove_stale_socket(path)?;
+                let (stream, _) = UnixListener::bind(path)?.accept()?;
+                return Self::from_unix(stream);
+            }
+        }
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Self::from_tcp(stream)
+    }
+
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = addr.strip_prefix("unix:") {
+                return Self::from_unix(UnixStream::connect(path)?);
+            }
+        }
+        Self::from_tcp(TcpStream::connect(addr)?)
+    }
+
+    pub fn from_tcp(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        Ok(Self::new(stream, reader))
+    }
+
+    #[cfg(unix)]
+    pub fn from_unix(stream: UnixStream) -> io::Result<Self> {
+        let reader = stream.try_clone()?;
+        Ok(Self::new(stream, reader))
+    }
+
+    fn send(&mut self, msg: Message) {
+        if self.connected && self.stream.write_all(&msg.encode()).is_err() {
+            self.disconnect();
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+        self.pending = None;
+    }
+
+    // Returns a reply received while handling the message.
+    fn handle(&mut self, msg: Message) -> Option<u8> {
+        match msg {
+            Message::Sync(clock) => self.peer_clock = clock,
+            Message::Transfer(clock, data) => {
+                self.peer_clock = clock;
+                self.pending = Some((clock, data));
+            }
+            Message::Reply(data) => return Some(data),
+        }
+        None
+    }
+
+    // Answer the other end's transfer once our own time has caught up with it.
+    fn answer_pending(&mut self) {
+        match self.pending {
+            Some((clock, data)) if clock <= self.clock => {
+                self.pending = None;
+                match self.waiting.take() {
+                    Some(v) => {
+                        self.received = Some(data);
+                        self.send(Message::Reply(v));
+                    }
+                    None => self.send(Message::Reply(0xff)),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn recv(&mut self) -> Option<Message> {
+        match self.rx.recv() {
+            Ok(msg) => Some(msg),
+            Err(_) => {
+                self.disconnect();
+                None
+            }
+        }
+    }
+}
+
+// A socket left behind by an earlier listener is removed, anything else at
+// `path` is kept and reported.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+impl SerialLink for NetworkLink {
+    fn transfer(&mut self, data: u8) -> u8 {
+        if !self.connected {
+            return 0xff;
+        }
+        self.waiting = None;
+        self.send(Message::Transfer(self.clock, data));
+        while let Some(msg) = self.recv() {
+            if let Some(v) = self.handle(msg) {
+                return v;
+            }
+            // Both ends drove the clock at once
+            if self.pending.take().is_some() {
+                self.send(Message::Reply(0xff));
+            }
+        }
+        0xff
+    }
+
+    fn poll(&mut self, data: u8) -> Option<u8> {
+        match self.received.take() {
+            Some(v) => Some(v),
+            None => {
+                self.waiting = Some(data);
+                None
+            }
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.connected
+    }
+
+    fn advance(&mut self, clocks: u32) {
+        if !self.connected {
+            return;
+        }
+        self.clock += u64::from(clocks);
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => {
+                    self.handle(msg);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnect();
+                    return;
+                }
+            }
+        }
+        if self.clock >= self.next_sync {
+            self.next_sync = self.clock + SYNC_CLOCKS;
+            self.send(Message::Sync(self.clock));
+            while self.connected && self.peer_clock + SYNC_CLOCKS < self.clock {
+                self.answer_pending();
+                if let Some(msg) = self.recv() {
+                    self.handle(msg);
+                }
+            }
+        }
+        self.answer_pending();
+        // `poll` renews this every step while a transfer waits for the external clock
+        self.waiting = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::Gameboy;
+    use crate::memory::Memory;
+    use crate::test_util::serial_rom;
+
+    fn run_transfer(mut gameboy: Gameboy, link: NetworkLink) -> (u8, u8) {
+        gameboy.set_serial_link(Box::new(link));
+        let mut clocks = 0;
+        while clocks < 2 * 8 * 512 {
+            clocks += gameboy.step_instruction();
+        }
+        (gameboy.mmu.read(0xff01), gameboy.mmu.read(0xff0f) & 0x08)
+    }
+
+    #[test]
+    fn test_tcp_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let slave = thread::spawn(move || {
+            let gameboy = Gameboy::from_bytes(serial_rom(0x99, 0x80), true).unwrap();
+            run_transfer(gameboy, NetworkLink::connect(&addr).unwrap())
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let gameboy = Gameboy::from_bytes(serial_rom(0x42, 0x81), true).unwrap();
+        let master = run_transfer(gameboy, NetworkLink::from_tcp(stream).unwrap());
+        assert_eq!(master, (0x99, 0x08));
+        assert_eq!(slave.join().unwrap(), (0x42, 0x08));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut link = NetworkLink::connect(&addr).unwrap();
+        assert!(link.connected());
+        drop(listener.accept().unwrap());
+        assert_eq!(link.transfer(0x42), 0xff);
+        assert!(!link.connected());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listen_path() {
+        let path = std::env::temp_dir().join(format!("gameboy-link-{}", std::process::id()));
+        let addr = format!("unix:{}", path.display());
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert!(NetworkLink::listen(&addr).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        drop(UnixListener::bind(&path).unwrap());
+        remove_stale_socket(path.to_str().unwrap()).unwrap();
+        assert!(!path.exists());
+        remove_stale_socket(path.to_str().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_message_encoding() {
+        for msg in [
+            Message::Sync(0x1234_5678_9abc),
+            Message::Transfer(42, 0x99),
+            Message::Reply(0x42),
+        ]
+        .iter()
+        {
+            assert!(Message::decode(msg.encode()) == Some(*msg));
+        }
+    }
+}
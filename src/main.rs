@@ -1,10 +1,34 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use gameboy::blend::{BlendMode, FrameBlender};
+use gameboy::disasm::{disassemble, RomBank};
+#[cfg(feature = "gui")]
 use gameboy::emu::Emulator;
+use gameboy::gameboy::Gameboy;
+#[cfg(feature = "rip")]
+use gameboy::gpu::{SCREEN_H, SCREEN_W};
+use gameboy::link::NetworkLink;
+use gameboy::palette::{ColorCorrection, DmgPalette, COLOR_CORRECTIONS};
+#[cfg(feature = "rip")]
+use gameboy::rip;
+#[cfg(feature = "rip")]
+use gameboy::scale;
+#[cfg(any(feature = "gui", feature = "rip"))]
+use gameboy::scale::Filter;
+use gameboy::scale::FILTERS;
+use gameboy::serial::SerialLink;
+use gameboy::trace::Tracer;
+use gameboy::wav::{SampleFormat, WavRecorder, SAMPLE_FORMATS};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
 
 fn main() {
     let matches = App::new("gameboy.rust")
         .version("1.0")
         .author("Yuma Matsune <yuma.matsune@gmail.com>")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
         .arg(
             Arg::with_name("file_path")
                 .help("path to ROM file")
@@ -28,13 +52,504 @@ fn main() {
                 .long("mute")
                 .help("disable sound"),
         )
+        .arg(
+            Arg::with_name("record_audio")
+                .long("record-audio")
+                .value_name("FILE")
+                .help("record the sound output to a WAV file"),
+        )
+        .arg(
+            Arg::with_name("record_format")
+                .long("record-format")
+                .value_name("FORMAT")
+                .possible_values(&SAMPLE_FORMATS)
+                .default_value("pcm16")
+                .help("sample format of the recording"),
+        )
+        .arg(
+            Arg::with_name("record_rate")
+                .long("record-rate")
+                .value_name("HZ")
+                .default_value("44100")
+                .help("sample rate of the recording"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .value_name("N")
+                .help("run N frames headless without a window, then exit"),
+        )
+        .arg(
+            Arg::with_name("screenshot")
+                .long("screenshot")
+                .value_name("FILE")
+                .requires("frames")
+                .help("save the last frame of a headless run as PNG, scaled by --filter"),
+        )
+        .arg(
+            Arg::with_name("link_listen")
+                .long("link-listen")
+                .value_name("ADDR")
+                .conflicts_with("link_connect")
+                .help("wait for a link cable connection on host:port or unix:path"),
+        )
+        .arg(
+            Arg::with_name("link_connect")
+                .long("link-connect")
+                .value_name("ADDR")
+                .help("connect the link cable to host:port or unix:path"),
+        )
+        .arg(
+            Arg::with_name("access_blocking")
+                .long("access-blocking")
+                .help("block CPU access to VRAM, OAM and palettes while the PPU uses them"),
+        )
+        .arg(palette_arg())
+        .arg(
+            Arg::with_name("color_correction")
+                .long("color-correction")
+                .value_name("MODE")
+                .possible_values(&COLOR_CORRECTIONS)
+                .default_value("lcd")
+                .help("how CGB colors are displayed, F10 switches while running"),
+        )
+        .arg(
+            Arg::with_name("frame_blend")
+                .long("frame-blend")
+                .value_name("MODE")
+                .help("blend frames to smooth flicker: off, mix or lcd[:PERSISTENCE]"),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("FILTER")
+                .possible_values(&FILTERS)
+                .default_value("nearest")
+                .help("upscaling filter, F11 switches while running"),
+        )
+        .arg(
+            Arg::with_name("debug_views")
+                .long("debug-views")
+                .help("show VRAM tiles, tile maps, OAM and palettes in extra windows"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short("d")
+                .long("debug")
+                .help("start paused with a debugger prompt on stdin"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help("log every executed instruction to FILE"),
+        )
+        .arg(
+            Arg::with_name("trace_pc")
+                .long("trace-pc")
+                .value_name("START-END")
+                .requires("trace")
+                .help("only trace instructions at addresses START-END (hex)"),
+        )
+        .arg(
+            Arg::with_name("trace_bank")
+                .long("trace-bank")
+                .value_name("N")
+                .requires("trace")
+                .help("only trace instructions while bank N is mapped at PC"),
+        )
+        .arg(
+            Arg::with_name("trace_cycles")
+                .long("trace-cycles")
+                .requires("trace")
+                .help("append the elapsed machine cycles to trace lines"),
+        )
+        .arg(
+            Arg::with_name("trace_show_bank")
+                .long("trace-show-bank")
+                .requires("trace")
+                .help("append the bank mapped at PC to trace lines"),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("disassemble a ROM bank")
+                .arg(
+                    Arg::with_name("file_path")
+                        .help("path to ROM file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("bank")
+                        .long("bank")
+                        .value_name("N")
+                        .default_value("0")
+                        .help("ROM bank to disassemble"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rip")
+                .about("run a ROM headless and export its tiles, tile maps and sprites as PNG")
+                .arg(
+                    Arg::with_name("file_path")
+                        .help("path to ROM file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("frame")
+                        .long("frame")
+                        .value_name("N")
+                        .default_value("300")
+                        .help("number of frames to run before ripping"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .short("o")
+                        .value_name("DIR")
+                        .default_value("rip")
+                        .help("directory to write the files to"),
+                )
+                .arg(palette_arg())
+                .arg(
+                    Arg::with_name("color_correction")
+                        .long("color-correction")
+                        .value_name("MODE")
+                        .possible_values(&COLOR_CORRECTIONS)
+                        .default_value("raw")
+                        .help("how CGB colors are exported"),
+                ),
+        )
         .get_matches();
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        disasm(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("rip") {
+        rip(matches);
+        return;
+    }
+    let link = open_link(&matches);
+    let tracer = open_trace(&matches);
+    let palette = load_palette(&matches);
+    let recorder = open_recorder(&matches);
+    let frame_blend = parse_frame_blend(&matches);
+    if matches.is_present("frames") {
+        headless(&matches, link, tracer, palette, frame_blend, recorder);
+        return;
+    }
+    run(&matches, link, tracer, palette, frame_blend, recorder);
+}
+
+fn palette_arg() -> Arg<'static, 'static> {
+    Arg::with_name("palette")
+        .long("palette")
+        .value_name("PALETTE")
+        .help("DMG colors: grey, green, pocket, light, four RRGGBB colors or a palette config file")
+}
+
+// A preset or colors, otherwise the path of a config file.
+fn load_palette(matches: &ArgMatches) -> DmgPalette {
+    let value = match matches.value_of("palette") {
+        Some(value) => value,
+        None => return DmgPalette::default(),
+    };
+    let palette = match DmgPalette::parse(value) {
+        Ok(palette) => Ok(palette),
+        Err(e) if !Path::new(value).is_file() => Err(e),
+        Err(_) => std::fs::read_to_string(value)
+            .map_err(|e| e.to_string())
+            .and_then(|text| DmgPalette::from_config(&text)),
+    };
+    palette.unwrap_or_else(|e| {
+        eprintln!("Invalid palette {}: {}", value, e);
+        std::process::exit(1);
+    })
+}
+
+fn open_link(matches: &ArgMatches) -> Option<Box<dyn SerialLink>> {
+    let link = if let Some(addr) = matches.value_of("link_listen") {
+        println!("Waiting for link cable connection on {}", addr);
+        NetworkLink::listen(addr)
+    } else {
+        NetworkLink::connect(matches.value_of("link_connect")?)
+    };
+    match link {
+        Ok(link) => Some(Box::new(link)),
+        Err(e) => {
+            eprintln!("Failed to connect link cable: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn open_trace(matches: &ArgMatches) -> Option<Tracer> {
+    let path = matches.value_of("trace")?;
+    let out = match File::create(path) {
+        Ok(f) => BufWriter::new(f),
+        Err(e) => {
+            eprintln!("Failed to create trace file {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let pc_range = match matches.value_of("trace_pc").map(parse_pc_range) {
+        Some(Some(range)) => range,
+        Some(None) => {
+            eprintln!("--trace-pc expects START-END in hex, e.g. 0150-01ff");
+            std::process::exit(1);
+        }
+        None => 0x0000..=0xffff,
+    };
+    let bank = matches.value_of("trace_bank").map(|n| match n.parse() {
+        Ok(bank) => bank,
+        Err(_) => {
+            eprintln!("--trace-bank expects a bank number");
+            std::process::exit(1);
+        }
+    });
+    let tracer = Tracer::new(Box::new(out))
+        .pc_range(pc_range)
+        .bank(bank)
+        .show_cycles(matches.is_present("trace_cycles"))
+        .show_bank(matches.is_present("trace_show_bank"));
+    Some(tracer)
+}
+
+fn open_recorder(matches: &ArgMatches) -> Option<WavRecorder<BufWriter<File>>> {
+    let path = matches.value_of("record_audio")?;
+    let format = SampleFormat::parse(matches.value_of("record_format").unwrap()).unwrap();
+    let sample_rate = match matches.value_of("record_rate").unwrap().parse::<u32>() {
+        Ok(rate) if rate > 0 => rate,
+        _ => {
+            eprintln!("--record-rate expects a sample rate in Hz");
+            std::process::exit(1);
+        }
+    };
+    let recorder =
+        File::create(path).and_then(|f| WavRecorder::new(BufWriter::new(f), format, sample_rate));
+    match recorder {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            eprintln!("Failed to create audio recording {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_frame_blend(matches: &ArgMatches) -> BlendMode {
+    let value = matches.value_of("frame_blend").unwrap_or("off");
+    BlendMode::parse(value).unwrap_or_else(|e| {
+        eprintln!("--frame-blend: {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn parse_pc_range(s: &str) -> Option<RangeInclusive<u16>> {
+    let mut parts = s.splitn(2, '-');
+    let start = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let end = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some(start..=end)
+}
+
+fn disasm(matches: &ArgMatches) {
     let file_path = matches.value_of("file_path").unwrap();
-    let sav_path = matches.value_of("sav_path");
-    let mute = matches.is_present("mute");
-    let bootrom = matches.is_present("bootrom");
-    Emulator::new(file_path)
-        .sav_path(sav_path)
-        .mute(mute)
-        .run(!bootrom);
+    let rom = match std::fs::read(file_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    };
+    let banks = RomBank::banks(&rom);
+    let bank = match matches.value_of("bank").unwrap().parse::<usize>() {
+        Ok(bank) if bank < banks => bank,
+        _ => {
+            eprintln!("bank must be a number below {}", banks);
+            std::process::exit(1);
+        }
+    };
+    let (start, end) = if bank == 0 {
+        (0x0000, 0x4000)
+    } else {
+        (0x4000, 0x8000)
+    };
+    // Stop quietly when the output is closed, e.g. piped into `head`
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for instruction in disassemble(&RomBank::new(&rom, bank), start, end) {
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let line = writeln!(
+            out,
+            "{:02x}:{:04x}  {:<8}  {}",
+            bank,
+            instruction.address,
+            bytes.join(" "),
+            instruction
+        );
+        if line.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(feature = "rip")]
+fn rip(matches: &ArgMatches) {
+    let file_path = matches.value_of("file_path").unwrap();
+    let rom = match std::fs::read(file_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    };
+    let frames = match matches.value_of("frame").unwrap().parse::<u32>() {
+        Ok(frames) => frames,
+        Err(_) => {
+            eprintln!("frame must be a number");
+            std::process::exit(1);
+        }
+    };
+    let mut gameboy = match Gameboy::from_bytes(rom, true) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    };
+    gameboy.set_dmg_palette(load_palette(matches));
+    gameboy.set_color_correction(
+        ColorCorrection::parse(matches.value_of("color_correction").unwrap()).unwrap(),
+    );
+    for _ in 0..frames {
+        gameboy.run_frame();
+    }
+    let out = Path::new(matches.value_of("out").unwrap());
+    match rip::rip(&gameboy.mmu.gpu, out) {
+        Ok(paths) => println!("Wrote {} files to {}", paths.len(), out.display()),
+        Err(e) => {
+            eprintln!("Failed to rip to {}: {}", out.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "rip"))]
+fn rip(_matches: &ArgMatches) {
+    eprintln!("gameboy was built without the `rip` feature");
+    std::process::exit(1);
+}
+
+// Run without a window or sound device, as fast as possible.
+fn headless(
+    matches: &ArgMatches,
+    link: Option<Box<dyn SerialLink>>,
+    tracer: Option<Tracer>,
+    palette: DmgPalette,
+    frame_blend: BlendMode,
+    recorder: Option<WavRecorder<BufWriter<File>>>,
+) {
+    let frames = match matches.value_of("frames").unwrap().parse::<u32>() {
+        Ok(frames) => frames,
+        Err(_) => {
+            eprintln!("frames must be a number");
+            std::process::exit(1);
+        }
+    };
+    let file_path = matches.value_of("file_path").unwrap();
+    let mut gameboy = match Gameboy::new(
+        file_path,
+        matches.value_of("sav_path"),
+        !matches.is_present("bootrom"),
+    ) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(link) = link {
+        gameboy.set_serial_link(link);
+    }
+    gameboy.set_tracer(tracer);
+    gameboy.set_access_blocking(matches.is_present("access_blocking"));
+    gameboy.set_dmg_palette(palette);
+    gameboy.set_color_correction(
+        ColorCorrection::parse(matches.value_of("color_correction").unwrap()).unwrap(),
+    );
+    if let Some(recorder) = recorder {
+        gameboy.enable_sound(Box::new(recorder));
+    }
+    let mut link_connected = gameboy.serial_link_connected();
+    let mut blender = FrameBlender::new(frame_blend);
+    let mut frame = gameboy.framebuffer().to_vec();
+    for _ in 0..frames {
+        gameboy.run_frame();
+        frame = blender.blend(gameboy.framebuffer());
+        if link_connected && !gameboy.serial_link_connected() {
+            link_connected = false;
+            println!("Link cable disconnected");
+        }
+    }
+    if let Some(path) = matches.value_of("screenshot") {
+        save_screenshot(matches, &frame, path);
+    }
+}
+
+#[cfg(feature = "rip")]
+fn save_screenshot(matches: &ArgMatches, frame: &[u8], path: &str) {
+    let filter = Filter::parse(matches.value_of("filter").unwrap()).unwrap();
+    let image = scale::scale(filter, SCREEN_W, SCREEN_H, frame);
+    if let Err(e) = rip::write_png(Path::new(path), &image) {
+        eprintln!("Failed to save screenshot {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "rip"))]
+fn save_screenshot(_matches: &ArgMatches, _frame: &[u8], _path: &str) {
+    eprintln!("gameboy was built without the `rip` feature, screenshots are unavailable");
+    std::process::exit(1);
+}
+
+#[cfg(feature = "gui")]
+fn run(
+    matches: &ArgMatches,
+    link: Option<Box<dyn SerialLink>>,
+    tracer: Option<Tracer>,
+    palette: DmgPalette,
+    frame_blend: BlendMode,
+    recorder: Option<WavRecorder<BufWriter<File>>>,
+) {
+    Emulator::new(matches.value_of("file_path").unwrap())
+        .sav_path(matches.value_of("sav_path"))
+        .mute(matches.is_present("mute"))
+        .link(link)
+        .debug(matches.is_present("debug"))
+        .debug_views(matches.is_present("debug_views"))
+        .trace(tracer)
+        .access_blocking(matches.is_present("access_blocking"))
+        .palette(palette)
+        .frame_blend(frame_blend)
+        .filter(Filter::parse(matches.value_of("filter").unwrap()).unwrap())
+        .color_correction(
+            ColorCorrection::parse(matches.value_of("color_correction").unwrap()).unwrap(),
+        )
+        .record_audio(recorder)
+        .run(!matches.is_present("bootrom"));
+}
+
+#[cfg(not(feature = "gui"))]
+fn run(
+    _matches: &ArgMatches,
+    _link: Option<Box<dyn SerialLink>>,
+    _tracer: Option<Tracer>,
+    _palette: DmgPalette,
+    _frame_blend: BlendMode,
+    _recorder: Option<WavRecorder<BufWriter<File>>>,
+) {
+    eprintln!("gameboy was built without the `gui` feature, use --frames to run headless");
+    std::process::exit(1);
 }
@@ -1,10 +1,14 @@
 use crate::cartridge::Cartridge;
+use crate::debugger::Watcher;
 use crate::gpu::{Hdma, HdmaMode, GPU};
 use crate::joypad::{Joypad, JoypadKey};
-use crate::serial::Serial;
+use crate::savestate::{invalid_data, SaveState};
+use crate::serial::{Serial, SerialLink};
 use crate::sound::{AudioPlayer, Sound};
 use crate::timer::Timer;
 use crate::util::{get_lsb, get_msb};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
 
 pub trait Memory {
     fn read(&self, address: u16) -> u8;
@@ -20,6 +24,16 @@ pub trait Memory {
         self.write(address, get_lsb(value));
         self.write(address + 1, get_msb(value));
     }
+
+    // Bank number mapped at `address`, 0 for unbanked regions.
+    fn bank_at(&self, _address: u16) -> u16 {
+        0
+    }
+
+    // Read for inspection, without side effects such as watchpoints.
+    fn peek(&self, address: u16) -> u8 {
+        self.read(address)
+    }
 }
 
 pub struct RAM {
@@ -36,6 +50,16 @@ impl RAM {
     }
 }
 
+impl SaveState for RAM {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.memory.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.memory.load(r)
+    }
+}
+
 impl Memory for RAM {
     fn read(&self, address: u16) -> u8 {
         self.memory[usize::from(address - self.offset)]
@@ -74,19 +98,126 @@ impl From<u8> for InterruptFlag {
     }
 }
 
+// Bits of each IO register from 0xff00 to 0xff7f that are unused and always
+// read as 1. Unmapped addresses read as 0xff.
+#[rustfmt::skip]
+const IO_UNUSED_BITS: [u8; 0x80] = [
+    // P1   SB    SC    -     DIV   TIMA  TMA   TAC   -     -     -     -     -     -     -     IF
+    0xc0, 0x00, 0x7c, 0xff, 0x00, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xe0,
+    // NR10 NR11  NR12  NR13  NR14  -     NR21  NR22  NR23  NR24  NR30  NR31  NR32  NR33  NR34  -
+    0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00, 0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff,
+    // NR41 NR42  NR43  NR44  NR50  NR51  NR52
+    0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    // Wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // LCDC STAT  SCY   SCX   LY    LYC   DMA   BGP   OBP0  OBP1  WY    WX    -     KEY1  -     VBK
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x7e, 0xff, 0xfe,
+    // BOOT HDMA1 HDMA2 HDMA3 HDMA4 HDMA5 RP
+    0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x3c, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    //                                          BCPS  BCPD  OCPS  OCPD  OPRI
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x40, 0x00, 0x40, 0x00, 0xfe, 0xff, 0xff, 0xff,
+    // SVBK -     FF72  FF73  FF74  FF75  PCM12 PCM34
+    0xf8, 0xff, 0x00, 0x00, 0x00, 0x8f, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+// Registers that only exist on CGB. DMG reads them as 0xff and ignores writes.
+fn is_cgb_register(address: u16) -> bool {
+    matches!(
+        address,
+        0xff4d | 0xff4f | 0xff51..=0xff56 | 0xff68..=0xff6c | 0xff70 | 0xff72..=0xff77
+    )
+}
+
+// OAM DMA copies 160 bytes to OAM in the background, one per machine cycle,
+// starting one cycle after FF46 is written. A new write restarts it.
+#[derive(Default)]
+struct OamDma {
+    register: u8,
+    active: bool,
+    source: u16,
+    index: u16,
+    // Cycles until the transfer requested by the last write begins
+    start_delay: u8,
+    // Byte read by the last transfer cycle
+    byte: u8,
+}
+
+impl OamDma {
+    fn start(&mut self, value: u8) {
+        self.register = value;
+        self.start_delay = 1;
+    }
+
+    // Advance one machine cycle. Returns the source address and OAM offset
+    // of the byte to copy. A restarted transfer keeps running until the new
+    // one begins.
+    fn advance(&mut self) -> Option<(u16, u16)> {
+        let copy = if self.active {
+            let index = self.index;
+            self.index += 1;
+            self.active = self.index < 0xa0;
+            Some((self.source + index, index))
+        } else {
+            None
+        };
+        if self.start_delay > 0 {
+            self.start_delay -= 1;
+            if self.start_delay == 0 {
+                self.active = true;
+                self.source = u16::from(self.register) << 8;
+                self.index = 0;
+            }
+        }
+        copy
+    }
+}
+
+impl SaveState for OamDma {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.register.save(w)?;
+        self.active.save(w)?;
+        self.source.save(w)?;
+        self.index.save(w)?;
+        self.start_delay.save(w)?;
+        self.byte.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.register.load(r)?;
+        self.active.load(r)?;
+        self.source.load(r)?;
+        self.index.load(r)?;
+        self.start_delay.load(r)?;
+        self.byte.load(r)?;
+        if self.index > 0xa0 || self.source & 0xff != 0 || self.start_delay > 1 {
+            return Err(invalid_data("invalid OAM DMA state"));
+        }
+        Ok(())
+    }
+}
+
 pub struct MMU {
     cartridge: Cartridge,
     wram: RAM,
     wram_bank: u8,
     hram: RAM,
     hdma: Hdma,
+    oam_dma: OamDma,
     serial: Serial,
     timer: Timer,
     joypad: Joypad,
-    sound: Option<Sound>,
+    // Channels only run when output is due or their state is read
+    sound: RefCell<Sound>,
+    double_speed: bool,
+    speed_switch: bool,
+    // Infrared port
+    rp: u8,
+    // Undocumented CGB registers FF72 to FF75
+    undocumented: [u8; 4],
     pub gpu: GPU,
     pub interrupt_flag: InterruptFlag,
     pub interrupt_enable: u8,
+    pub watcher: Watcher,
 }
 
 impl MMU {
@@ -98,39 +229,154 @@ impl MMU {
             wram_bank: 0x01,
             hram: RAM::new(0xff80, 0x7f),
             hdma: Hdma::new(),
-            serial: Serial::default(),
+            oam_dma: OamDma::default(),
+            serial: Serial::new(is_gbc),
             timer: Timer::default(),
             joypad: Joypad::default(),
-            sound: None,
+            sound: RefCell::new(Sound::new()),
+            double_speed: false,
+            speed_switch: false,
+            rp: 0,
+            undocumented: [0; 4],
             gpu: GPU::new(is_gbc, skip_boot),
             interrupt_flag: InterruptFlag::from(0),
             interrupt_enable: 0,
+            watcher: Watcher::default(),
         }
     }
 
     pub fn enable_sound(&mut self, player: Box<dyn AudioPlayer>) {
-        self.sound = Some(Sound::new(player));
+        self.sound.get_mut().set_player(player);
     }
 
     pub fn title(&self) -> &str {
         self.cartridge.title()
     }
 
+    pub fn set_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        self.serial.set_link(link);
+    }
+
+    pub fn serial_link_connected(&self) -> bool {
+        self.serial.link_connected()
+    }
+
     pub fn tick(&mut self, clocks: u32) -> u32 {
-        let speed = 1;
+        let speed = if self.double_speed { 2 } else { 1 };
         let vram_clocks = self.tick_dma();
 
         let gpu_clocks = clocks / speed + vram_clocks;
         let cpu_clocks = clocks + vram_clocks * speed;
+        self.tick_oam_dma(cpu_clocks / 4);
         self.timer.tick(cpu_clocks, &mut self.interrupt_flag);
+        self.serial.advance_link(gpu_clocks);
+        self.serial.tick(cpu_clocks, &mut self.interrupt_flag);
         self.gpu.tick(gpu_clocks, &mut self.interrupt_flag);
-        if let Some(sound) = &mut self.sound {
-            sound.tick(gpu_clocks);
-        }
+        self.sound.get_mut().tick(gpu_clocks);
 
         gpu_clocks
     }
 
+    // Read without triggering watchpoints.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7fff => self.cartridge.read(address),
+            0x8000..=0x9fff => self.gpu.read(address),
+            0xa000..=0xbfff => self.cartridge.read(address),
+            0xc000..=0xcfff => self.wram.read(address),
+            0xd000..=0xdfff => self
+                .wram
+                .read(address - 0x1000 + u16::from(self.wram_bank) * 0x1000),
+            0xe000..=0xefff => self.wram.read(address - 0x2000),
+            0xf000..=0xfdff => self
+                .wram
+                .read(address - 0x3000 + u16::from(self.wram_bank) * 0x1000),
+            0xfe00..=0xfe9f => self.gpu.read(address),
+            0xff00..=0xff7f => self.read_io(address),
+            0xff80..=0xfffe => self.hram.read(address),
+            0xffff => self.interrupt_enable,
+            _ => 0,
+        }
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        if is_cgb_register(address) && !self.cartridge.is_gbc {
+            return 0xff;
+        }
+        let value = match address {
+            0xff00 => self.joypad.read(address),
+            0xff01..=0xff02 => self.serial.read(address),
+            0xff04..=0xff07 => self.timer.read(address),
+            0xff0f => self.interrupt_flag.get(),
+            // NR52 and PCM12/PCM34 report the channels as they are now
+            0xff26 | 0xff76..=0xff77 => {
+                let mut sound = self.sound.borrow_mut();
+                sound.run();
+                sound.read(address)
+            }
+            0xff10..=0xff3f => self.sound.borrow().read(address),
+            0xff4d => {
+                (if self.double_speed { 0x80 } else { 0x00 })
+                    | (if self.speed_switch { 0x01 } else { 0x00 })
+            }
+            0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f => self.gpu.read(address),
+            0xff46 => self.oam_dma.register,
+            0xff55 => self.hdma.read(address),
+            // No light is ever received
+            0xff56 => self.rp | 0x02,
+            0xff68..=0xff6c => self.gpu.read(address),
+            0xff70 => self.wram_bank,
+            0xff72..=0xff75 => self.undocumented[usize::from(address - 0xff72)],
+            _ => 0xff,
+        };
+        value | IO_UNUSED_BITS[usize::from(address - 0xff00)]
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        if is_cgb_register(address) && !self.cartridge.is_gbc {
+            return;
+        }
+        match address {
+            0xff00 => self.joypad.write(address, value),
+            0xff01..=0xff02 => self.serial.write(address, value),
+            0xff04..=0xff07 => self.timer.write(address, value),
+            0xff0f => self.interrupt_flag = InterruptFlag::from(value),
+            0xff10..=0xff3f => self.sound.get_mut().write(address, value),
+            0xff4d => self.speed_switch = value & 0x01 == 0x01,
+            0xff46 => self.oam_dma.start(value),
+            0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f => self.gpu.write(address, value),
+            0xff50 => self.cartridge.write(address, value),
+            0xff51..=0xff55 => self.hdma.write(address, value),
+            0xff56 => self.rp = value & 0xc1,
+            0xff68..=0xff6c => self.gpu.write(address, value),
+            0xff70 => {
+                self.wram_bank = match value & 0x07 {
+                    0 => 1,
+                    n => n,
+                };
+            }
+            0xff72..=0xff74 => self.undocumented[usize::from(address - 0xff72)] = value,
+            0xff75 => self.undocumented[3] = value & 0x70,
+            _ => {}
+        }
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called when the CPU executes STOP. Returns true if a speed switch
+    // was armed through KEY1 and has been performed.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch = false;
+        self.timer.write(0xff04, 0);
+        true
+    }
+
     pub fn keydown(&mut self, key: JoypadKey) {
         self.joypad.keydown(&mut self.interrupt_flag, key);
     }
@@ -139,6 +385,35 @@ impl MMU {
         self.joypad.keyup(key);
     }
 
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.joypad.set_buttons(&mut self.interrupt_flag, buttons);
+    }
+
+    fn tick_oam_dma(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if let Some((source, index)) = self.oam_dma.advance() {
+                // Sources above 0xdfff read work RAM like the echo area
+                let source = match source {
+                    a @ 0xe000..=0xffff => a - 0x2000,
+                    a => a,
+                };
+                self.oam_dma.byte = self.peek(source);
+                self.gpu.write(0xfe00 + index, self.oam_dma.byte);
+            }
+        }
+    }
+
+    // While OAM DMA runs the CPU can only use HRAM and the IO registers.
+    // OAM reads return 0xff, other reads the byte being transferred.
+    fn dma_conflict(&self, address: u16) -> Option<u8> {
+        match address {
+            _ if !self.oam_dma.active => None,
+            0xfe00..=0xfeff => Some(0xff),
+            0x0000..=0xfdff => Some(self.oam_dma.byte),
+            _ => None,
+        }
+    }
+
     fn tick_dma(&mut self) -> u32 {
         if !self.hdma.is_transfer {
             return 0;
@@ -171,8 +446,9 @@ impl MMU {
             let b = self.read(mmu_src + i);
             self.gpu.write(self.hdma.dst + i, b);
         }
-        self.hdma.src += 0x10;
-        self.hdma.dst += 0x10;
+        // Both addresses wrap, the destination within VRAM
+        self.hdma.src = self.hdma.src.wrapping_add(0x10);
+        self.hdma.dst = (self.hdma.dst + 0x10) & 0x1ff0 | 0x8000;
         if self.hdma.len == 0 {
             self.hdma.len = 0x7f;
         } else {
@@ -181,43 +457,69 @@ impl MMU {
     }
 }
 
+impl SaveState for MMU {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.cartridge.save(w)?;
+        self.wram.save(w)?;
+        self.wram_bank.save(w)?;
+        self.hram.save(w)?;
+        self.hdma.save(w)?;
+        self.oam_dma.save(w)?;
+        self.serial.save(w)?;
+        self.timer.save(w)?;
+        self.joypad.save(w)?;
+        self.sound.borrow().save(w)?;
+        self.double_speed.save(w)?;
+        self.speed_switch.save(w)?;
+        self.rp.save(w)?;
+        self.undocumented.save(w)?;
+        self.gpu.save(w)?;
+        self.interrupt_flag.inner.save(w)?;
+        self.interrupt_enable.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.cartridge.load(r)?;
+        self.wram.load(r)?;
+        self.wram_bank.load(r)?;
+        self.hram.load(r)?;
+        self.hdma.load(r)?;
+        self.oam_dma.load(r)?;
+        self.serial.load(r)?;
+        self.timer.load(r)?;
+        self.joypad.load(r)?;
+        self.sound.get_mut().load(r)?;
+        self.double_speed.load(r)?;
+        self.speed_switch.load(r)?;
+        self.rp.load(r)?;
+        self.undocumented.load(r)?;
+        self.gpu.load(r)?;
+        self.interrupt_flag.inner.load(r)?;
+        self.interrupt_enable.load(r)?;
+        if !(1..=7).contains(&self.wram_bank) {
+            return Err(invalid_data("invalid WRAM bank"));
+        }
+        Ok(())
+    }
+}
+
 impl Memory for MMU {
     fn read(&self, address: u16) -> u8 {
-        match address {
-            0x0000..=0x7fff => self.cartridge.read(address),
-            0x8000..=0x9fff => self.gpu.read(address),
-            0xa000..=0xbfff => self.cartridge.read(address),
-            0xc000..=0xcfff => self.wram.read(address),
-            0xd000..=0xdfff => self
-                .wram
-                .read(address - 0x1000 + u16::from(self.wram_bank) * 0x1000),
-            0xe000..=0xefff => self.wram.read(address - 0x2000),
-            0xf000..=0xfdff => self
-                .wram
-                .read(address - 0x3000 + u16::from(self.wram_bank) * 0x1000),
-            0xfe00..=0xfe9f => self.gpu.read(address),
-            0xff00 => self.joypad.read(address),
-            0xff01..=0xff02 => self.serial.read(address),
-            0xff04..=0xff07 => self.timer.read(address),
-            0xff0f => self.interrupt_flag.get(),
-            0xff10..=0xff3f => match &self.sound {
-                Some(sound) => sound.read(address),
-                None => 0,
-            },
-            0xff4d => 0, // TODO: speed
-            0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f => self.gpu.read(address),
-            0xff50 => self.cartridge.read(address),
-            0xff51..=0xff55 => self.hdma.read(address),
-            0xff68..=0xff6b => self.gpu.read(address),
-            0xff70 => self.wram_bank,
-            0xff80..=0xfffe => self.hram.read(address),
-            0xffff => self.interrupt_enable,
-            _ => 0,
-        }
+        let value = if let Some(value) = self.dma_conflict(address) {
+            value
+        } else if self.gpu.is_locked(address) {
+            0xff
+        } else {
+            self.peek(address)
+        };
+        self.watcher.check_read(address, value);
+        value
     }
 
     fn write(&mut self, address: u16, value: u8) {
+        self.watcher.check_write(address, value);
         match address {
+            _ if self.dma_conflict(address).is_some() || self.gpu.is_locked(address) => {}
             0x0000..=0x7fff => self.cartridge.write(address, value),
             0x8000..=0x9fff => self.gpu.write(address, value),
             0xa000..=0xbfff => self.cartridge.write(address, value),
@@ -230,35 +532,22 @@ impl Memory for MMU {
                 .wram
                 .write(address - 0x3000 + u16::from(self.wram_bank) * 0x1000, value),
             0xfe00..=0xfe9f => self.gpu.write(address, value),
-            0xff00 => self.joypad.write(address, value),
-            0xff01..=0xff02 => self.serial.write(address, value),
-            0xff04..=0xff07 => self.timer.write(address, value),
-            0xff0f => self.interrupt_flag = InterruptFlag::from(value),
-            0xff10..=0xff3f => match &mut self.sound {
-                Some(sound) => sound.write(address, value),
-                None => {}
-            },
-            0xff4d => {} // TODO: shift
-            0xff46 => {
-                let base = u16::from(value) << 8;
-                for i in 0..0xa0 {
-                    let b = self.read(base + i);
-                    self.write(0xfe00 + i, b);
-                }
-            }
-            0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f => self.gpu.write(address, value),
-            0xff50 => self.cartridge.write(address, value),
-            0xff51..=0xff55 => self.hdma.write(address, value),
-            0xff68..=0xff6b => self.gpu.write(address, value),
-            0xff70 => {
-                self.wram_bank = match value & 0x07 {
-                    0 => 1,
-                    n => n,
-                };
-            }
+            0xff00..=0xff7f => self.write_io(address, value),
             0xff80..=0xfffe => self.hram.write(address, value),
             0xffff => self.interrupt_enable = value,
             _ => {}
         };
     }
+
+    fn bank_at(&self, address: u16) -> u16 {
+        match address {
+            0x4000..=0x7fff => self.cartridge.rom_bank(),
+            0xd000..=0xdfff => u16::from(self.wram_bank),
+            _ => 0,
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        MMU::peek(self, address)
+    }
 }
@@ -0,0 +1,291 @@
+// Screen colors: shade tables for DMG games and color correction for CGB
+// games.
+//
+// Background, OBP0 and OBP1 have separate DMG shade tables so DMG games can
+// be colorized like the CGB boot ROM does. A palette is given as a preset
+// name, as four colors from lightest to darkest (`9bbc0f,8bac0f,306230,0f380f`)
+// used for every layer, or as a config file:
+//
+//   # Start from a preset, then override single layers
+//   palette = pocket
+//   bg = ffffff 7bff31 0063c5 000000
+//   obp0 = ffffff ff8484 943a3a 000000
+//   obp1 = ffffff ff8484 943a3a 000000
+
+pub type Rgb = (u8, u8, u8);
+pub type Shades = [Rgb; 4];
+
+pub const PRESETS: [&str; 4] = ["grey", "green", "pocket", "light"];
+
+const GREY: Shades = [
+    (0xff, 0xff, 0xff),
+    (0xc0, 0xc0, 0xc0),
+    (0x60, 0x60, 0x60),
+    (0x00, 0x00, 0x00),
+];
+const GREEN: Shades = [
+    (0x9b, 0xbc, 0x0f),
+    (0x8b, 0xac, 0x0f),
+    (0x30, 0x62, 0x30),
+    (0x0f, 0x38, 0x0f),
+];
+const POCKET: Shades = [
+    (0xc4, 0xcf, 0xa1),
+    (0x8b, 0x95, 0x6d),
+    (0x4d, 0x53, 0x3c),
+    (0x1f, 0x1f, 0x1f),
+];
+const LIGHT: Shades = [
+    (0x00, 0xb5, 0x81),
+    (0x00, 0x9a, 0x71),
+    (0x00, 0x69, 0x4a),
+    (0x00, 0x4f, 0x3b),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmgPalette {
+    pub bg: Shades,
+    pub obp0: Shades,
+    pub obp1: Shades,
+}
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        Self::uniform(GREY)
+    }
+}
+
+impl DmgPalette {
+    // The same shades for background and sprites.
+    pub fn uniform(shades: Shades) -> Self {
+        Self {
+            bg: shades,
+            obp0: shades,
+            obp1: shades,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        let shades = match name.to_ascii_lowercase().as_str() {
+            "grey" | "gray" => GREY,
+            "green" | "dmg" => GREEN,
+            "pocket" => POCKET,
+            "light" => LIGHT,
+            _ => return None,
+        };
+        Some(Self::uniform(shades))
+    }
+
+    // A preset name or four colors for all layers.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match Self::preset(s.trim()) {
+            Some(palette) => Ok(palette),
+            None => parse_shades(s).map(Self::uniform),
+        }
+    }
+
+    // Read `key = value` lines: `palette` picks the starting preset or colors,
+    // `bg`, `obp0` and `obp1` replace single layers.
+    pub fn from_config(text: &str) -> Result<Self, String> {
+        let mut palette = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = parts
+                .next()
+                .ok_or_else(|| format!("line {}: expected key = value", n + 1))?;
+            let result = match key {
+                "palette" => Self::parse(value).map(|p| palette = p),
+                "bg" => parse_shades(value).map(|s| palette.bg = s),
+                "obp0" => parse_shades(value).map(|s| palette.obp0 = s),
+                "obp1" => parse_shades(value).map(|s| palette.obp1 = s),
+                _ => Err(format!("unknown key {}", key)),
+            };
+            result.map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+        Ok(palette)
+    }
+}
+
+// Four RRGGBB colors separated by commas or spaces, lightest first.
+fn parse_shades(s: &str) -> Result<Shades, String> {
+    let colors = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|c| !c.is_empty())
+        .map(parse_color)
+        .collect::<Result<Vec<_>, _>>()?;
+    match colors[..] {
+        [a, b, c, d] => Ok([a, b, c, d]),
+        _ => Err(format!(
+            "expected a preset ({}) or four colors, got {:?}",
+            PRESETS.join(", "),
+            s.trim()
+        )),
+    }
+}
+
+fn parse_color(s: &str) -> Result<Rgb, String> {
+    let hex = s.trim_start_matches('#');
+    let value = match u32::from_str_radix(hex, 16) {
+        Ok(value) if hex.len() == 6 => value,
+        _ => return Err(format!("{} is not an RRGGBB color", s)),
+    };
+    Ok(((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
+
+// How CGB colors (five bits per channel) are turned into RGB888. Raw scales
+// the channels, the others mimic how the colors looked on a screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorCorrection {
+    Raw,
+    // The CGB LCD blending its channels
+    #[default]
+    Lcd,
+    // The LCD blending done in linear light, for sRGB monitors
+    Modern,
+    // The darker GBA screen
+    Gba,
+}
+
+pub const COLOR_CORRECTIONS: [&str; 4] = ["raw", "lcd", "modern", "gba"];
+
+impl ColorCorrection {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "raw" => Ok(ColorCorrection::Raw),
+            "lcd" => Ok(ColorCorrection::Lcd),
+            "modern" => Ok(ColorCorrection::Modern),
+            "gba" => Ok(ColorCorrection::Gba),
+            _ => Err(format!(
+                "unknown color correction {}, expected one of {}",
+                s,
+                COLOR_CORRECTIONS.join(", ")
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        COLOR_CORRECTIONS[self as usize]
+    }
+
+    // The mode after this one, for cycling with a hotkey.
+    pub fn next(self) -> Self {
+        match self {
+            ColorCorrection::Raw => ColorCorrection::Lcd,
+            ColorCorrection::Lcd => ColorCorrection::Modern,
+            ColorCorrection::Modern => ColorCorrection::Gba,
+            ColorCorrection::Gba => ColorCorrection::Raw,
+        }
+    }
+
+    // Colors for every RGB555 value, indexed by `b << 10 | g << 5 | r`.
+    pub fn table(self) -> Vec<Rgb> {
+        (0..0x8000u16)
+            .map(|c| {
+                let r = (c & 0x1f) as u8;
+                let g = ((c >> 5) & 0x1f) as u8;
+                let b = (c >> 10) as u8;
+                self.correct(r, g, b)
+            })
+            .collect()
+    }
+
+    fn correct(self, r: u8, g: u8, b: u8) -> Rgb {
+        match self {
+            ColorCorrection::Raw => (r << 3 | r >> 2, g << 3 | g >> 2, b << 3 | b >> 2),
+            ColorCorrection::Lcd => {
+                let (r, g, b) = (u32::from(r), u32::from(g), u32::from(b));
+                (
+                    ((r * 13 + g * 2 + b) >> 1) as u8,
+                    ((g * 3 + b) << 1) as u8,
+                    ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+                )
+            }
+            ColorCorrection::Modern => {
+                let (r, g, b) = (linear(r, 2.2), linear(g, 2.2), linear(b, 2.2));
+                (
+                    encode((r * 13.0 + g * 2.0 + b) / 16.0, 1.0),
+                    encode((g * 3.0 + b) / 4.0, 1.0),
+                    encode((r * 3.0 + g * 2.0 + b * 11.0) / 16.0, 1.0),
+                )
+            }
+            ColorCorrection::Gba => {
+                let (r, g, b) = (linear(r, 4.0), linear(g, 4.0), linear(b, 4.0));
+                let scale = 255.0 / 280.0;
+                (
+                    encode((g * 50.0 + r * 255.0) / 255.0, scale),
+                    encode((b * 30.0 + g * 230.0 + r * 10.0) / 255.0, scale),
+                    encode((b * 220.0 + g * 10.0 + r * 50.0) / 255.0, scale),
+                )
+            }
+        }
+    }
+}
+
+// Light emitted for a five bit channel on a screen with the given gamma
+fn linear(c: u8, gamma: f32) -> f32 {
+    (f32::from(c) / 31.0).powf(gamma)
+}
+
+// Gamma encode linear light for a 2.2 gamma display
+fn encode(light: f32, scale: f32) -> u8 {
+    (light.min(1.0).powf(1.0 / 2.2) * scale * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(DmgPalette::parse("Green"), Ok(DmgPalette::uniform(GREEN)));
+        let palette = DmgPalette::parse("#ffffff,aa5500 555555 000000").unwrap();
+        assert_eq!(palette.obp1[1], (0xaa, 0x55, 0x00));
+        assert!(DmgPalette::parse("ffffff,aaaaaa,555555").is_err());
+        assert!(DmgPalette::parse("ffffff,aaaaaa,555555,00000g").is_err());
+        assert!(DmgPalette::parse("sepia").is_err());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = "\
+            # colorized\n\
+            palette = pocket\n\
+            obp0 = ffffff ff8484 943a3a 000000 # red sprites\n";
+        let palette = DmgPalette::from_config(config).unwrap();
+        assert_eq!(palette.bg, POCKET);
+        assert_eq!(palette.obp0[1], (0xff, 0x84, 0x84));
+        assert_eq!(palette.obp1, POCKET);
+
+        let err = DmgPalette::from_config("bg = ffffff\n").unwrap_err();
+        assert!(err.starts_with("line 1: "));
+        assert!(DmgPalette::from_config("window = green").is_err());
+        assert!(DmgPalette::from_config("green").is_err());
+    }
+
+    #[test]
+    fn test_color_correction() {
+        let white = 0x7fff;
+        let table = ColorCorrection::Raw.table();
+        assert_eq!(table[white], (0xff, 0xff, 0xff));
+        assert_eq!(table[0x001f], (0xff, 0x00, 0x00));
+        assert_eq!(table[0x0210], (0x84, 0x84, 0x00));
+        assert_eq!(ColorCorrection::Lcd.table()[white], (0xf8, 0xf8, 0xf8));
+        assert_eq!(ColorCorrection::Modern.table()[white], (0xff, 0xff, 0xff));
+        assert_eq!(ColorCorrection::Modern.table()[0], (0x00, 0x00, 0x00));
+        let (r, g, b) = ColorCorrection::Gba.table()[0x001f];
+        assert!(r > g && r > b);
+
+        let mut mode = ColorCorrection::default();
+        for _ in 0..4 {
+            assert_eq!(ColorCorrection::parse(mode.name()), Ok(mode));
+            mode = mode.next();
+        }
+        assert_eq!(mode, ColorCorrection::default());
+        assert!(ColorCorrection::parse("srgb").is_err());
+    }
+}
@@ -1,4 +1,6 @@
+use crate::savestate::SaveState;
 use crate::util::{get_lsb, get_msb, is_bit_on, set_bit};
+use std::io::{self, Read, Write};
 
 #[derive(Default)]
 pub struct Registers {
@@ -118,3 +120,31 @@ impl Registers {
         set_bit(&mut self.f, f as u8, b);
     }
 }
+
+impl SaveState for Registers {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.a.save(w)?;
+        self.f.save(w)?;
+        self.b.save(w)?;
+        self.c.save(w)?;
+        self.d.save(w)?;
+        self.e.save(w)?;
+        self.h.save(w)?;
+        self.l.save(w)?;
+        self.sp.save(w)?;
+        self.pc.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.a.load(r)?;
+        self.f.load(r)?;
+        self.b.load(r)?;
+        self.c.load(r)?;
+        self.d.load(r)?;
+        self.e.load(r)?;
+        self.h.load(r)?;
+        self.l.load(r)?;
+        self.sp.load(r)?;
+        self.pc.load(r)
+    }
+}
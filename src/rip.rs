@@ -0,0 +1,281 @@
+// Ripping graphics out of a running game as PNG files: the tile sets with
+// the palettes in use, both 256x256 tile maps and the sprites on screen,
+// with sprites that touch assembled into whole objects.
+//
+// `rip` writes everything to a directory:
+//   tiles-bgp.png, tiles-obp0.png, tiles-obp1.png   DMG tile set per palette
+//   tiles-bank0-bg0.png ... tiles-bank1-obj7.png    CGB tile sets per palette
+//   map-9800.png, map-9c00.png                      tile maps
+//   map-9800.txt, map-9c00.txt                      tile numbers and attributes
+//   sprite-NN.png                                   sprites, NN is the first
+//                                                   OAM index in the group
+//   oam.txt                                         the 40 OAM entries
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::gpu::{TilePalette, GPU, SCREEN_H, SCREEN_W};
+use crate::scale::Image;
+
+// Sprites that touch or overlap, e.g. a character made of several tiles,
+// drawn as RGBA with transparent pixels at alpha 0. `x` and `y` are the top
+// left corner on screen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpriteGroup {
+    pub x: i32,
+    pub y: i32,
+    pub width: usize,
+    pub height: usize,
+    pub indexes: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+// Tile sets of every VRAM bank and palette, with their file names.
+pub fn tile_sheets(gpu: &GPU) -> Vec<(String, Image)> {
+    if !gpu.is_gbc() {
+        return vec![
+            (
+                "tiles-bgp.png".to_owned(),
+                gpu.tile_sheet(0, TilePalette::Bg(0)),
+            ),
+            (
+                "tiles-obp0.png".to_owned(),
+                gpu.tile_sheet(0, TilePalette::Sprite(0)),
+            ),
+            (
+                "tiles-obp1.png".to_owned(),
+                gpu.tile_sheet(0, TilePalette::Sprite(1)),
+            ),
+        ];
+    }
+    let mut sheets = Vec::new();
+    for bank in 0..2 {
+        for n in 0..8 {
+            let name = format!("tiles-bank{}-bg{}.png", bank, n);
+            sheets.push((name, gpu.tile_sheet(bank, TilePalette::Bg(n))));
+        }
+        for n in 0..8 {
+            let name = format!("tiles-bank{}-obj{}.png", bank, n);
+            sheets.push((name, gpu.tile_sheet(bank, TilePalette::Sprite(n))));
+        }
+    }
+    sheets
+}
+
+// A tile map as text: 32 rows of tile numbers, then on CGB 32 rows of
+// attributes.
+pub fn tile_map_dump(gpu: &GPU, high: bool) -> String {
+    let entries = gpu.tile_map_entries(high);
+    let mut text = format!("# {:04x} tiles\n", if high { 0x9c00 } else { 0x9800 });
+    let rows = |text: &mut String, value: &dyn Fn(&(u8, u8)) -> u8| {
+        for row in entries.chunks(32) {
+            let row: Vec<String> = row.iter().map(|e| format!("{:02x}", value(e))).collect();
+            text.push_str(&row.join(" "));
+            text.push('\n');
+        }
+    };
+    rows(&mut text, &|&(tile, _)| tile);
+    if gpu.is_gbc() {
+        text.push_str("# attributes\n");
+        rows(&mut text, &|&(_, attr)| attr);
+    }
+    text
+}
+
+// The sprites at least partly on screen, grouped with the sprites they
+// touch. Groups are ordered by their first OAM index.
+pub fn sprite_groups(gpu: &GPU) -> Vec<SpriteGroup> {
+    let entries = gpu.oam_entries();
+    let height = i32::from(gpu.sprite_height());
+    let rect = |i: u8| {
+        let entry = &entries[usize::from(i)];
+        (i32::from(entry.x) - 8, i32::from(entry.y) - 16)
+    };
+    let touch = |a: u8, b: u8| {
+        let ((ax, ay), (bx, by)) = (rect(a), rect(b));
+        ax <= bx + 8 && bx <= ax + 8 && ay <= by + height && by <= ay + height
+    };
+
+    let mut groups: Vec<Vec<u8>> = Vec::new();
+    for entry in &entries {
+        let (x, y) = rect(entry.index);
+        if x <= -8 || x >= SCREEN_W as i32 || y <= -height || y >= SCREEN_H as i32 {
+            continue;
+        }
+        let mut group = vec![entry.index];
+        let mut rest = Vec::new();
+        for g in groups {
+            if g.iter().any(|&i| touch(i, entry.index)) {
+                group.extend(g);
+            } else {
+                rest.push(g);
+            }
+        }
+        rest.push(group);
+        groups = rest;
+    }
+    groups.iter_mut().for_each(|g| g.sort_unstable());
+    groups.sort_unstable();
+
+    groups
+        .into_iter()
+        .map(|mut indexes| {
+            let x = indexes.iter().map(|&i| rect(i).0).min().unwrap();
+            let y = indexes.iter().map(|&i| rect(i).1).min().unwrap();
+            let width = (indexes.iter().map(|&i| rect(i).0).max().unwrap() - x + 8) as usize;
+            let height = (indexes.iter().map(|&i| rect(i).1).max().unwrap() - y + height) as usize;
+            let mut data = vec![0; width * height * 4];
+            // Draw the highest priority sprite last: on DMG the one furthest
+            // left, then the lowest OAM index
+            let mut order = indexes.clone();
+            if !gpu.is_gbc() {
+                order.sort_by_key(|&i| (entries[usize::from(i)].x, i));
+            }
+            for &i in order.iter().rev() {
+                let (sx, sy) = rect(i);
+                let (ox, oy) = ((sx - x) as usize, (sy - y) as usize);
+                for (p, pixel) in gpu.sprite_pixels(i).into_iter().enumerate() {
+                    if let Some((r, g, b)) = pixel {
+                        let q = ((oy + p / 8) * width + ox + p % 8) * 4;
+                        data[q..q + 4].copy_from_slice(&[r, g, b, 0xff]);
+                    }
+                }
+            }
+            indexes.sort_unstable();
+            SpriteGroup {
+                x,
+                y,
+                width,
+                height,
+                indexes,
+                data,
+            }
+        })
+        .collect()
+}
+
+pub fn write_png(path: &Path, image: &Image) -> io::Result<()> {
+    write_png_data(
+        path,
+        image.width,
+        image.height,
+        png::ColorType::Rgb,
+        &image.data,
+    )
+}
+
+pub fn write_sprite_png(path: &Path, group: &SpriteGroup) -> io::Result<()> {
+    write_png_data(
+        path,
+        group.width,
+        group.height,
+        png::ColorType::Rgba,
+        &group.data,
+    )
+}
+
+fn write_png_data(
+    path: &Path,
+    width: usize,
+    height: usize,
+    color: png::ColorType,
+    data: &[u8],
+) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    Ok(())
+}
+
+// Write all the files listed at the top to `dir`, creating it if needed.
+// Returns the paths written.
+pub fn rip(gpu: &GPU, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut paths = Vec::new();
+    for (name, image) in tile_sheets(gpu) {
+        let path = dir.join(name);
+        write_png(&path, &image)?;
+        paths.push(path);
+    }
+    for &(high, name) in &[(false, "map-9800"), (true, "map-9c00")] {
+        let path = dir.join(format!("{}.png", name));
+        write_png(&path, &gpu.tile_map(high))?;
+        paths.push(path);
+        let path = dir.join(format!("{}.txt", name));
+        fs::write(&path, tile_map_dump(gpu, high))?;
+        paths.push(path);
+    }
+    for group in sprite_groups(gpu) {
+        let path = dir.join(format!("sprite-{:02}.png", group.indexes[0]));
+        write_sprite_png(&path, &group)?;
+        paths.push(path);
+    }
+    let path = dir.join("oam.txt");
+    let mut out = BufWriter::new(File::create(&path)?);
+    for entry in gpu.oam_entries() {
+        writeln!(out, "{}", entry)?;
+    }
+    out.flush()?;
+    paths.push(path);
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::test_util::put_sprite;
+
+    #[test]
+    fn test_sprite_groups() {
+        let mut gpu = GPU::new(false, true);
+        // Tile 1 solid color 1, tile 2 transparent
+        for a in (0x8010..0x8020).step_by(2) {
+            gpu.write(a, 0xff);
+        }
+        gpu.write(0xff48, 0xe4);
+        // A 16x16 object from four tiles, the right half transparent
+        put_sprite(&mut gpu, 3, 16, 32, 1);
+        put_sprite(&mut gpu, 4, 24, 32, 2);
+        put_sprite(&mut gpu, 5, 16, 40, 1);
+        put_sprite(&mut gpu, 6, 24, 40, 2);
+        // A sprite on its own and one off screen
+        put_sprite(&mut gpu, 1, 100, 100, 1);
+        put_sprite(&mut gpu, 2, 0, 50, 1);
+        // The rest of OAM is at 0,0, hidden as well
+
+        let groups = sprite_groups(&gpu);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].indexes, [1]);
+        assert_eq!((groups[0].x, groups[0].y), (92, 84));
+        let group = &groups[1];
+        assert_eq!(group.indexes, [3, 4, 5, 6]);
+        assert_eq!(
+            (group.x, group.y, group.width, group.height),
+            (8, 16, 16, 16)
+        );
+        let light = crate::palette::DmgPalette::default().obp0[1];
+        let pixel = |x: usize, y: usize| {
+            let p = (y * group.width + x) * 4;
+            group.data[p..p + 4].to_vec()
+        };
+        assert_eq!(pixel(7, 15), [light.0, light.1, light.2, 0xff]);
+        assert_eq!(pixel(8, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_tile_map_dump() {
+        let mut gpu = GPU::new(false, true);
+        gpu.write(0x9c21, 0xab);
+        let dump = tile_map_dump(&gpu, true);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 33);
+        assert_eq!(lines[0], "# 9c00 tiles");
+        assert!(lines[2].starts_with("00 ab 00"));
+        assert_eq!(tile_sheets(&gpu).len(), 3);
+    }
+}
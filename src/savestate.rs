@@ -0,0 +1,197 @@
+use std::io::{self, Read, Write};
+
+// Bump whenever the layout of any component's state changes.
+pub const STATE_VERSION: u32 = 10;
+const STATE_MAGIC: &[u8; 4] = b"GBSS";
+
+pub trait SaveState {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()>;
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()>;
+}
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn write_header(w: &mut dyn Write, title: &str) -> io::Result<()> {
+    w.write_all(STATE_MAGIC)?;
+    STATE_VERSION.save(w)?;
+    save_blob(w, title.as_bytes())
+}
+
+pub fn read_header(r: &mut dyn Read, title: &str) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != STATE_MAGIC {
+        return Err(invalid_data("not a save state"));
+    }
+    let mut version = 0u32;
+    version.load(r)?;
+    if version != STATE_VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported save state version {}",
+            version
+        )));
+    }
+    if load_blob(r, title.len())? != title.as_bytes() {
+        return Err(invalid_data("save state belongs to another ROM"));
+    }
+    Ok(())
+}
+
+// Variable length data, prefixed with its length.
+pub fn save_blob(w: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+    (data.len() as u32).save(w)?;
+    w.write_all(data)
+}
+
+// Fails without allocating when the stored length is over `max_len`.
+pub fn load_blob(r: &mut dyn Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut len = 0u32;
+    len.load(r)?;
+    if len as usize > max_len {
+        return Err(invalid_data("data length out of range"));
+    }
+    let mut data = vec![0u8; len as usize];
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+impl SaveState for u8 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&[*self])
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut buf = [0u8; 1];
+        r.read_exact(&mut buf)?;
+        *self = buf[0];
+        Ok(())
+    }
+}
+
+impl SaveState for u16 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut buf = [0u8; 2];
+        r.read_exact(&mut buf)?;
+        *self = u16::from_le_bytes(buf);
+        Ok(())
+    }
+}
+
+impl SaveState for u32 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf)?;
+        *self = u32::from_le_bytes(buf);
+        Ok(())
+    }
+}
+
+impl SaveState for u64 {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)?;
+        *self = u64::from_le_bytes(buf);
+        Ok(())
+    }
+}
+
+impl SaveState for usize {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        (*self as u64).save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut v = 0u64;
+        v.load(r)?;
+        *self = v as usize;
+        Ok(())
+    }
+}
+
+impl SaveState for bool {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        u8::from(*self).save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut v = 0u8;
+        v.load(r)?;
+        *self = v != 0;
+        Ok(())
+    }
+}
+
+impl<T: SaveState, const N: usize> SaveState for [T; N] {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for v in self.iter() {
+            v.save(w)?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for v in self.iter_mut() {
+            v.load(r)?;
+        }
+        Ok(())
+    }
+}
+
+// Memory whose size is fixed by the cartridge header, so the length must match.
+impl SaveState for Vec<u8> {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        save_blob(w, self)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let data = load_blob(r, self.len())?;
+        if data.len() != self.len() {
+            return Err(invalid_data("memory size mismatch"));
+        }
+        *self = data;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let mut buf = Vec::new();
+        write_header(&mut buf, "TETRIS").unwrap();
+        assert!(read_header(&mut buf.as_slice(), "TETRIS").is_ok());
+        assert!(read_header(&mut buf.as_slice(), "ZELDA").is_err());
+        buf[4] = 0xff;
+        assert!(read_header(&mut buf.as_slice(), "TETRIS").is_err());
+    }
+
+    #[test]
+    fn test_vec_size_mismatch() {
+        let mut buf = Vec::new();
+        vec![1u8, 2, 3].save(&mut buf).unwrap();
+        let mut v = vec![0u8; 3];
+        v.load(&mut buf.as_slice()).unwrap();
+        assert_eq!(v, vec![1, 2, 3]);
+        let mut v = vec![0u8; 4];
+        assert!(v.load(&mut buf.as_slice()).is_err());
+        let mut v = vec![0u8; 2];
+        assert!(v.load(&mut buf.as_slice()).is_err());
+        assert_eq!(v, vec![0, 0]);
+    }
+}
@@ -0,0 +1,430 @@
+// Pixel art upscaling on the CPU. A filter turns an RGB888 frame into one a
+// fixed number of times larger, which is then shown at the largest integer
+// multiple that fits, keeping the aspect ratio.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Xbr2x,
+}
+
+pub const FILTERS: [&str; 5] = ["nearest", "scale2x", "scale3x", "hq2x", "xbr"];
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "scale2x" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "hq2x" => Ok(Filter::Hq2x),
+            "xbr" => Ok(Filter::Xbr2x),
+            _ => Err(format!(
+                "unknown filter {}, expected one of {}",
+                s,
+                FILTERS.join(", ")
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        FILTERS[self as usize]
+    }
+
+    // The filter after this one, for cycling with a hotkey.
+    pub fn next(self) -> Self {
+        match self {
+            Filter::Nearest => Filter::Scale2x,
+            Filter::Scale2x => Filter::Scale3x,
+            Filter::Scale3x => Filter::Hq2x,
+            Filter::Hq2x => Filter::Xbr2x,
+            Filter::Xbr2x => Filter::Nearest,
+        }
+    }
+
+    pub fn factor(self) -> usize {
+        match self {
+            Filter::Nearest => 1,
+            Filter::Scale3x => 3,
+            Filter::Scale2x | Filter::Hq2x | Filter::Xbr2x => 2,
+        }
+    }
+}
+
+// An RGB888 image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    // This image with `other` to its right, top aligned on black.
+    pub fn beside(&self, other: &Image) -> Image {
+        let width = self.width + other.width;
+        let height = self.height.max(other.height);
+        let mut data = vec![0; width * height * 3];
+        for (image, x) in [(self, 0), (other, self.width)] {
+            for (y, row) in image.data.chunks(image.width * 3).enumerate() {
+                let start = (y * width + x) * 3;
+                data[start..start + row.len()].copy_from_slice(row);
+            }
+        }
+        Image {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+pub fn scale(filter: Filter, width: usize, height: usize, data: &[u8]) -> Image {
+    let src = Source::new(width, height, data);
+    let factor = filter.factor();
+    let mut out = vec![0; width * factor * height * factor];
+    for y in 0..height {
+        for x in 0..width {
+            let block = |dx: usize, dy: usize| (y * factor + dy) * width * factor + x * factor + dx;
+            match filter {
+                Filter::Nearest => out[block(0, 0)] = src.get(x, y, 0, 0),
+                Filter::Scale3x => {
+                    let pixels = scale3x(&src, x, y);
+                    for (i, &p) in pixels.iter().enumerate() {
+                        out[block(i % 3, i / 3)] = p;
+                    }
+                }
+                _ => {
+                    for &(sx, sy) in &[(-1, -1), (1, -1), (-1, 1), (1, 1)] {
+                        let p = |u: isize, v: isize| src.get(x, y, u * sx, v * sy);
+                        let pixel = match filter {
+                            Filter::Scale2x => scale2x_corner(p),
+                            Filter::Hq2x => hq2x_corner(p),
+                            _ => xbr_corner(p),
+                        };
+                        out[block((sx + 1) as usize / 2, (sy + 1) as usize / 2)] = pixel;
+                    }
+                }
+            }
+        }
+    }
+    Image {
+        width: width * factor,
+        height: height * factor,
+        data: out
+            .iter()
+            .flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8])
+            .collect(),
+    }
+}
+
+// Where to draw an image inside a `target_w` x `target_h` area: the largest
+// integer scale that fits, or 1 if none does, centered. Returns (x, y, w, h).
+pub fn fit(target_w: u32, target_h: u32, width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let scale = (target_w / width).min(target_h / height).max(1);
+    let (w, h) = (width * scale, height * scale);
+    (
+        target_w.saturating_sub(w) / 2,
+        target_h.saturating_sub(h) / 2,
+        w,
+        h,
+    )
+}
+
+// Pixels as 0xRRGGBB, edges repeated outwards.
+struct Source {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+
+impl Source {
+    fn new(width: usize, height: usize, data: &[u8]) -> Self {
+        let pixels = data
+            .chunks(3)
+            .map(|p| u32::from(p[0]) << 16 | u32::from(p[1]) << 8 | u32::from(p[2]))
+            .collect();
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+        let clamp = |v: usize, d: isize, max: usize| (v as isize + d).clamp(0, max as isize - 1);
+        let x = clamp(x, dx, self.width) as usize;
+        let y = clamp(y, dy, self.height) as usize;
+        self.pixels[y * self.width + x]
+    }
+}
+
+// The corner filters below are written for the bottom right quarter of the
+// center pixel, with `p(u, v)` the neighbor u pixels right and v pixels down.
+// The other corners use the same code with the neighborhood mirrored.
+
+// AdvMAME2x
+fn scale2x_corner(p: impl Fn(isize, isize) -> u32) -> u32 {
+    let (e, f, h) = (p(0, 0), p(1, 0), p(0, 1));
+    let (d, b) = (p(-1, 0), p(0, -1));
+    if h == f && d != h && b != f {
+        f
+    } else {
+        e
+    }
+}
+
+// AdvMAME3x, returning the nine pixels row by row.
+fn scale3x(src: &Source, x: usize, y: usize) -> [u32; 9] {
+    let p = |dx, dy| src.get(x, y, dx, dy);
+    let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+    let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+    let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+    if b == h || d == f {
+        return [e; 9];
+    }
+    let pick = |cond: bool, p: u32| if cond { p } else { e };
+    [
+        pick(d == b, d),
+        pick((d == b && e != c) || (b == f && e != a), b),
+        pick(b == f, f),
+        pick((d == b && e != g) || (d == h && e != a), d),
+        e,
+        pick((b == f && e != i) || (h == f && e != c), f),
+        pick(d == h, d),
+        pick((d == h && e != i) || (h == f && e != g), h),
+        pick(h == f, f),
+    ]
+}
+
+// HQ2x. The neighbors that differ from the center pixel form an 8 bit
+// pattern, and each of the 256 patterns picks how the corner is interpolated.
+// The rules come from the original lookup table, grouped by result. They are
+// written for the top left corner with neighbors numbered row by row:
+//   w0 w1 w2
+//   w3 w4 w5
+//   w6 w7 w8
+// so the neighborhood is turned around for the bottom right corner here.
+fn hq2x_corner(p: impl Fn(isize, isize) -> u32) -> u32 {
+    let w: Vec<u32> = (0..9).map(|i| p(1 - i % 3, 1 - i / 3)).collect();
+    let pattern = [0, 1, 2, 3, 5, 6, 7, 8]
+        .iter()
+        .enumerate()
+        .filter(|&(_, &n)| !similar(w[4], w[n]))
+        .fold(0u8, |k, (bit, _)| k | 1 << bit);
+    let any = |cases: &[(u8, u8)]| cases.iter().any(|&(m, r)| pattern & m == r);
+    let differ = |a: usize, b: usize| !similar(w[a], w[b]);
+    if any(&[(0xbf, 0x37), (0xdb, 0x13)]) && differ(1, 5) {
+        mix(&[(w[4], 3), (w[3], 1)])
+    } else if any(&[(0xdb, 0x49), (0xef, 0x6d)]) && differ(7, 3) {
+        mix(&[(w[4], 3), (w[1], 1)])
+    } else if any(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && differ(3, 1) {
+        w[4]
+    } else if any(&[
+        (0x6f, 0x2a),
+        (0x5b, 0x0a),
+        (0xbf, 0x3a),
+        (0xdf, 0x5a),
+        (0x9f, 0x8a),
+        (0xcf, 0x8a),
+        (0xef, 0x4e),
+        (0x3f, 0x0e),
+        (0xfb, 0x5a),
+        (0xbb, 0x8a),
+        (0x7f, 0x5a),
+        (0xaf, 0x8a),
+        (0xeb, 0x8a),
+    ]) && differ(3, 1)
+    {
+        mix(&[(w[4], 3), (w[0], 1)])
+    } else if any(&[(0x0b, 0x08)]) {
+        mix(&[(w[4], 2), (w[0], 1), (w[1], 1)])
+    } else if any(&[(0x0b, 0x02)]) {
+        mix(&[(w[4], 2), (w[0], 1), (w[3], 1)])
+    } else if any(&[(0x2f, 0x2f)]) {
+        mix(&[(w[4], 14), (w[3], 1), (w[1], 1)])
+    } else if any(&[(0xbf, 0x37), (0xdb, 0x13)]) {
+        mix(&[(w[4], 5), (w[1], 2), (w[3], 1)])
+    } else if any(&[(0xdb, 0x49), (0xef, 0x6d)]) {
+        mix(&[(w[4], 5), (w[3], 2), (w[1], 1)])
+    } else if any(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+        mix(&[(w[4], 3), (w[3], 1)])
+    } else if any(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+        mix(&[(w[4], 3), (w[1], 1)])
+    } else if any(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+        mix(&[(w[4], 2), (w[3], 3), (w[1], 3)])
+    } else if any(&[
+        (0xfb, 0x6a),
+        (0x6f, 0x6e),
+        (0x3f, 0x3e),
+        (0xfb, 0xfa),
+        (0xdf, 0xde),
+        (0xdf, 0x1e),
+    ]) {
+        mix(&[(w[4], 3), (w[0], 1)])
+    } else if any(&[
+        (0x0a, 0x00),
+        (0x4f, 0x4b),
+        (0x9f, 0x1b),
+        (0x2f, 0x0b),
+        (0xbe, 0x0a),
+        (0xee, 0x0a),
+        (0x7e, 0x0a),
+        (0xeb, 0x4b),
+        (0x3b, 0x1b),
+    ]) {
+        mix(&[(w[4], 2), (w[3], 1), (w[1], 1)])
+    } else {
+        mix(&[(w[4], 6), (w[3], 1), (w[1], 1)])
+    }
+}
+
+// xBR level 1 (2xBR): blend the corner along an edge when the pixel
+// differences across the diagonal are smaller than along it.
+fn xbr_corner(p: impl Fn(isize, isize) -> u32) -> u32 {
+    let (e, f, h, i) = (p(0, 0), p(1, 0), p(0, 1), p(1, 1));
+    let (b, d, c, g) = (p(0, -1), p(-1, 0), p(1, -1), p(-1, 1));
+    let (f4, h5, i4, i5) = (p(2, 0), p(0, 2), p(2, 1), p(1, 2));
+    let along = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5);
+    let across = distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b);
+    if along + 4 * distance(h, f) < across + 4 * distance(e, i) {
+        let edge = if distance(e, f) <= distance(e, h) {
+            f
+        } else {
+            h
+        };
+        mix(&[(e, 1), (edge, 1)])
+    } else {
+        e
+    }
+}
+
+fn yuv(p: u32) -> (i32, i32, i32) {
+    let (r, g, b) = (
+        (p >> 16 & 0xff) as i32,
+        (p >> 8 & 0xff) as i32,
+        (p & 0xff) as i32,
+    );
+    (
+        (r * 299 + g * 587 + b * 114) / 1000,
+        (-r * 169 - g * 331 + b * 500) / 1000 + 128,
+        (r * 500 - g * 419 - b * 81) / 1000 + 128,
+    )
+}
+
+// Colors HQx treats as equal, compared in its own cheap YUV.
+fn similar(a: u32, b: u32) -> bool {
+    let hqx_yuv = |p: u32| {
+        let (r, g, b) = (
+            (p >> 16 & 0xff) as i32,
+            (p >> 8 & 0xff) as i32,
+            (p & 0xff) as i32,
+        );
+        (
+            (r + g + b) >> 2,
+            128 + ((r - b) >> 2),
+            128 + ((2 * g - r - b) >> 3),
+        )
+    };
+    let (ya, ua, va) = hqx_yuv(a);
+    let (yb, ub, vb) = hqx_yuv(b);
+    (ya - yb).abs() <= 0x30 && (ua - ub).abs() <= 7 && (va - vb).abs() <= 6
+}
+
+fn distance(a: u32, b: u32) -> u32 {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    48 * (ya - yb).unsigned_abs() + 7 * (ua - ub).unsigned_abs() + 6 * (va - vb).unsigned_abs()
+}
+
+// Weighted average of colors.
+fn mix(colors: &[(u32, u32)]) -> u32 {
+    let total: u32 = colors.iter().map(|&(_, w)| w).sum();
+    let channel = |shift: u32| {
+        let sum: u32 = colors.iter().map(|&(c, w)| (c >> shift & 0xff) * w).sum();
+        (sum + total / 2) / total
+    };
+    channel(16) << 16 | channel(8) << 8 | channel(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: u8 = 0xff;
+
+    // 3x3 black image with a white diagonal from the top left
+    fn diagonal() -> Vec<u8> {
+        (0..9)
+            .flat_map(|i| if i % 4 == 0 { [W; 3] } else { [0; 3] })
+            .collect()
+    }
+
+    fn at(image: &Image, x: usize, y: usize) -> u8 {
+        image.data[(y * image.width + x) * 3]
+    }
+
+    #[test]
+    fn test_filters() {
+        let data = diagonal();
+        for &name in &FILTERS {
+            let filter = Filter::parse(name).unwrap();
+            let image = scale(filter, 3, 3, &data);
+            assert_eq!(image.width, 3 * filter.factor(), "{}", name);
+            assert_eq!(image.data.len(), image.width * image.height * 3);
+            // Corners far from any edge keep their color
+            assert_eq!(at(&image, 0, 0), W, "{}", name);
+            assert_eq!(at(&image, image.width - 1, 0), 0, "{}", name);
+        }
+
+        // The diagonal gets filled in next to the center pixel
+        let image = scale(Filter::Scale2x, 3, 3, &data);
+        assert_eq!(at(&image, 2, 1), W);
+        assert_eq!(at(&image, 1, 2), W);
+        assert_eq!(at(&image, 3, 1), 0);
+        let image = scale(Filter::Scale3x, 3, 3, &data);
+        assert_eq!(at(&image, 3, 2), W);
+        assert_eq!(at(&image, 4, 2), 0);
+        let image = scale(Filter::Xbr2x, 3, 3, &data);
+        assert_eq!(at(&image, 2, 1), 0x80);
+        let image = scale(Filter::Hq2x, 3, 3, &data);
+        assert_eq!(at(&image, 2, 1), 0xbf);
+        // A lone pixel keeps 14/16 of its color in every corner
+        let dot: Vec<u8> = (0..9)
+            .flat_map(|i| if i == 4 { [W; 3] } else { [0; 3] })
+            .collect();
+        let image = scale(Filter::Hq2x, 3, 3, &dot);
+        for &(x, y) in &[(2, 2), (3, 2), (2, 3), (3, 3)] {
+            assert_eq!(at(&image, x, y), 0xdf);
+        }
+        assert_eq!(at(&image, 1, 2), 0);
+
+        let image = scale(Filter::Nearest, 3, 3, &data);
+        let wide = image.beside(&scale(Filter::Scale2x, 3, 3, &data));
+        assert_eq!((wide.width, wide.height), (9, 6));
+        assert_eq!(
+            (at(&wide, 2, 2), at(&wide, 2, 3), at(&wide, 3, 1)),
+            (W, 0, W)
+        );
+    }
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit(320, 288, 160, 144), (0, 0, 320, 288));
+        assert_eq!(fit(800, 600, 160, 144), (80, 12, 640, 576));
+        assert_eq!(fit(100, 100, 160, 144), (0, 0, 160, 144));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut filter = Filter::default();
+        for _ in 0..FILTERS.len() {
+            assert_eq!(Filter::parse(filter.name()), Ok(filter));
+            filter = filter.next();
+        }
+        assert_eq!(filter, Filter::Nearest);
+    }
+}
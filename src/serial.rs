@@ -1,16 +1,191 @@
-use crate::memory::Memory;
+use crate::memory::{InterruptFlag, InterruptType, Memory};
+use crate::savestate::SaveState;
+use crate::util::is_bit_on;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+// CPU clocks to shift one bit with the internal clock (8192Hz / 262144Hz)
+const NORMAL_BIT_CLOCKS: u32 = 512;
+const FAST_BIT_CLOCKS: u32 = 16;
+
+// The device on the other end of the link cable.
+pub trait SerialLink: Send {
+    // This side drives the clock and has shifted out `data`.
+    // Returns the byte shifted in from the other end.
+    fn transfer(&mut self, data: u8) -> u8;
+
+    // This side waits for the other end to drive the clock with `data` in SB.
+    // Returns the received byte once the other end has completed a transfer.
+    fn poll(&mut self, data: u8) -> Option<u8>;
+
+    // Called on every step with the elapsed clocks at normal speed, before
+    // `transfer` or `poll`. Links that keep time with the other end use this.
+    fn advance(&mut self, _clocks: u32) {}
+
+    // Whether the other end is there. Links that can lose it report the loss
+    // here so the frontend can tell the user.
+    fn connected(&self) -> bool {
+        true
+    }
+}
+
+// Nothing connected: the data line is pulled up and no external clock arrives.
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn transfer(&mut self, _data: u8) -> u8 {
+        0xff
+    }
+
+    fn poll(&mut self, _data: u8) -> Option<u8> {
+        None
+    }
+
+    fn connected(&self) -> bool {
+        false
+    }
+}
+
+// Writes every byte sent with the internal clock, e.g. test ROM output to stdout.
+pub struct TextLink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> TextLink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl TextLink<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send> SerialLink for TextLink<W> {
+    fn transfer(&mut self, data: u8) -> u8 {
+        let _ = self.out.write_all(&[data]).and_then(|_| self.out.flush());
+        0xff
+    }
+
+    fn poll(&mut self, _data: u8) -> Option<u8> {
+        None
+    }
+}
 
 #[derive(Default)]
+struct Port {
+    // SB of a side waiting for an external clock
+    waiting: Option<u8>,
+    received: Option<u8>,
+}
+
+// One end of a cable connecting two emulated Game Boys.
+pub struct LinkCable {
+    ports: Arc<Mutex<[Port; 2]>>,
+    side: usize,
+}
+
+impl LinkCable {
+    pub fn pair() -> (LinkCable, LinkCable) {
+        let ports = Arc::new(Mutex::new([Port::default(), Port::default()]));
+        (
+            LinkCable {
+                ports: ports.clone(),
+                side: 0,
+            },
+            LinkCable { ports, side: 1 },
+        )
+    }
+}
+
+impl SerialLink for LinkCable {
+    fn transfer(&mut self, data: u8) -> u8 {
+        let mut ports = self.ports.lock().unwrap();
+        ports[self.side].waiting = None;
+        let other = &mut ports[1 - self.side];
+        match other.waiting.take() {
+            Some(v) => {
+                other.received = Some(data);
+                v
+            }
+            None => 0xff,
+        }
+    }
+
+    fn poll(&mut self, data: u8) -> Option<u8> {
+        let mut ports = self.ports.lock().unwrap();
+        let port = &mut ports[self.side];
+        match port.received.take() {
+            Some(v) => Some(v),
+            None => {
+                port.waiting = Some(data);
+                None
+            }
+        }
+    }
+}
+
 pub struct Serial {
+    is_gbc: bool,
     data: u8,
     control: u8,
+    clocks: u32,
+    link: Box<dyn SerialLink>,
+}
+
+impl Serial {
+    pub fn new(is_gbc: bool) -> Self {
+        Self {
+            is_gbc,
+            data: 0,
+            control: 0,
+            clocks: 0,
+            link: Box::new(Disconnected),
+        }
+    }
+
+    pub fn set_link(&mut self, link: Box<dyn SerialLink>) {
+        self.link = link;
+    }
+
+    pub fn advance_link(&mut self, clocks: u32) {
+        self.link.advance(clocks);
+    }
+
+    pub fn link_connected(&self) -> bool {
+        self.link.connected()
+    }
+
+    pub fn tick(&mut self, clocks: u32, int_flag: &mut InterruptFlag) {
+        if !is_bit_on(self.control, 7) {
+            return;
+        }
+        if is_bit_on(self.control, 0) {
+            if self.clocks > clocks {
+                self.clocks -= clocks;
+                return;
+            }
+            self.clocks = 0;
+            self.data = self.link.transfer(self.data);
+        } else {
+            match self.link.poll(self.data) {
+                Some(v) => self.data = v,
+                None => return,
+            }
+        }
+        self.control &= 0x7f;
+        int_flag.interrupt(InterruptType::Serial);
+    }
 }
 
 impl Memory for Serial {
     fn read(&self, a: u16) -> u8 {
         match a {
             0xff01 => self.data,
-            0xff02 => self.control,
+            0xff02 if self.is_gbc => self.control | 0x7c,
+            0xff02 => self.control | 0x7e,
             _ => panic!(),
         }
     }
@@ -18,8 +193,30 @@ impl Memory for Serial {
     fn write(&mut self, a: u16, v: u8) {
         match a {
             0xff01 => self.data = v,
-            0xff02 => self.control = v,
+            0xff02 => {
+                self.control = v & if self.is_gbc { 0x83 } else { 0x81 };
+                let bit_clocks = if is_bit_on(self.control, 1) {
+                    FAST_BIT_CLOCKS
+                } else {
+                    NORMAL_BIT_CLOCKS
+                };
+                self.clocks = bit_clocks * 8;
+            }
             _ => panic!(),
         }
     }
 }
+
+impl SaveState for Serial {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.data.save(w)?;
+        self.control.save(w)?;
+        self.clocks.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.data.load(r)?;
+        self.control.load(r)?;
+        self.clocks.load(r)
+    }
+}
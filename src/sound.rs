@@ -1,6 +1,8 @@
 use crate::memory::Memory;
+use crate::savestate::{invalid_data, SaveState};
 use crate::util::is_bit_on;
 use blip_buf::BlipBuf;
+use std::io::{self, Read, Write};
 
 const WAVE_PATTERN: [[i32; 8]; 4] = [
     [-1, -1, -1, -1, 1, -1, -1, -1],
@@ -10,11 +12,18 @@ const WAVE_PATTERN: [[i32; 8]; 4] = [
 ];
 const CLOCKS_PER_SECOND: u32 = 1 << 22;
 const OUTPUT_SAMPLE_COUNT: usize = 2000;
+// Longest channel period, that of the noise channel with the slowest clock
+const MAX_PERIOD: u32 = 112 << 15;
+// Rate of the blip buffers until a player is attached
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
 
 pub trait AudioPlayer: Send {
     fn play(&mut self, left_channel: &[f32], right_channel: &[f32]);
     fn samples_rate(&self) -> u32;
     fn underflowed(&self) -> bool;
+
+    // Samples dropped to let the player catch up after loading a state.
+    fn discard(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
 }
 
 pub struct Sound {
@@ -31,7 +40,9 @@ pub struct Sound {
     next_time: u32,
     need_sync: bool,
     time_divider: u8,
-    player: Box<dyn AudioPlayer>,
+    // Without a player the registers and channels still run, the samples
+    // are thrown away.
+    player: Option<Box<dyn AudioPlayer>>,
     nr51: u8,
 }
 
@@ -41,15 +52,23 @@ fn create_blipbuf(samples_rate: u32) -> BlipBuf {
     blipbuf
 }
 
+fn output_period(samples_rate: u32) -> u32 {
+    ((OUTPUT_SAMPLE_COUNT as u64 * u64::from(CLOCKS_PER_SECOND)) / u64::from(samples_rate)) as u32
+}
+
+impl Default for Sound {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Sound {
-    pub fn new(player: Box<dyn AudioPlayer>) -> Self {
-        let blipbuf1 = create_blipbuf(player.samples_rate());
-        let blipbuf2 = create_blipbuf(player.samples_rate());
-        let blipbuf3 = create_blipbuf(player.samples_rate());
-        let blipbuf4 = create_blipbuf(player.samples_rate());
-
-        let output_period = (OUTPUT_SAMPLE_COUNT as u64 * u64::from(CLOCKS_PER_SECOND))
-            / u64::from(player.samples_rate());
+    pub fn new() -> Self {
+        let blipbuf1 = create_blipbuf(DEFAULT_SAMPLE_RATE);
+        let blipbuf2 = create_blipbuf(DEFAULT_SAMPLE_RATE);
+        let blipbuf3 = create_blipbuf(DEFAULT_SAMPLE_RATE);
+        let blipbuf4 = create_blipbuf(DEFAULT_SAMPLE_RATE);
+
         Sound {
             channel1: SquareSound::new(blipbuf1, true),
             channel2: SquareSound::new(blipbuf2, false),
@@ -59,15 +78,26 @@ impl Sound {
             volume_right: 7,
             on: false,
             time: 0,
-            output_period: output_period as u32,
+            output_period: output_period(DEFAULT_SAMPLE_RATE),
             prev_time: 0,
             next_time: 0,
             need_sync: false,
             time_divider: 0,
-            player,
+            player: None,
             nr51: 0,
         }
     }
+
+    // Send the output to `player`, resampling to its rate from now on.
+    pub fn set_player(&mut self, player: Box<dyn AudioPlayer>) {
+        let rate = player.samples_rate();
+        self.channel1.blip = create_blipbuf(rate);
+        self.channel2.blip = create_blipbuf(rate);
+        self.channel3.blip = create_blipbuf(rate);
+        self.channel4.blip = create_blipbuf(rate);
+        self.output_period = output_period(rate);
+        self.player = Some(player);
+    }
 }
 
 impl Sound {
@@ -78,17 +108,20 @@ impl Sound {
             self.channel3.run(self.prev_time, self.next_time);
             self.channel4.run(self.prev_time, self.next_time);
 
-            self.channel1.step_length();
-            self.channel2.step_length();
-            self.channel3.step_length();
-            self.channel4.step_length();
-
-            if self.time_divider == 0 {
-                self.channel1.volume_envelope.step();
-                self.channel2.volume_envelope.step();
-                self.channel4.volume_envelope.step();
-            } else if self.time_divider & 1 == 1 {
-                self.channel1.step_sweep();
+            // The frame sequencer stops while powered off
+            if self.on {
+                self.channel1.step_length();
+                self.channel2.step_length();
+                self.channel3.step_length();
+                self.channel4.step_length();
+
+                if self.time_divider == 0 {
+                    self.channel1.volume_envelope.step();
+                    self.channel2.volume_envelope.step();
+                    self.channel4.volume_envelope.step();
+                } else if self.time_divider & 1 == 1 {
+                    self.channel1.step_sweep();
+                }
             }
 
             self.time_divider = (self.time_divider + 1) % 4;
@@ -106,10 +139,11 @@ impl Sound {
         }
     }
 
+    // Time keeps running while powered off so the output stays in step
+    // with the emulation, the silenced channels then output silence. The
+    // channels catch up in `run`, before a write, a read of their state or
+    // the next output.
     pub fn tick(&mut self, clocks: u32) {
-        if !self.on {
-            return;
-        }
         self.time += clocks;
 
         if self.time >= self.output_period {
@@ -128,15 +162,21 @@ impl Sound {
         self.time = 0;
         self.prev_time = 0;
 
-        if !self.need_sync || self.player.underflowed() {
+        let play = match &self.player {
+            Some(player) => !self.need_sync || player.underflowed(),
+            None => {
+                self.clear_buffers();
+                return;
+            }
+        };
+        if play {
             self.need_sync = false;
-            self.mix_buffers();
-        } else {
-            self.clear_buffers();
         }
+        self.mix_buffers(play);
     }
 
-    fn mix_buffers(&mut self) {
+    // Hand the samples to the player, to be played or discarded.
+    fn mix_buffers(&mut self, play: bool) {
         let sample_count = self.channel1.blip.samples_avail() as usize;
         debug_assert!(sample_count == self.channel2.blip.samples_avail() as usize);
         debug_assert!(sample_count == self.channel3.blip.samples_avail() as usize);
@@ -196,7 +236,13 @@ impl Sound {
             debug_assert!(count1 == count3);
             debug_assert!(count1 == count4);
 
-            self.player.play(&buf_left[..count1], &buf_right[..count1]);
+            if let Some(player) = &mut self.player {
+                if play {
+                    player.play(&buf_left[..count1], &buf_right[..count1]);
+                } else {
+                    player.discard(&buf_left[..count1], &buf_right[..count1]);
+                }
+            }
 
             outputted += count1;
         }
@@ -226,12 +272,15 @@ impl Memory for Sound {
                     | (if self.channel3.on() { 4 } else { 0 })
                     | (if self.channel4.on() { 8 } else { 0 })
             }
+            0xff76 => self.channel2.pcm() << 4 | self.channel1.pcm(),
+            0xff77 => self.channel4.pcm() << 4 | self.channel3.pcm(),
             _ => 0,
         }
     }
 
     fn write(&mut self, a: u16, v: u8) {
-        if a != 0xff26 && !self.on {
+        // Only NR52 and wave RAM can be written while powered off
+        if !self.on && !matches!(a, 0xff26 | 0xff30..=0xff3f) {
             return;
         }
         self.run();
@@ -245,8 +294,23 @@ impl Memory for Sound {
                 self.volume_right = (v & 0b0111_0000) >> 4;
             }
             0xff25 => self.nr51 = v,
-            0xff26 => self.on = is_bit_on(v, 7),
-            _ => panic!(),
+            0xff26 => {
+                // Powering off clears NR10-NR51 and stops every channel
+                if !is_bit_on(v, 7) {
+                    for register in 0xff10..=0xff25 {
+                        self.write(register, 0);
+                    }
+                    self.on = false;
+                    self.channel1.enabled = false;
+                    self.channel2.enabled = false;
+                    self.channel3.channel_enabled = false;
+                    self.channel4.enabled = false;
+                    self.run();
+                } else {
+                    self.on = true;
+                }
+            }
+            _ => {}
         }
     }
 }
@@ -357,6 +421,11 @@ impl SquareSound {
         self.enabled
     }
 
+    // Current digital output, 0-15
+    fn pcm(&self) -> u8 {
+        self.last_amp.max(0) as u8
+    }
+
     fn calc_period(&mut self) {
         if self.frequency > 2048 {
             self.period = 0;
@@ -382,11 +451,7 @@ impl SquareSound {
 
             let offset = self.sweep_frequency >> self.sweep_shift;
             self.sweep_frequency = if self.sweep_negate {
-                if self.sweep_frequency <= offset {
-                    0
-                } else {
-                    self.sweep_frequency - offset
-                }
+                self.sweep_frequency.saturating_sub(offset)
             } else if self.sweep_frequency >= 2048 - offset {
                 2048
             } else {
@@ -539,6 +604,10 @@ impl WaveSound {
         self.channel_enabled
     }
 
+    fn pcm(&self) -> u8 {
+        self.last_amp.max(0) as u8
+    }
+
     fn calc_period(&mut self) {
         if self.frequency > 2048 {
             self.period = 0;
@@ -697,6 +766,10 @@ impl NoiseSound {
         self.enabled
     }
 
+    fn pcm(&self) -> u8 {
+        self.last_amp.max(0) as u8
+    }
+
     fn step_length(&mut self) {
         if self.length_enabled && self.length != 0 {
             self.length -= 1;
@@ -788,3 +861,225 @@ impl Memory for NoiseSound {
         }
     }
 }
+
+// Blip buffers are not saved. They are cleared on load and every channel's
+// amplitude restarts from zero.
+impl SaveState for Sound {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.channel1.save(w)?;
+        self.channel2.save(w)?;
+        self.channel3.save(w)?;
+        self.channel4.save(w)?;
+        self.volume_left.save(w)?;
+        self.volume_right.save(w)?;
+        self.on.save(w)?;
+        self.time.save(w)?;
+        self.prev_time.save(w)?;
+        self.next_time.save(w)?;
+        self.time_divider.save(w)?;
+        self.nr51.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.channel1.load(r)?;
+        self.channel2.load(r)?;
+        self.channel3.load(r)?;
+        self.channel4.load(r)?;
+        self.volume_left.load(r)?;
+        self.volume_right.load(r)?;
+        self.on.load(r)?;
+        self.time.load(r)?;
+        self.prev_time.load(r)?;
+        self.next_time.load(r)?;
+        self.time_divider.load(r)?;
+        self.nr51.load(r)?;
+        // Time is kept below a second, the length of the blip buffers
+        if self.prev_time > self.time
+            || self.time > CLOCKS_PER_SECOND / 2
+            || self.next_time < self.prev_time
+            || self.next_time > self.prev_time + CLOCKS_PER_SECOND / 256
+            || self.time_divider > 3
+            || self.volume_left > 7
+            || self.volume_right > 7
+        {
+            return Err(invalid_data("invalid APU state"));
+        }
+        self.clear_buffers();
+        self.need_sync = true;
+        Ok(())
+    }
+}
+
+impl SaveState for VolumeEnvelope {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.init_volume.save(w)?;
+        self.is_amplify.save(w)?;
+        self.sweep_period.save(w)?;
+        self.delay.save(w)?;
+        self.volume.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.init_volume.load(r)?;
+        self.is_amplify.load(r)?;
+        self.sweep_period.load(r)?;
+        self.delay.load(r)?;
+        self.volume.load(r)?;
+        if self.init_volume > 15 || self.volume > 15 {
+            return Err(invalid_data("invalid APU state"));
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for SquareSound {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.nr0.save(w)?;
+        self.nr1.save(w)?;
+        self.nr2.save(w)?;
+        self.nr3.save(w)?;
+        self.nr4.save(w)?;
+        self.sweep_period.save(w)?;
+        self.sweep_negate.save(w)?;
+        self.sweep_shift.save(w)?;
+        self.duty.save(w)?;
+        self.new_length.save(w)?;
+        self.frequency.save(w)?;
+        self.length.save(w)?;
+        self.enabled.save(w)?;
+        self.sweep_frequency.save(w)?;
+        self.length_enabled.save(w)?;
+        self.sweep_delay.save(w)?;
+        self.period.save(w)?;
+        self.volume_envelope.save(w)?;
+        self.delay.save(w)?;
+        self.phase.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.nr0.load(r)?;
+        self.nr1.load(r)?;
+        self.nr2.load(r)?;
+        self.nr3.load(r)?;
+        self.nr4.load(r)?;
+        self.sweep_period.load(r)?;
+        self.sweep_negate.load(r)?;
+        self.sweep_shift.load(r)?;
+        self.duty.load(r)?;
+        self.new_length.load(r)?;
+        self.frequency.load(r)?;
+        self.length.load(r)?;
+        self.enabled.load(r)?;
+        self.sweep_frequency.load(r)?;
+        self.length_enabled.load(r)?;
+        self.sweep_delay.load(r)?;
+        self.period.load(r)?;
+        self.volume_envelope.load(r)?;
+        self.delay.load(r)?;
+        self.phase.load(r)?;
+        if self.duty > 3
+            || self.phase > 7
+            || self.sweep_shift > 7
+            || self.period > MAX_PERIOD
+            || self.delay > MAX_PERIOD
+        {
+            return Err(invalid_data("invalid APU state"));
+        }
+        self.last_amp = 0;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveSound {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.nr0.save(w)?;
+        self.nr1.save(w)?;
+        self.nr2.save(w)?;
+        self.nr3.save(w)?;
+        self.nr4.save(w)?;
+        self.dac_enabled.save(w)?;
+        self.channel_enabled.save(w)?;
+        self.length.save(w)?;
+        self.new_length.save(w)?;
+        self.length_enabled.save(w)?;
+        self.volume_code.save(w)?;
+        self.frequency.save(w)?;
+        self.current_wave.save(w)?;
+        self.delay.save(w)?;
+        self.period.save(w)?;
+        self.waveram.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.nr0.load(r)?;
+        self.nr1.load(r)?;
+        self.nr2.load(r)?;
+        self.nr3.load(r)?;
+        self.nr4.load(r)?;
+        self.dac_enabled.load(r)?;
+        self.channel_enabled.load(r)?;
+        self.length.load(r)?;
+        self.new_length.load(r)?;
+        self.length_enabled.load(r)?;
+        self.volume_code.load(r)?;
+        self.frequency.load(r)?;
+        self.current_wave.load(r)?;
+        self.delay.load(r)?;
+        self.period.load(r)?;
+        self.waveram.load(r)?;
+        if self.current_wave > 31
+            || self.volume_code > 3
+            || self.period > MAX_PERIOD
+            || self.delay > MAX_PERIOD
+        {
+            return Err(invalid_data("invalid APU state"));
+        }
+        self.last_amp = 0;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseSound {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.nr0.save(w)?;
+        self.nr1.save(w)?;
+        self.nr2.save(w)?;
+        self.nr3.save(w)?;
+        self.nr4.save(w)?;
+        self.enabled.save(w)?;
+        self.length.save(w)?;
+        self.new_length.save(w)?;
+        self.length_enabled.save(w)?;
+        self.volume_envelope.save(w)?;
+        self.period.save(w)?;
+        self.delay.save(w)?;
+        self.shift_width.save(w)?;
+        self.state.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.nr0.load(r)?;
+        self.nr1.load(r)?;
+        self.nr2.load(r)?;
+        self.nr3.load(r)?;
+        self.nr4.load(r)?;
+        self.enabled.load(r)?;
+        self.length.load(r)?;
+        self.new_length.load(r)?;
+        self.length_enabled.load(r)?;
+        self.volume_envelope.load(r)?;
+        self.period.load(r)?;
+        self.delay.load(r)?;
+        self.shift_width.load(r)?;
+        self.state.load(r)?;
+        if self.shift_width > 15
+            || self.period == 0
+            || self.period > MAX_PERIOD
+            || self.delay > MAX_PERIOD
+        {
+            return Err(invalid_data("invalid APU state"));
+        }
+        self.last_amp = 0;
+        Ok(())
+    }
+}
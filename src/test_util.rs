@@ -0,0 +1,72 @@
+// Fixtures shared by the unit tests.
+
+use crate::gpu::GPU;
+use crate::memory::Memory;
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+// 32KB ROM only cartridge with each code block copied at its address.
+pub(crate) fn rom(code: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut rom = vec![0u8; 0x8000];
+    for (address, bytes) in code {
+        rom[*address..*address + bytes.len()].copy_from_slice(bytes);
+    }
+    rom
+}
+
+// Loops forever at the entry point.
+pub(crate) fn looping_rom() -> Vec<u8> {
+    rom(&[(0x100, &[0x18, 0xfe])]) // JR -2
+}
+
+// Starts a serial transfer of `data` with SC set to `control`, then loops.
+pub(crate) fn serial_rom(data: u8, control: u8) -> Vec<u8> {
+    rom(&[(
+        0x100,
+        &[
+            0x3e, data, // LD A,data
+            0xe0, 0x01, // LDH (SB),A
+            0x3e, control, // LD A,control
+            0xe0, 0x02, // LDH (SC),A
+            0x18, 0xfe, // JR -2
+        ],
+    )])
+}
+
+pub(crate) fn put_sprite(gpu: &mut GPU, index: u16, x: u8, y: u8, tile: u8) {
+    put_sprite_attr(gpu, index, x, y, tile, 0x00);
+}
+
+pub(crate) fn put_sprite_attr(gpu: &mut GPU, index: u16, x: u8, y: u8, tile: u8, attr: u8) {
+    let address = 0xfe00 + index * 4;
+    gpu.write(address, y);
+    gpu.write(address + 1, x);
+    gpu.write(address + 2, tile);
+    gpu.write(address + 3, attr);
+}
+
+// In-memory output that stays readable after being boxed away.
+#[derive(Clone, Default)]
+pub(crate) struct Shared(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl Shared {
+    pub(crate) fn data(&self) -> Vec<u8> {
+        self.0.lock().unwrap().get_ref().clone()
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Shared {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.lock().unwrap().seek(pos)
+    }
+}
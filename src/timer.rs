@@ -1,5 +1,7 @@
 use crate::memory::{InterruptFlag, InterruptType, Memory};
+use crate::savestate::{invalid_data, SaveState};
 use crate::util::is_bit_on;
+use std::io::{self, Read, Write};
 
 #[derive(Default)]
 pub struct Timer {
@@ -99,3 +101,28 @@ impl Memory for Timer {
         }
     }
 }
+
+impl SaveState for Timer {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.div_clocks.save(w)?;
+        self.main_clocks.save(w)?;
+        self.divider.save(w)?;
+        self.counter.save(w)?;
+        self.modulo.save(w)?;
+        self.tac.inner.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.div_clocks.load(r)?;
+        self.main_clocks.load(r)?;
+        self.divider.load(r)?;
+        self.counter.load(r)?;
+        self.modulo.load(r)?;
+        self.tac.inner.load(r)?;
+        // The counter may still hold clocks of a slower rate set before TAC changed
+        if self.div_clocks >= 256 || self.main_clocks >= 1024 {
+            return Err(invalid_data("invalid timer state"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+// Per-instruction trace log in the format used by Gameboy Doctor and other
+// reference emulators:
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+
+use crate::memory::Memory;
+use crate::reg::Registers;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    // Machine cycles elapsed since tracing started
+    cycles: u64,
+    show_cycles: bool,
+    show_bank: bool,
+    pc_range: RangeInclusive<u16>,
+    bank: Option<u16>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out,
+            cycles: 0,
+            show_cycles: false,
+            show_bank: false,
+            pc_range: 0x0000..=0xffff,
+            bank: None,
+        }
+    }
+
+    // Append the elapsed machine cycles to each line.
+    pub fn show_cycles(mut self, show_cycles: bool) -> Self {
+        self.show_cycles = show_cycles;
+        self
+    }
+
+    // Append the bank mapped at PC to each line.
+    pub fn show_bank(mut self, show_bank: bool) -> Self {
+        self.show_bank = show_bank;
+        self
+    }
+
+    // Only log instructions whose address is in `pc_range`.
+    pub fn pc_range(mut self, pc_range: RangeInclusive<u16>) -> Self {
+        self.pc_range = pc_range;
+        self
+    }
+
+    // Only log instructions executed while `bank` is mapped at PC.
+    pub fn bank(mut self, bank: Option<u16>) -> Self {
+        self.bank = bank;
+        self
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        self.cycles += u64::from(cycles);
+    }
+
+    // Log the instruction about to be executed at `reg.pc`.
+    pub fn trace(&mut self, reg: &Registers, mem: &dyn Memory) {
+        let pc = reg.pc;
+        if !self.pc_range.contains(&pc) {
+            return;
+        }
+        let bank = mem.bank_at(pc);
+        if self.bank.is_some_and(|b| b != bank) {
+            return;
+        }
+        if let Err(e) = self.write_line(reg, mem, bank) {
+            eprintln!("Failed to write trace, tracing stopped: {}", e);
+            self.out = Box::new(io::sink());
+        }
+    }
+
+    fn write_line(&mut self, reg: &Registers, mem: &dyn Memory, bank: u16) -> io::Result<()> {
+        let pc = reg.pc;
+        write!(
+            self.out,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+             SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            reg.a,
+            reg.f,
+            reg.b,
+            reg.c,
+            reg.d,
+            reg.e,
+            reg.h,
+            reg.l,
+            reg.sp,
+            pc,
+            mem.peek(pc),
+            mem.peek(pc.wrapping_add(1)),
+            mem.peek(pc.wrapping_add(2)),
+            mem.peek(pc.wrapping_add(3)),
+        )?;
+        if self.show_bank {
+            write!(self.out, " BANK:{:02X}", bank)?;
+        }
+        if self.show_cycles {
+            write!(self.out, " CY:{}", self.cycles)?;
+        }
+        writeln!(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::{WatchKind, Watchpoint};
+    use crate::gameboy::Gameboy;
+    use crate::test_util::{rom, Shared};
+
+    // Run three instructions of a ROM that is NOP; JR -3 at 0x100.
+    fn trace(tracer: impl FnOnce(Tracer) -> Tracer) -> Vec<String> {
+        let rom = rom(&[(0x100, &[0x00, 0x18, 0xfd])]);
+        let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+        // Only the tracer looks at 0x103
+        gameboy.mmu.watcher.add(Watchpoint {
+            address: 0x103,
+            kind: WatchKind::Read,
+        });
+        let out = Shared::default();
+        gameboy.set_tracer(Some(tracer(Tracer::new(Box::new(out.clone())))));
+        for _ in 0..3 {
+            gameboy.step_instruction();
+        }
+        assert_eq!(gameboy.mmu.watcher.take_hit(), None);
+        let log = String::from_utf8(out.data()).unwrap();
+        log.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_trace_format() {
+        let lines = trace(|t| t);
+        assert_eq!(
+            lines,
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,18,FD,00",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:18,FD,00,00",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,18,FD,00",
+            ]
+        );
+        let lines = trace(|t| t.show_cycles(true).show_bank(true));
+        assert!(lines[0].ends_with("PCMEM:00,18,FD,00 BANK:00 CY:0"));
+        assert!(lines[1].ends_with(" BANK:00 CY:1"));
+        assert!(lines[2].ends_with(" BANK:00 CY:4"));
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let lines = trace(|t| t.pc_range(0x101..=0x1ff));
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("PC:0101"));
+        assert!(trace(|t| t.bank(Some(1))).is_empty());
+        assert_eq!(trace(|t| t.bank(Some(0))).len(), 3);
+    }
+}
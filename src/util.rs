@@ -28,9 +28,9 @@ mod tests {
 
     #[test]
     fn test_get_bit() {
-        assert_eq!(is_bit_on(0b_1000_0000, 7), true);
-        assert_eq!(is_bit_on(0b_1000_0000, 6), false);
-        assert_eq!(is_bit_on(0b_0010_0000, 5), true);
+        assert!(is_bit_on(0b_1000_0000, 7));
+        assert!(!is_bit_on(0b_1000_0000, 6));
+        assert!(is_bit_on(0b_0010_0000, 5));
     }
 
     #[test]
@@ -0,0 +1,335 @@
+// Recording of the mixed stereo output to a WAV file. The recorder is an
+// `AudioPlayer` and can wrap the real player to keep the game audible; the
+// samples are then resampled from the player's rate to the recorded one.
+
+use crate::sound::AudioPlayer;
+use std::io::{self, Seek, SeekFrom, Write};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleFormat {
+    #[default]
+    Pcm16,
+    Float,
+}
+
+pub const SAMPLE_FORMATS: [&str; 2] = ["pcm16", "float"];
+
+impl SampleFormat {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "pcm16" => Ok(SampleFormat::Pcm16),
+            "float" => Ok(SampleFormat::Float),
+            _ => Err(format!(
+                "unknown sample format {}, expected one of {}",
+                s,
+                SAMPLE_FORMATS.join(", ")
+            )),
+        }
+    }
+
+    fn bytes(self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 2,
+            SampleFormat::Float => 4,
+        }
+    }
+
+    // Most frames whose data still fits the 32-bit RIFF length.
+    fn max_frames(self) -> u32 {
+        (u32::MAX - 50) / u32::from(2 * self.bytes())
+    }
+
+    // WAVE_FORMAT_PCM or WAVE_FORMAT_IEEE_FLOAT
+    fn tag(self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 1,
+            SampleFormat::Float => 3,
+        }
+    }
+}
+
+pub struct WavRecorder<W: Write + Seek + Send> {
+    // None once a write failed
+    out: Option<W>,
+    format: SampleFormat,
+    sample_rate: u32,
+    player: Option<Box<dyn AudioPlayer>>,
+    // Input samples per recorded sample, None when the rates match
+    step: Option<f64>,
+    // Position of the next recorded sample after `last`, in input samples
+    position: f64,
+    last: (f32, f32),
+    frames: u32,
+}
+
+impl<W: Write + Seek + Send> WavRecorder<W> {
+    // Start a recording of `sample_rate` stereo frames per second.
+    pub fn new(mut out: W, format: SampleFormat, sample_rate: u32) -> io::Result<Self> {
+        write_header(&mut out, format, sample_rate, 0)?;
+        Ok(Self {
+            out: Some(out),
+            format,
+            sample_rate,
+            player: None,
+            step: None,
+            position: 0.0,
+            last: (0.0, 0.0),
+            frames: 0,
+        })
+    }
+
+    // Pass the samples on to `player`, which then sets the rate of the APU.
+    pub fn player(mut self, player: Option<Box<dyn AudioPlayer>>) -> Self {
+        self.step = match &player {
+            Some(player) if player.samples_rate() != self.sample_rate => {
+                Some(f64::from(player.samples_rate()) / f64::from(self.sample_rate))
+            }
+            _ => None,
+        };
+        self.player = player;
+        self
+    }
+
+    // Complete the header and return the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.out.take() {
+            Some(mut out) => {
+                self.finish_header(&mut out)?;
+                Ok(out)
+            }
+            None => Err(io::Error::other("recording stopped after a write error")),
+        }
+    }
+
+    fn finish_header(&self, out: &mut W) -> io::Result<()> {
+        out.seek(SeekFrom::Start(0))?;
+        write_header(out, self.format, self.sample_rate, self.frames)?;
+        out.seek(SeekFrom::End(0))?;
+        out.flush()
+    }
+
+    fn record(&mut self, left: &[f32], right: &[f32]) -> io::Result<()> {
+        let mut frames = Vec::new();
+        for (&l, &r) in left.iter().zip(right) {
+            match self.step {
+                None => frames.push((l, r)),
+                Some(step) => {
+                    let (last_l, last_r) = self.last;
+                    while self.position < 1.0 {
+                        let t = self.position as f32;
+                        frames.push((last_l + (l - last_l) * t, last_r + (r - last_r) * t));
+                        self.position += step;
+                    }
+                    self.position -= 1.0;
+                    self.last = (l, r);
+                }
+            }
+        }
+
+        let out = match &mut self.out {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+        let full =
+            frames.len() as u64 + u64::from(self.frames) >= u64::from(self.format.max_frames());
+        frames.truncate((self.format.max_frames() - self.frames) as usize);
+        let mut data = Vec::with_capacity(frames.len() * 2 * usize::from(self.format.bytes()));
+        for (l, r) in &frames {
+            for v in &[*l, *r] {
+                match self.format {
+                    SampleFormat::Pcm16 => {
+                        let v = (v.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+                        data.extend_from_slice(&v.to_le_bytes());
+                    }
+                    SampleFormat::Float => data.extend_from_slice(&v.to_le_bytes()),
+                }
+            }
+        }
+        out.write_all(&data)?;
+        self.frames += frames.len() as u32;
+        if full {
+            eprintln!("Audio recording reached the WAV size limit, recording stopped");
+            if let Some(mut out) = self.out.take() {
+                self.finish_header(&mut out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_error(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            eprintln!("Failed to write audio recording, recording stopped: {}", e);
+            self.out = None;
+        }
+    }
+}
+
+impl<W: Write + Seek + Send> AudioPlayer for WavRecorder<W> {
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        if let Some(player) = &mut self.player {
+            player.play(left_channel, right_channel);
+        }
+        let result = self.record(left_channel, right_channel);
+        self.write_error(result);
+    }
+
+    // Keep what the player skips so the recording stays in time.
+    fn discard(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        let result = self.record(left_channel, right_channel);
+        self.write_error(result);
+    }
+
+    fn samples_rate(&self) -> u32 {
+        match &self.player {
+            Some(player) => player.samples_rate(),
+            None => self.sample_rate,
+        }
+    }
+
+    // Without a player the APU is never held back.
+    fn underflowed(&self) -> bool {
+        match &self.player {
+            Some(player) => player.underflowed(),
+            None => true,
+        }
+    }
+}
+
+impl<W: Write + Seek + Send> Drop for WavRecorder<W> {
+    fn drop(&mut self) {
+        if let Some(mut out) = self.out.take() {
+            if let Err(e) = self.finish_header(&mut out) {
+                eprintln!("Failed to finish audio recording: {}", e);
+            }
+        }
+    }
+}
+
+// RIFF header with the format, fact and data chunk headers. It has the same
+// length whatever the values, so it can be rewritten once the length is known.
+fn write_header(
+    w: &mut dyn Write,
+    format: SampleFormat,
+    sample_rate: u32,
+    frames: u32,
+) -> io::Result<()> {
+    let block_align = 2 * format.bytes();
+    let sizes = frames
+        .checked_mul(u32::from(block_align))
+        .and_then(|data_len| Some((data_len, data_len.checked_add(50)?)));
+    let (data_len, riff_len) =
+        sizes.ok_or_else(|| io::Error::other("recording too long for a WAV file"))?;
+    w.write_all(b"RIFF")?;
+    w.write_all(&riff_len.to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&18u32.to_le_bytes())?;
+    w.write_all(&format.tag().to_le_bytes())?;
+    w.write_all(&2u16.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&(format.bytes() * 8).to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?;
+
+    w.write_all(b"fact")?;
+    w.write_all(&4u32.to_le_bytes())?;
+    w.write_all(&frames.to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Shared;
+    use std::io::Cursor;
+
+    struct NullPlayer(u32);
+
+    impl AudioPlayer for NullPlayer {
+        fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+
+        fn samples_rate(&self) -> u32 {
+            self.0
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    #[test]
+    fn test_pcm16() {
+        let mut recorder =
+            WavRecorder::new(Cursor::new(Vec::new()), SampleFormat::Pcm16, 44100).unwrap();
+        assert_eq!(recorder.samples_rate(), 44100);
+        recorder.play(&[0.0, 1.0, -2.0], &[0.5, -1.0, 0.0]);
+        let data = recorder.finish().unwrap().into_inner();
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(u32_at(&data, 24), 44100);
+        assert_eq!(u32_at(&data, 46), 3);
+        assert_eq!(&data[50..54], b"data");
+        assert_eq!(u32_at(&data, 54), 12);
+        let samples: Vec<i16> = data[58..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, [0, 16383, 32767, -32767, -32767, 0]);
+    }
+
+    #[test]
+    fn test_resample() {
+        let recorder =
+            WavRecorder::new(Cursor::new(Vec::new()), SampleFormat::Float, 22050).unwrap();
+        let mut recorder = recorder.player(Some(Box::new(NullPlayer(44100))));
+        assert_eq!(recorder.samples_rate(), 44100);
+        assert!(!recorder.underflowed());
+        recorder.play(&[1.0, 1.0, 0.5, 0.5], &[0.0, 0.0, 0.0, -1.0]);
+        let data = recorder.finish().unwrap().into_inner();
+        assert_eq!(u32_at(&data, 24), 22050);
+        let samples: Vec<f32> = data[58..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(samples, [0.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let out = Shared::default();
+        let mut recorder = WavRecorder::new(out.clone(), SampleFormat::Pcm16, 44100).unwrap();
+        let max_frames = SampleFormat::Pcm16.max_frames();
+        recorder.frames = max_frames - 1;
+        recorder.play(&[0.0, 0.5, 1.0], &[0.0, 0.5, 1.0]);
+        recorder.play(&[0.0], &[0.0]);
+        assert!(recorder.finish().is_err());
+        let data = out.data();
+        assert_eq!(data.len(), 58 + 4);
+        assert_eq!(u32_at(&data, 4), 50 + max_frames * 4);
+        assert_eq!(u32_at(&data, 46), max_frames);
+        assert!(write_header(&mut Vec::new(), SampleFormat::Pcm16, 44100, max_frames + 1).is_err());
+    }
+
+    #[test]
+    fn test_discard() {
+        let recorder =
+            WavRecorder::new(Cursor::new(Vec::new()), SampleFormat::Pcm16, 44100).unwrap();
+        let mut recorder = recorder.player(Some(Box::new(NullPlayer(44100))));
+        recorder.discard(&[0.0, 0.0], &[0.0, 0.0]);
+        let data = recorder.finish().unwrap().into_inner();
+        assert_eq!(u32_at(&data, 46), 2);
+    }
+}
@@ -0,0 +1,75 @@
+// Helpers shared by the integration tests that run test ROMs.
+#![allow(dead_code)]
+
+use gameboy::gameboy::{Gameboy, FRAME_CLOCKS};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// Test ROMs and reference images are not distributed with the crate. They
+// live in tests/roms, or the directory named by GAMEBOY_TEST_ROMS. Missing
+// files are skipped in tests/roms, but fail the test when the directory was
+// given explicitly, so a CI run with the ROMs can't pass without them.
+pub fn test_file(path: &str) -> Option<PathBuf> {
+    let explicit = env::var_os("GAMEBOY_TEST_ROMS").map(PathBuf::from);
+    let dir = explicit
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"));
+    let path = dir.join(path);
+    if path.exists() {
+        Some(path)
+    } else if explicit.is_some() {
+        panic!("{} not found in GAMEBOY_TEST_ROMS", path.display());
+    } else {
+        eprintln!("skipping, {} not found", path.display());
+        None
+    }
+}
+
+pub fn load_rom(path: &str) -> Option<Vec<u8>> {
+    test_file(path).map(|path| fs::read(path).unwrap())
+}
+
+// Serial output collected by a `TextLink`.
+#[derive(Clone, Default)]
+pub struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Run until the CPU is about to execute `LD B,B`, which test ROMs use as a
+// breakpoint to report that they are done. Returns false on timeout.
+pub fn run_to_breakpoint(gameboy: &mut Gameboy, max_frames: u32) -> bool {
+    let max_clocks = u64::from(max_frames) * u64::from(FRAME_CLOCKS);
+    let mut clocks = 0;
+    while clocks < max_clocks {
+        if gameboy.mmu.peek(gameboy.cpu.reg.pc) == 0x40 {
+            return true;
+        }
+        clocks += u64::from(gameboy.step_instruction());
+    }
+    false
+}
+
+// The entry point jumps over the header to `code` at 0x150.
+pub fn harness_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    rom
+}
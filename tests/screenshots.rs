@@ -0,0 +1,271 @@
+// Screenshot regression tests: run a ROM headlessly and compare the final
+// frame against a reference PNG, e.g.
+//   tests/roms/acid2/dmg-acid2.gb with tests/roms/acid2/dmg-acid2.png
+// Tests whose ROM or reference is missing are skipped, or fail when
+// GAMEBOY_TEST_ROMS is set.
+//
+// On a mismatch the expected frame, the actual frame and the differing pixels
+// are written side by side to target/tmp/screenshot-diffs/<name>.png. Set
+// GAMEBOY_UPDATE_SCREENSHOTS=1 to overwrite the references with the current
+// output instead of comparing.
+
+mod common;
+
+use common::{harness_rom, run_to_breakpoint, test_file};
+use gameboy::gameboy::Gameboy;
+use gameboy::gpu::{SCREEN_H, SCREEN_W};
+use gameboy::palette::ColorCorrection;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy)]
+enum Stop {
+    Frames(u32),
+    // Stop at `LD B,B`, giving up after the number of frames
+    Breakpoint(u32),
+}
+
+#[derive(Clone, Copy)]
+enum Match {
+    Exact,
+    // Compare the four DMG shades only, so references made with another
+    // grey palette still match.
+    Shades,
+}
+
+fn capture(rom: Vec<u8>, stop: Stop) -> Result<Vec<u8>, String> {
+    let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+    gameboy.set_access_blocking(true);
+    // References hold the unprocessed CGB colors
+    gameboy.set_color_correction(ColorCorrection::Raw);
+    match stop {
+        Stop::Frames(frames) => {
+            for _ in 0..frames {
+                gameboy.run_frame();
+            }
+        }
+        Stop::Breakpoint(frames) => {
+            if !run_to_breakpoint(&mut gameboy, frames) {
+                return Err("timed out waiting for LD B,B".to_string());
+            }
+        }
+    }
+    Ok(gameboy.mmu.gpu.get_rgb_data())
+}
+
+// Decode a SCREEN_W x SCREEN_H PNG into RGB888.
+fn read_png(path: &Path) -> Result<Vec<u8>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+    if info.width as usize != SCREEN_W || info.height as usize != SCREEN_H {
+        return Err(format!(
+            "{} is {}x{}, expected {}x{}",
+            path.display(),
+            info.width,
+            info.height,
+            SCREEN_W,
+            SCREEN_H
+        ));
+    }
+    let data = &buf[..info.buffer_size()];
+    let rgb = match info.color_type {
+        png::ColorType::Rgb => data.to_vec(),
+        png::ColorType::Rgba => data
+            .chunks(4)
+            .flat_map(|p| p[..3].iter().copied())
+            .collect(),
+        png::ColorType::Grayscale => data.iter().flat_map(|&g| [g, g, g]).collect(),
+        png::ColorType::GrayscaleAlpha => data.chunks(2).flat_map(|p| [p[0]; 3]).collect(),
+        png::ColorType::Indexed => unreachable!("palettes are expanded"),
+    };
+    Ok(rgb)
+}
+
+fn write_png(path: &Path, width: usize, height: usize, rgb: &[u8]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(rgb).map_err(|e| e.to_string())
+}
+
+fn shade(p: &[u8]) -> u8 {
+    let luma = (u32::from(p[0]) * 299 + u32::from(p[1]) * 587 + u32::from(p[2]) * 114) / 1000;
+    match luma {
+        0xd5..=0xff => 0,
+        0x80..=0xd4 => 1,
+        0x2b..=0x7f => 2,
+        _ => 3,
+    }
+}
+
+// One flag per pixel, set where the images differ.
+fn mismatches(expected: &[u8], actual: &[u8], m: Match) -> Vec<bool> {
+    expected
+        .chunks(3)
+        .zip(actual.chunks(3))
+        .map(|(e, a)| match m {
+            Match::Exact => e != a,
+            Match::Shades => shade(e) != shade(a),
+        })
+        .collect()
+}
+
+// Expected, actual and a dimmed copy of actual with differing pixels in red.
+fn diff_image(expected: &[u8], actual: &[u8], mismatches: &[bool]) -> Vec<u8> {
+    let mut image = Vec::with_capacity(SCREEN_W * 3 * SCREEN_H * 3);
+    for y in 0..SCREEN_H {
+        let row = y * SCREEN_W;
+        image.extend_from_slice(&expected[row * 3..(row + SCREEN_W) * 3]);
+        image.extend_from_slice(&actual[row * 3..(row + SCREEN_W) * 3]);
+        for x in row..row + SCREEN_W {
+            if mismatches[x] {
+                image.extend_from_slice(&[0xff, 0x00, 0x00]);
+            } else {
+                image.extend(actual[x * 3..x * 3 + 3].iter().map(|c| c / 3));
+            }
+        }
+    }
+    image
+}
+
+fn check(name: &str, actual: &[u8], reference: &Path, m: Match) -> Result<(), String> {
+    if env::var_os("GAMEBOY_UPDATE_SCREENSHOTS").is_some() {
+        return write_png(reference, SCREEN_W, SCREEN_H, actual);
+    }
+    let expected = read_png(reference)?;
+    let mismatches = mismatches(&expected, actual, m);
+    let count = mismatches.iter().filter(|&&m| m).count();
+    if count == 0 {
+        return Ok(());
+    }
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("screenshot-diffs");
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let path = dir.join(format!("{}.png", name));
+    let diff = diff_image(&expected, actual, &mismatches);
+    write_png(&path, SCREEN_W * 3, SCREEN_H, &diff)?;
+    Err(format!("{} pixels differ, see {}", count, path.display()))
+}
+
+fn screenshot(name: &str, rom: &str, reference: &str, stop: Stop, m: Match) {
+    let (rom, reference) = match (test_file(rom), test_file(reference)) {
+        (Some(rom), Some(reference)) => (rom, reference),
+        _ => return,
+    };
+    let result = capture(fs::read(rom).unwrap(), stop)
+        .and_then(|actual| check(name, &actual, &reference, m));
+    if let Err(e) = result {
+        panic!("{}: {}", name, e);
+    }
+}
+
+macro_rules! screenshot_tests {
+    ($($name:ident: $rom:expr, $reference:expr, $stop:expr, $match:expr;)*) => {
+        $(
+            #[test]
+            fn $name() {
+                screenshot(stringify!($name), $rom, $reference, $stop, $match);
+            }
+        )*
+    };
+}
+
+const MEALYBUG_FRAMES: Stop = Stop::Breakpoint(60 * 5);
+
+screenshot_tests! {
+    dmg_acid2: "acid2/dmg-acid2.gb", "acid2/dmg-acid2.png", Stop::Breakpoint(60 * 10), Match::Shades;
+    cgb_acid2: "acid2/cgb-acid2.gbc", "acid2/cgb-acid2.png", Stop::Breakpoint(60 * 10), Match::Exact;
+    mealybug_m2_win_en_toggle: "mealybug/m2_win_en_toggle.gb",
+        "mealybug/expected/CPU CGB D/m2_win_en_toggle.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_bgp_change: "mealybug/m3_bgp_change.gb",
+        "mealybug/expected/CPU CGB D/m3_bgp_change.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_lcdc_bg_en_change: "mealybug/m3_lcdc_bg_en_change.gb",
+        "mealybug/expected/CPU CGB D/m3_lcdc_bg_en_change.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_lcdc_obj_size_change: "mealybug/m3_lcdc_obj_size_change.gb",
+        "mealybug/expected/CPU CGB D/m3_lcdc_obj_size_change.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_scx_low_3_bits: "mealybug/m3_scx_low_3_bits.gb",
+        "mealybug/expected/CPU CGB D/m3_scx_low_3_bits.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_window_timing: "mealybug/m3_window_timing.gb",
+        "mealybug/expected/CPU CGB D/m3_window_timing.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_wx_4_change: "mealybug/m3_wx_4_change.gb",
+        "mealybug/expected/CPU CGB D/m3_wx_4_change.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_wx_4_change_sprites: "mealybug/m3_wx_4_change_sprites.gb",
+        "mealybug/expected/CPU CGB D/m3_wx_4_change_sprites.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_wx_5_change: "mealybug/m3_wx_5_change.gb",
+        "mealybug/expected/CPU CGB D/m3_wx_5_change.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_wx_6_change: "mealybug/m3_wx_6_change.gb",
+        "mealybug/expected/CPU CGB D/m3_wx_6_change.png", MEALYBUG_FRAMES, Match::Exact;
+    mealybug_m3_window_timing_wx_0: "mealybug/m3_window_timing_wx_0.gb",
+        "mealybug/expected/CPU CGB D/m3_window_timing_wx_0.png", MEALYBUG_FRAMES, Match::Exact;
+}
+
+// Check the comparison itself against the blank frame of a looping ROM.
+#[test]
+fn test_screenshot_diff() {
+    let actual = capture(harness_rom(&[0x18, 0xfe]), Stop::Frames(2)).unwrap();
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let reference = dir.join("screenshot-reference.png");
+
+    let mut expected: Vec<u8> = actual.chunks(3).flat_map(|_| [0xff, 0xff, 0xff]).collect();
+    write_png(&reference, SCREEN_W, SCREEN_H, &expected).unwrap();
+    assert!(check("self_test", &actual, &reference, Match::Exact).is_ok());
+
+    // Darker white is still the same shade
+    expected.iter_mut().for_each(|c| *c = 0xee);
+    write_png(&reference, SCREEN_W, SCREEN_H, &expected).unwrap();
+    assert!(check("self_test", &actual, &reference, Match::Shades).is_ok());
+    assert!(check("self_test", &actual, &reference, Match::Exact).is_err());
+
+    expected[..3].copy_from_slice(&[0x00, 0x00, 0x00]);
+    write_png(&reference, SCREEN_W, SCREEN_H, &expected).unwrap();
+    let err = check("self_test", &actual, &reference, Match::Shades).unwrap_err();
+    assert!(err.starts_with("1 pixels differ"));
+    let diff = dir.join("screenshot-diffs/self_test.png");
+    let file = File::open(diff).unwrap();
+    let reader = png::Decoder::new(file).read_info().unwrap();
+    assert_eq!(reader.info().width as usize, SCREEN_W * 3);
+}
+
+// Screenshots taken by `--frames` go through `--frame-blend`. The ROM inverts
+// BGP every vblank, so the blank screen alternates between white and black.
+#[cfg(feature = "rip")]
+#[test]
+fn test_headless_frame_blend() {
+    #[rustfmt::skip]
+    let code = [
+        0xf0, 0x44, 0xfe, 0x90, 0x20, 0xfa, // wait for LY 0x90
+        0xf0, 0x47, 0x2f, 0xe0, 0x47, // invert BGP
+        0xf0, 0x44, 0xfe, 0x90, 0x28, 0xfa, // wait for LY to move on
+        0x18, 0xed,
+    ];
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let rom = dir.join("flicker.gb");
+    fs::write(&rom, harness_rom(&code)).unwrap();
+    let screenshot = |frames: &str, blend: &str| {
+        let path = dir.join(format!("flicker-{}-{}.png", frames, blend));
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_gameboy"))
+            .arg(&rom)
+            .args(["--frames", frames, "--frame-blend", blend])
+            .arg("--screenshot")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        read_png(&path).unwrap()
+    };
+    let even = screenshot("10", "off");
+    let odd = screenshot("11", "off");
+    assert_ne!(even[..3], odd[..3]);
+    let mixed = screenshot("11", "mix");
+    for i in 0..3 {
+        let average = (i32::from(even[i]) + i32::from(odd[i])) / 2;
+        assert!((i32::from(mixed[i]) - average).abs() <= 1);
+    }
+}
@@ -0,0 +1,164 @@
+// Runs Blargg and Mooneye test ROMs headlessly.
+//
+// Put the ROMs in tests/roms (see common::test_file) using the layout of the
+// upstream repositories:
+//   tests/roms/blargg/cpu_instrs/individual/01-special.gb
+//   tests/roms/mooneye/acceptance/timer/tim00.gb
+// Tests whose ROM is missing are skipped, or fail when GAMEBOY_TEST_ROMS is set.
+
+mod common;
+
+use common::{harness_rom, load_rom, run_to_breakpoint, Output};
+use gameboy::gameboy::Gameboy;
+use gameboy::serial::TextLink;
+
+const BLARGG_FRAMES: u32 = 60 * 60;
+const MOONEYE_FRAMES: u32 = 60 * 20;
+
+// Registers loaded by Mooneye tests before `LD B,B` when they pass
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Blargg tests print their result on the serial port and finish with
+// "Passed" or "Failed".
+fn run_blargg(rom: Vec<u8>, max_frames: u32) -> Result<String, String> {
+    let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+    let output = Output::default();
+    gameboy.set_serial_link(Box::new(TextLink::new(output.clone())));
+    for _ in 0..max_frames {
+        gameboy.run_frame();
+        let text = output.text();
+        if text.contains("Passed") {
+            return Ok(text);
+        }
+        if text.contains("Failed") {
+            return Err(text);
+        }
+    }
+    Err(format!("timed out, serial output:\n{}", output.text()))
+}
+
+// Mooneye tests execute `LD B,B` when done and signal success by loading the
+// Fibonacci numbers 3, 5, 8, 13, 21, 34 into B, C, D, E, H, L.
+fn run_mooneye(rom: Vec<u8>, max_frames: u32) -> Result<(), String> {
+    let mut gameboy = Gameboy::from_bytes(rom, true).unwrap();
+    gameboy.set_access_blocking(true);
+    if !run_to_breakpoint(&mut gameboy, max_frames) {
+        return Err(format!("timed out at {:?}", gameboy.cpu.reg));
+    }
+    let reg = &gameboy.cpu.reg;
+    if [reg.b, reg.c, reg.d, reg.e, reg.h, reg.l] == MOONEYE_PASS {
+        Ok(())
+    } else {
+        Err(format!("failed with {:?}", reg))
+    }
+}
+
+fn blargg(path: &str) {
+    if let Some(rom) = load_rom(path) {
+        if let Err(e) = run_blargg(rom, BLARGG_FRAMES) {
+            panic!("{}: {}", path, e);
+        }
+    }
+}
+
+fn mooneye(path: &str) {
+    if let Some(rom) = load_rom(path) {
+        if let Err(e) = run_mooneye(rom, MOONEYE_FRAMES) {
+            panic!("{}: {}", path, e);
+        }
+    }
+}
+
+macro_rules! test_roms {
+    ($runner:ident { $($name:ident: $path:expr,)* }) => {
+        $(
+            #[test]
+            fn $name() {
+                $runner($path);
+            }
+        )*
+    };
+}
+
+test_roms!(blargg {
+    blargg_cpu_instrs_01: "blargg/cpu_instrs/individual/01-special.gb",
+    blargg_cpu_instrs_02: "blargg/cpu_instrs/individual/02-interrupts.gb",
+    blargg_cpu_instrs_03: "blargg/cpu_instrs/individual/03-op sp,hl.gb",
+    blargg_cpu_instrs_04: "blargg/cpu_instrs/individual/04-op r,imm.gb",
+    blargg_cpu_instrs_05: "blargg/cpu_instrs/individual/05-op rp.gb",
+    blargg_cpu_instrs_06: "blargg/cpu_instrs/individual/06-ld r,r.gb",
+    blargg_cpu_instrs_07: "blargg/cpu_instrs/individual/07-jr,jp,call,ret,rst.gb",
+    blargg_cpu_instrs_08: "blargg/cpu_instrs/individual/08-misc instrs.gb",
+    blargg_cpu_instrs_09: "blargg/cpu_instrs/individual/09-op r,r.gb",
+    blargg_cpu_instrs_10: "blargg/cpu_instrs/individual/10-bit ops.gb",
+    blargg_cpu_instrs_11: "blargg/cpu_instrs/individual/11-op a,(hl).gb",
+    blargg_instr_timing: "blargg/instr_timing/instr_timing.gb",
+    blargg_mem_timing_01: "blargg/mem_timing/individual/01-read_timing.gb",
+    blargg_mem_timing_02: "blargg/mem_timing/individual/02-write_timing.gb",
+    blargg_mem_timing_03: "blargg/mem_timing/individual/03-modify_timing.gb",
+});
+
+test_roms!(mooneye {
+    mooneye_daa: "mooneye/acceptance/instr/daa.gb",
+    mooneye_reg_f: "mooneye/acceptance/bits/reg_f.gb",
+    mooneye_mem_oam: "mooneye/acceptance/bits/mem_oam.gb",
+    mooneye_div_write: "mooneye/acceptance/timer/div_write.gb",
+    mooneye_rapid_toggle: "mooneye/acceptance/timer/rapid_toggle.gb",
+    mooneye_tim00: "mooneye/acceptance/timer/tim00.gb",
+    mooneye_tim00_div_trigger: "mooneye/acceptance/timer/tim00_div_trigger.gb",
+    mooneye_tim01: "mooneye/acceptance/timer/tim01.gb",
+    mooneye_tim01_div_trigger: "mooneye/acceptance/timer/tim01_div_trigger.gb",
+    mooneye_tim10: "mooneye/acceptance/timer/tim10.gb",
+    mooneye_tim10_div_trigger: "mooneye/acceptance/timer/tim10_div_trigger.gb",
+    mooneye_tim11: "mooneye/acceptance/timer/tim11.gb",
+    mooneye_tim11_div_trigger: "mooneye/acceptance/timer/tim11_div_trigger.gb",
+    mooneye_tima_reload: "mooneye/acceptance/timer/tima_reload.gb",
+    mooneye_tima_write_reloading: "mooneye/acceptance/timer/tima_write_reloading.gb",
+    mooneye_tma_write_reloading: "mooneye/acceptance/timer/tma_write_reloading.gb",
+    mooneye_ei_sequence: "mooneye/acceptance/ei_sequence.gb",
+    mooneye_if_ie_registers: "mooneye/acceptance/if_ie_registers.gb",
+    mooneye_intr_timing: "mooneye/acceptance/intr_timing.gb",
+    mooneye_oam_dma_basic: "mooneye/acceptance/oam_dma/basic.gb",
+    mooneye_oam_dma_reg_read: "mooneye/acceptance/oam_dma/reg_read.gb",
+    mooneye_stat_irq_blocking: "mooneye/acceptance/ppu/stat_irq_blocking.gb",
+    mooneye_stat_lyc_onoff: "mooneye/acceptance/ppu/stat_lyc_onoff.gb",
+    mooneye_vblank_stat_intr: "mooneye/acceptance/ppu/vblank_stat_intr-GS.gb",
+});
+
+// Check the harness itself with tiny ROMs that report like the real suites.
+
+// Print `text` on the serial port with the internal clock, then loop.
+fn serial_print_rom(text: &str) -> Vec<u8> {
+    let mut code = Vec::new();
+    for b in text.bytes() {
+        code.extend_from_slice(&[
+            0x3e, b, // LD A,b
+            0xe0, 0x01, // LDH (SB),A
+            0x3e, 0x81, // LD A,81
+            0xe0, 0x02, // LDH (SC),A
+            0xf0, 0x02, // LDH A,(SC)
+            0x87, // ADD A,A
+            0x38, 0xfb, // JR C,-5
+        ]);
+    }
+    code.extend_from_slice(&[0x18, 0xfe]); // JR -2
+    harness_rom(&code)
+}
+
+// Load B, C, D, E, H, L then execute LD B,B.
+fn fibonacci_rom(values: [u8; 6]) -> Vec<u8> {
+    harness_rom(&[
+        0x06, values[0], 0x0e, values[1], 0x16, values[2], 0x1e, values[3], 0x26, values[4], 0x2e,
+        values[5], 0x40, 0x18, 0xfe,
+    ])
+}
+
+#[test]
+fn test_harness() {
+    assert!(run_blargg(serial_print_rom("Passed\n"), 60).is_ok());
+    assert!(run_blargg(serial_print_rom("Failed #3\n"), 60).is_err());
+    assert!(run_blargg(harness_rom(&[0x18, 0xfe]), 2).is_err());
+    assert!(run_mooneye(fibonacci_rom(MOONEYE_PASS), 1).is_ok());
+    assert!(run_mooneye(fibonacci_rom([0x42; 6]), 1).is_err());
+    assert!(run_mooneye(harness_rom(&[0x18, 0xfe]), 1).is_err());
+}